@@ -0,0 +1,37 @@
+use aya_ebpf::{
+    bindings::BPF_F_NO_PREALLOC,
+    macros::map,
+    maps::{Array, LpmTrie, lpm_trie::Key},
+};
+use beryl_common::{AntiSpoofParams, PacketAction, RuleEntry, packet::PacketMeta};
+use core::mem;
+
+/// Anti-spoofing settings (index 0), written by userspace on config load
+#[map]
+static ANTI_SPOOF_CONFIG: Array<AntiSpoofParams> = Array::pinned(1, 0);
+
+/// Bogon and LAN source prefixes (network byte order) -> drop, with pass
+/// entries for configured exceptions
+#[map]
+static BOGONS: LpmTrie<u32, RuleEntry> = LpmTrie::pinned(256, BPF_F_NO_PREALLOC);
+
+/// IPv6 bogon and LAN source prefixes, like `BOGONS`
+#[map]
+static BOGONS_V6: LpmTrie<[u8; 16], RuleEntry> = LpmTrie::pinned(256, BPF_F_NO_PREALLOC);
+
+/// Returns the bogon entry the source address of a packet from an upstream
+/// interface falls in, if the packet must be dropped as spoofed.
+#[inline(always)]
+pub(crate) fn check(meta: &PacketMeta) -> Option<&'static RuleEntry> {
+    if ANTI_SPOOF_CONFIG.get(0)?.enabled == 0 {
+        return None;
+    }
+
+    let entry = if meta.is_ipv6 {
+        let src: [u8; 16] = unsafe { mem::transmute(meta.src) };
+        BOGONS_V6.get(&Key::new(128, src))
+    } else {
+        BOGONS.get(&Key::new(32, meta.src[3]))
+    }?;
+    (entry.action == PacketAction::Drop as u32).then_some(entry)
+}
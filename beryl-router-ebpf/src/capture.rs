@@ -0,0 +1,70 @@
+use aya_ebpf::{
+    helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns, bpf_probe_read_kernel_buf},
+    macros::map,
+    maps::{Array, RingBuf},
+};
+use beryl_common::{CAPTURE_SNAPLEN_MAX, CaptureParams, CaptureRecord, packet::PacketMeta};
+
+/// Capture filter (index 0), written by userspace while a capture is running
+#[map]
+static CAPTURE_CONFIG: Array<CaptureParams> = Array::with_max_entries(1, 0);
+
+/// Captured packets for `beryl-routerd`; packets are lost while the buffer is
+/// full. Pinned like `EVENTS`.
+#[map]
+static CAPTURE: RingBuf = RingBuf::pinned(512 * 1024, 0);
+
+/// Returns whether a capture is running.
+#[inline(always)]
+pub(crate) fn active() -> bool {
+    CAPTURE_CONFIG
+        .get(0)
+        .is_some_and(|params| params.enabled != 0)
+}
+
+/// Copies the start of the frame in `start..end` to userspace if it matches
+/// the running capture.
+#[inline(always)]
+pub(crate) fn sample(start: usize, end: usize, meta: &PacketMeta, pkt_len: u64) {
+    let Some(params) = CAPTURE_CONFIG.get(0) else {
+        return;
+    };
+    if params.enabled == 0
+        || !params.matches(
+            &meta.src,
+            &meta.dst,
+            meta.proto,
+            meta.src_port,
+            meta.dst_port,
+        )
+    {
+        return;
+    }
+    if params.sample_rate > 1 && unsafe { bpf_get_prandom_u32() } % params.sample_rate != 0 {
+        return;
+    }
+
+    let Some(mut entry) = CAPTURE.reserve::<CaptureRecord>(0) else {
+        return;
+    };
+    let record = entry.as_mut_ptr();
+
+    // Copied with a helper: the verifier cannot follow a per-byte bounds
+    // check on packet memory through a loop
+    let cap_len = (end - start)
+        .min(params.snaplen as usize)
+        .min(CAPTURE_SNAPLEN_MAX);
+    let copied = unsafe { (*record).data.get_mut(..cap_len) }
+        .is_some_and(|data| unsafe { bpf_probe_read_kernel_buf(start as *const u8, data) }.is_ok());
+    if !copied {
+        entry.discard(0);
+        return;
+    }
+
+    unsafe {
+        (*record).timestamp_ns = bpf_ktime_get_ns();
+        (*record).orig_len = pkt_len as u32;
+        (*record).cap_len = cap_len as u32;
+    }
+    entry.submit(0);
+}
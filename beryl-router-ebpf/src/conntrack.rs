@@ -0,0 +1,55 @@
+use aya_ebpf::{
+    helpers::bpf_ktime_get_ns,
+    macros::map,
+    maps::{Array, LruHashMap},
+};
+use beryl_common::{ConntrackParams, FlowKey, FlowState, IPPROTO_TCP, IPPROTO_UDP};
+
+/// Connection tracking settings (index 0), written by userspace on config load
+#[map]
+static CONNTRACK_CONFIG: Array<ConntrackParams> = Array::pinned(1, 0);
+
+/// Tracked flows, populated by TC egress and consulted by XDP ingress.
+/// The size is overridden from config when the object is loaded.
+#[map]
+static CONNTRACK: LruHashMap<FlowKey, FlowState> = LruHashMap::pinned(65536, 0);
+
+/// Returns the connection tracking settings when flow tracking is enabled.
+#[inline(always)]
+pub(crate) fn params() -> Option<&'static ConntrackParams> {
+    CONNTRACK_CONFIG.get(0).filter(|p| p.track != 0)
+}
+
+/// Records (or refreshes) an outbound flow.
+#[inline(always)]
+pub(crate) fn track(key: &FlowKey) {
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if let Some(state) = CONNTRACK.get_ptr_mut(key) {
+        unsafe { (*state).last_seen_ns = now };
+        return;
+    }
+    let _ = CONNTRACK.insert(key, &FlowState { last_seen_ns: now }, 0);
+}
+
+/// Returns whether an inbound packet belongs to a live tracked flow,
+/// refreshing the flow when it does.
+#[inline(always)]
+pub(crate) fn is_established(key: &FlowKey, params: &ConntrackParams) -> bool {
+    let Some(state) = CONNTRACK.get_ptr_mut(key) else {
+        return false;
+    };
+
+    let timeout = match key.proto {
+        IPPROTO_TCP => params.tcp_timeout_ns,
+        IPPROTO_UDP => params.udp_timeout_ns,
+        _ => params.other_timeout_ns,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    if now.saturating_sub(unsafe { (*state).last_seen_ns }) > timeout {
+        return false;
+    }
+
+    unsafe { (*state).last_seen_ns = now };
+    true
+}
@@ -0,0 +1,28 @@
+use aya_ebpf::{helpers::bpf_ktime_get_ns, macros::map, maps::RingBuf};
+use beryl_common::{DropEvent, DropReason, packet::PacketMeta};
+
+/// Drop events for `beryl-routerd`; events are lost while the buffer is full.
+/// Pinned so that reloaded programs keep feeding the daemon's reader.
+#[map]
+static EVENTS: RingBuf = RingBuf::pinned(256 * 1024, 0);
+
+/// Pushes a drop event for `meta` into the ring buffer.
+#[inline(always)]
+pub(crate) fn report_drop(meta: &PacketMeta, reason: DropReason, rule: u32) {
+    let Some(mut entry) = EVENTS.reserve::<DropEvent>(0) else {
+        return;
+    };
+    entry.write(DropEvent {
+        timestamp_ns: unsafe { bpf_ktime_get_ns() },
+        src: meta.src,
+        dst: meta.dst,
+        src_port: meta.src_port,
+        dst_port: meta.dst_port,
+        proto: meta.proto,
+        is_ipv6: meta.is_ipv6 as u8,
+        _pad: [0; 2],
+        reason: reason as u32,
+        rule,
+    });
+    entry.submit(0);
+}
@@ -0,0 +1,170 @@
+use aya_ebpf::{
+    bindings::{bpf_fib_lookup as BpfFibLookup, xdp_action},
+    helpers::{bpf_fib_lookup, bpf_redirect},
+    macros::map,
+    maps::{Array, HashMap},
+    programs::XdpContext,
+};
+use beryl_common::{
+    ConntrackParams, FastPathParams,
+    packet::{EthHdr, Ipv4Hdr, Ipv6Hdr, PacketMeta, ptr_in},
+};
+use core::mem;
+
+use crate::{capture, conntrack, interfaces, tc_egress};
+
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+/// `BPF_FIB_LKUP_RET_SUCCESS`: route found and neighbour resolved
+const FIB_LKUP_RET_SUCCESS: i64 = 0;
+
+/// Flow label and traffic class bits of the first IPv6 header word
+const IPV6_FLOWINFO_MASK: u32 = 0x0fff_ffff;
+
+/// Fast-path settings (index 0), written by userspace on config load
+#[map]
+static FAST_PATH_CONFIG: Array<FastPathParams> = Array::pinned(1, 0);
+
+/// Interfaces the fast path may redirect to: ifindex -> unused
+#[map]
+static FAST_PATH_IFACES: HashMap<u32, u32> = HashMap::pinned(16, 0);
+
+/// Parameters and results of `bpf_fib_lookup` (`struct bpf_fib_lookup` in the
+/// kernel UAPI), with the unions flattened to the members used here.
+#[repr(C)]
+struct FibLookup {
+    family: u8,
+    l4_protocol: u8,
+    sport: u16,
+    dport: u16,
+    /// Input: L3 length; output: MTU when fragmentation would be needed
+    tot_len: u16,
+    /// Input: ingress ifindex; output: egress ifindex
+    ifindex: u32,
+    /// IPv4 TOS or IPv6 flow info
+    tos_flowinfo: u32,
+    src: [u32; 4],
+    dst: [u32; 4],
+    h_vlan_proto: u16,
+    h_vlan_tci: u16,
+    smac: [u8; 6],
+    dmac: [u8; 6],
+}
+
+/// Returns whether the fast path is enabled.
+#[inline(always)]
+pub(crate) fn enabled() -> bool {
+    FAST_PATH_CONFIG.get(0).is_some_and(|p| p.enabled != 0)
+}
+
+/// Forwards a packet of an established flow straight to its egress interface.
+///
+/// Returns `None` when the packet has to take the kernel stack instead: the
+/// flow is not tracked, the route is not a plain forward (local delivery,
+/// unresolved neighbour, MTU exceeded, ...), the TTL is about to expire, the
+/// egress interface is not a fast-path interface, or the TC egress program
+/// has to see the packet.
+///
+/// A redirected packet skips TC egress, netfilter and NAT. Packets the egress
+/// blocklist would drop are left to TC egress, as is all traffic while a
+/// capture is running; the caller counts redirected packets as egress.
+#[inline(always)]
+pub(crate) fn forward(
+    ctx: &XdpContext,
+    meta: &PacketMeta,
+    params: &ConntrackParams,
+) -> Option<u32> {
+    // Tagged frames would need their VLAN tags rewritten for the egress device
+    if meta.vlan_id != 0 {
+        return None;
+    }
+    // The capture samples egress packets in TC
+    if capture::active() {
+        return None;
+    }
+    if !conntrack::is_established(&meta.inbound_flow(), params)
+        && !conntrack::is_established(&meta.outbound_flow(), params)
+    {
+        return None;
+    }
+
+    let (start, end) = (ctx.data(), ctx.data_end());
+    let mut fib: FibLookup = unsafe { mem::zeroed() };
+    fib.l4_protocol = meta.proto;
+    fib.sport = meta.src_port.to_be();
+    fib.dport = meta.dst_port.to_be();
+    fib.ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    fib.src = meta.src;
+    fib.dst = meta.dst;
+
+    if meta.is_ipv6 {
+        let ipv6_hdr = ptr_in::<Ipv6Hdr>(start, end, meta.l3_offset).ok()? as *mut Ipv6Hdr;
+        if unsafe { (*ipv6_hdr).hop_limit } <= 1 {
+            return None;
+        }
+        let first_word = unsafe { *(ipv6_hdr as *const u32) };
+        fib.family = AF_INET6;
+        fib.tos_flowinfo = first_word & IPV6_FLOWINFO_MASK.to_be();
+        fib.tot_len = u16::from_be_bytes(unsafe { (*ipv6_hdr).payload_len }) + Ipv6Hdr::LEN as u16;
+    } else {
+        let ipv4_hdr = ptr_in::<Ipv4Hdr>(start, end, meta.l3_offset).ok()? as *mut Ipv4Hdr;
+        if unsafe { (*ipv4_hdr).ttl } <= 1 {
+            return None;
+        }
+        fib.family = AF_INET;
+        fib.tos_flowinfo = unsafe { (*ipv4_hdr).tos } as u32;
+        fib.tot_len = u16::from_be_bytes(unsafe { (*ipv4_hdr).tot_len });
+        // IPv4 addresses live in the first word of the address unions
+        fib.src = [meta.src[3], 0, 0, 0];
+        fib.dst = [meta.dst[3], 0, 0, 0];
+    }
+
+    let ret = unsafe {
+        bpf_fib_lookup(
+            ctx.ctx as *mut _,
+            &mut fib as *mut FibLookup as *mut BpfFibLookup,
+            mem::size_of::<FibLookup>() as i32,
+            0,
+        )
+    };
+    if ret != FIB_LKUP_RET_SUCCESS {
+        return None;
+    }
+    unsafe { FAST_PATH_IFACES.get(&fib.ifindex) }?;
+
+    // TC egress drops, counts and reports blocked destinations
+    if interfaces::role(fib.ifindex).is_upstream() && tc_egress::blocked(meta).is_some() {
+        return None;
+    }
+
+    // Decrement the TTL / hop limit as the kernel would when forwarding
+    if meta.is_ipv6 {
+        let ipv6_hdr = ptr_in::<Ipv6Hdr>(start, end, meta.l3_offset).ok()? as *mut Ipv6Hdr;
+        unsafe { (*ipv6_hdr).hop_limit -= 1 };
+    } else {
+        let ipv4_hdr = ptr_in::<Ipv4Hdr>(start, end, meta.l3_offset).ok()? as *mut Ipv4Hdr;
+        unsafe { decrease_ttl(ipv4_hdr) };
+    }
+
+    // Rewrite the Ethernet addresses for the next hop
+    let eth_hdr = ptr_in::<EthHdr>(start, end, 0).ok()? as *mut EthHdr;
+    unsafe {
+        (*eth_hdr).dst_addr = fib.dmac;
+        (*eth_hdr).src_addr = fib.smac;
+    }
+
+    let action = unsafe { bpf_redirect(fib.ifindex, 0) } as u32;
+    (action == xdp_action::XDP_REDIRECT).then_some(action)
+}
+
+/// Decrements the IPv4 TTL and patches the header checksum incrementally
+/// (RFC 1624), mirroring the kernel's `ip_decrease_ttl`.
+#[inline(always)]
+unsafe fn decrease_ttl(hdr: *mut Ipv4Hdr) {
+    unsafe {
+        let check = u16::from_be_bytes((*hdr).check) as u32 + 0x0100;
+        (*hdr).check = ((check + (check >> 16)) as u16).to_be_bytes();
+        (*hdr).ttl -= 1;
+    }
+}
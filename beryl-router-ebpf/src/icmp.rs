@@ -0,0 +1,57 @@
+use aya_ebpf::{
+    helpers::bpf_ktime_get_ns,
+    macros::map,
+    maps::{Array, HashMap, LruHashMap},
+};
+use beryl_common::{
+    Addr128, IcmpAction, IcmpKey, IcmpParams, MAX_ICMP_RULES, TokenBucket,
+    packet::{self, PacketMeta},
+};
+
+use crate::rate_limit;
+
+/// ICMP rate limit (index 0), written by userspace on config load
+#[map]
+static ICMP_CONFIG: Array<IcmpParams> = Array::pinned(1, 0);
+
+/// ICMP/ICMPv6 rules: protocol, type and code (or any code) -> `IcmpAction`
+#[map]
+static ICMP_RULES: HashMap<IcmpKey, u32> = HashMap::pinned(MAX_ICMP_RULES, 0);
+
+/// Per-source buckets for `rate_limit` rules, keyed like `RATE_LIMIT`
+#[map]
+static ICMP_RATE_LIMIT: LruHashMap<Addr128, TokenBucket> = LruHashMap::pinned(16384, 0);
+
+/// Outcome of running a packet through the ICMP rules.
+pub(crate) enum Verdict {
+    Allow,
+    Drop,
+    RateLimited,
+}
+
+/// Applies the ICMP rules to an ICMP/ICMPv6 packet; anything else is allowed.
+#[inline(always)]
+pub(crate) fn check(meta: &PacketMeta) -> Verdict {
+    let action = packet::icmp_action(meta, |key| {
+        unsafe { ICMP_RULES.get(key) }.map(|&action| IcmpAction::from(action))
+    });
+
+    match action {
+        Some(IcmpAction::Drop) => Verdict::Drop,
+        Some(IcmpAction::RateLimit) => {
+            let Some(params) = ICMP_CONFIG.get(0) else {
+                return Verdict::Allow;
+            };
+            if params.pps == 0 {
+                return Verdict::Allow;
+            }
+            let now = unsafe { bpf_ktime_get_ns() };
+            if rate_limit::take_token(&ICMP_RATE_LIMIT, &meta.src, params.pps, params.burst, now) {
+                Verdict::Allow
+            } else {
+                Verdict::RateLimited
+            }
+        }
+        Some(IcmpAction::Allow) | None => Verdict::Allow,
+    }
+}
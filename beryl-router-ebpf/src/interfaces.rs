@@ -0,0 +1,14 @@
+use aya_ebpf::{macros::map, maps::HashMap};
+use beryl_common::InterfaceRole;
+
+/// Interface roles from the interface config: ifindex -> `InterfaceRole`
+#[map]
+static IFACE_ROLES: HashMap<u32, u32> = HashMap::pinned(16, 0);
+
+/// Returns the role of an interface, `Unassigned` if it has none.
+#[inline(always)]
+pub(crate) fn role(ifindex: u32) -> InterfaceRole {
+    unsafe { IFACE_ROLES.get(&ifindex) }
+        .copied()
+        .map_or(InterfaceRole::Unassigned, InterfaceRole::from)
+}
@@ -1,34 +1,74 @@
 #![no_std]
 #![no_main]
+// Atomic adds on map values (`record_hit`); the BPF target has no `AtomicU64::fetch_add`
+#![feature(core_intrinsics)]
+#![allow(internal_features)]
 
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{BPF_F_NO_PREALLOC, xdp_action},
     macros::{map, xdp},
-    maps::{HashMap, PerCpuArray},
+    maps::{Array, LpmTrie, PerCpuArray},
     programs::XdpContext,
 };
-use aya_log_ebpf::info;
-use beryl_common::{PacketAction, Stats};
+use beryl_common::{
+    ConntrackParams, DropReason, FragmentPolicy, ICMPV6_NDP_FIRST, ICMPV6_NDP_LAST, IPPROTO_ICMPV6,
+    IPPROTO_UDP, MalformedPolicy, RuleEntry, Stats, ValidationParams,
+    packet::{self, PacketMeta, TcpFlagViolation, Verdict},
+};
+mod anti_spoof;
+mod capture;
+mod conntrack;
+mod events;
+mod fast_path;
+mod icmp;
+mod interfaces;
+mod port_scan;
+mod rate_limit;
+mod rules;
 mod tc_egress;
-use core::mem;
-use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::{IpProto, Ipv4Hdr},
-    tcp::TcpHdr,
-    udp::UdpHdr,
+use core::{
+    intrinsics::{AtomicOrdering, atomic_xadd},
+    ptr,
 };
 
-/// Blocklist: IPv4 address -> action (0 = pass, 1 = drop)
+/// DHCPv4 server/client UDP ports
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// DHCPv6 server/client UDP ports
+const DHCPV6_SERVER_PORT: u16 = 547;
+const DHCPV6_CLIENT_PORT: u16 = 546;
+
+// The sizes of the blocklists below, like those of the tracking tables, are
+// overridden from `firewall.map_sizes` when the object is loaded.
+
+/// Blocklist: IPv4 prefix (network byte order) -> action and hit counters
+#[map]
+static BLOCKLIST: LpmTrie<u32, RuleEntry> = LpmTrie::pinned(4096, BPF_F_NO_PREALLOC);
+
+/// IPv6 blocklist: IPv6 prefix -> action and hit counters
+#[map]
+static BLOCKLIST_V6: LpmTrie<[u8; 16], RuleEntry> = LpmTrie::pinned(4096, BPF_F_NO_PREALLOC);
+
+/// Port blocklist (both address families): protocol + destination port prefix
+/// (see `beryl_common::port_key`) -> action and hit counters
 #[map]
-static BLOCKLIST: HashMap<u32, u32> = HashMap::with_max_entries(4096, 0);
+static PORT_BLOCKLIST: LpmTrie<[u8; 4], RuleEntry> = LpmTrie::pinned(1024, BPF_F_NO_PREALLOC);
 
-/// Port blocklist: port number -> action (0 = pass, 1 = drop)
+/// Fragment and malformed-packet policy (index 0), written by userspace on config load
 #[map]
-static PORT_BLOCKLIST: HashMap<u16, u32> = HashMap::with_max_entries(1024, 0);
+static VALIDATION_CONFIG: Array<ValidationParams> = Array::pinned(1, 0);
 
-/// Per-CPU statistics
+/// Per-CPU statistics (shared by the XDP and TC programs)
 #[map]
-static STATS: PerCpuArray<Stats> = PerCpuArray::with_max_entries(1, 0);
+static STATS: PerCpuArray<Stats> = PerCpuArray::pinned(1, 0);
+
+/// Headers of the packet being processed (index 0). The XDP stages read them
+/// from this map value rather than the stack: the verifier does not track map
+/// contents, so the paths through the parser converge instead of each being
+/// verified through the whole program, and the stack stays under 512 bytes.
+#[map]
+static PACKET_META: PerCpuArray<PacketMeta> = PerCpuArray::with_max_entries(1, 0);
 
 #[xdp]
 pub fn xdp_firewall(ctx: XdpContext) -> u32 {
@@ -38,84 +78,218 @@ pub fn xdp_firewall(ctx: XdpContext) -> u32 {
     }
 }
 
-#[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
-
-    if start + offset + len > end {
-        return Err(());
-    }
+fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
+    // Update packet/byte counters
+    let pkt_len = (ctx.data_end() - ctx.data()) as u64;
+    with_stats(|stats| stats.ingress.count_total(pkt_len));
 
-    Ok((start + offset) as *const T)
-}
+    let validation = VALIDATION_CONFIG.get(0).copied().unwrap_or_default();
 
-fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
-    // Update packet counter
-    if let Some(stats) = STATS.get_ptr_mut(0) {
-        unsafe { (*stats).packets_total += 1 };
-    }
+    // Parse Ethernet, IP and transport headers
+    let meta = match packet::parse_packet(ctx.data(), ctx.data_end()) {
+        Ok(Some(meta)) => meta,
+        Ok(None) => {
+            with_stats(|stats| stats.ingress.count_passed(pkt_len));
+            return Ok(xdp_action::XDP_PASS);
+        }
+        // Truncated or invalid headers
+        Err(_) => {
+            with_stats(|stats| stats.malformed += 1);
+            if MalformedPolicy::from(validation.malformed) == MalformedPolicy::Pass {
+                with_stats(|stats| stats.ingress.count_passed(pkt_len));
+                return Ok(xdp_action::XDP_PASS);
+            }
+            with_stats(|stats| stats.ingress.count_dropped(pkt_len));
+            return Ok(xdp_action::XDP_DROP);
+        }
+    };
+    // Later stages read the headers back from a map value (see `PACKET_META`);
+    // the volatile store keeps the compiler from forwarding the parsed values
+    let slot = PACKET_META.get_ptr_mut(0).ok_or(())?;
+    unsafe { ptr::write_volatile(slot, meta) };
+    let meta = unsafe { &*slot };
 
-    // Parse Ethernet header
-    let eth_hdr: *const EthHdr = ptr_at(&ctx, 0)?;
-    let eth_type = unsafe { (*eth_hdr).ether_type };
+    capture::sample(ctx.data(), ctx.data_end(), meta, pkt_len);
+    let role = interfaces::role(unsafe { (*ctx.ctx).ingress_ifindex });
+    // Anti-spoofing, the blocklists and the stateful checks protect the router
+    // and LAN from upstream networks; LAN and guest clients only go through
+    // the ordered rules (and the fast path)
+    let upstream = role.is_upstream();
 
-    // Only process IPv4
-    if eth_type != EtherType::Ipv4 {
-        return Ok(xdp_action::XDP_PASS);
+    // TCP flag combinations used by stealth scans
+    if validation.tcp_flags != 0
+        && let Some(violation) = meta.tcp_flag_violation()
+    {
+        drop_packet(meta, pkt_len, DropReason::TcpFlags, 0);
+        with_stats(|stats| match violation {
+            TcpFlagViolation::Null => stats.tcp_null += 1,
+            TcpFlagViolation::Xmas => stats.tcp_xmas += 1,
+            TcpFlagViolation::SynFin => stats.tcp_syn_fin += 1,
+        });
+        return Ok(xdp_action::XDP_DROP);
     }
 
-    // Parse IPv4 header
-    let ipv4_hdr: *const Ipv4Hdr = ptr_at(&ctx, EthHdr::LEN)?;
-    let src_ip = u32::from_be(unsafe { (*ipv4_hdr).src_addr });
-    let proto = unsafe { (*ipv4_hdr).proto };
+    // Anti-spoofing: bogon and LAN sources never arrive legitimately from
+    // upstream networks
+    if upstream && let Some(entry) = anti_spoof::check(meta) {
+        record_hit(entry, pkt_len);
+        drop_packet(meta, pkt_len, DropReason::Spoofed, 0);
+        with_stats(|stats| stats.spoofed += 1);
+        return Ok(xdp_action::XDP_DROP);
+    }
 
-    // Check IP blocklist
-    if let Some(&action) = unsafe { BLOCKLIST.get(&src_ip) } {
-        if action == PacketAction::Drop as u32 {
-            if let Some(stats) = STATS.get_ptr_mut(0) {
-                unsafe { (*stats).packets_dropped += 1 };
+    // Ordered rule table (rules for any interface and for this interface's
+    // role), then on upstream interfaces the source and port blocklists (or
+    // the fragment policy for non-initial fragments)
+    let fragments = FragmentPolicy::from(validation.fragments);
+    match packet::classify(&rules::Tables::active(), meta, role, fragments) {
+        Verdict::Allow(entry) => {
+            record_hit(entry, pkt_len);
+            with_stats(|stats| stats.ingress.count_passed(pkt_len));
+            return Ok(xdp_action::XDP_PASS);
+        }
+        Verdict::Drop(reason, entry) => {
+            if let Some(entry) = entry {
+                record_hit(entry, pkt_len);
             }
-            info!(&ctx, "DROP: blocked IP {:i}", src_ip);
+            drop_packet(meta, pkt_len, reason, entry.map_or(0, |entry| entry.rule));
             return Ok(xdp_action::XDP_DROP);
         }
+        Verdict::Continue => {}
     }
 
-    // Check port blocklist for TCP/UDP
-    let ip_hdr_len = ((unsafe { (*ipv4_hdr).ihl() }) as usize) * 4;
-    let transport_offset = EthHdr::LEN + ip_hdr_len;
-
-    let dst_port: u16 = match proto {
-        IpProto::Tcp => {
-            let tcp_hdr: *const TcpHdr = ptr_at(&ctx, transport_offset)?;
-            u16::from_be(unsafe { (*tcp_hdr).dest })
+    // ICMP/ICMPv6 rules by type and code
+    let icmp = if upstream {
+        icmp::check(meta)
+    } else {
+        icmp::Verdict::Allow
+    };
+    match icmp {
+        icmp::Verdict::Allow => {}
+        icmp::Verdict::Drop => {
+            drop_packet(meta, pkt_len, DropReason::Icmp, 0);
+            with_stats(|stats| stats.icmp_dropped += 1);
+            return Ok(xdp_action::XDP_DROP);
         }
-        IpProto::Udp => {
-            let udp_hdr: *const UdpHdr = ptr_at(&ctx, transport_offset)?;
-            u16::from_be(unsafe { (*udp_hdr).dest })
+        icmp::Verdict::RateLimited => {
+            drop_packet(meta, pkt_len, DropReason::IcmpRateLimited, 0);
+            with_stats(|stats| stats.icmp_rate_limited += 1);
+            return Ok(xdp_action::XDP_DROP);
         }
-        _ => 0,
+    }
+
+    let conntrack = conntrack::params();
+
+    // Port-scan detection: probes from a source past the threshold are
+    // dropped, and the drop events tell userspace to block it
+    if upstream && port_scan::is_scanning(meta, conntrack) {
+        drop_packet(meta, pkt_len, DropReason::PortScan, 0);
+        with_stats(|stats| stats.port_scan_dropped += 1);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Per-source rate limiting and SYN flood protection
+    let rate_limit = if upstream {
+        rate_limit::check(&meta.src, meta.is_syn())
+    } else {
+        rate_limit::Verdict::Allow
     };
+    match rate_limit {
+        rate_limit::Verdict::Allow => {}
+        rate_limit::Verdict::RateLimited => {
+            drop_packet(meta, pkt_len, DropReason::RateLimited, 0);
+            with_stats(|stats| stats.rate_limited += 1);
+            return Ok(xdp_action::XDP_DROP);
+        }
+        rate_limit::Verdict::SynLimited => {
+            drop_packet(meta, pkt_len, DropReason::SynLimited, 0);
+            with_stats(|stats| stats.syn_limited += 1);
+            return Ok(xdp_action::XDP_DROP);
+        }
+    }
 
-    if dst_port != 0 {
-        if let Some(&action) = unsafe { PORT_BLOCKLIST.get(&dst_port) } {
-            if action == PacketAction::Drop as u32 {
-                if let Some(stats) = STATS.get_ptr_mut(0) {
-                    unsafe { (*stats).packets_dropped += 1 };
-                }
-                info!(&ctx, "DROP: blocked port {}", dst_port);
-                return Ok(xdp_action::XDP_DROP);
-            }
+    // Default-deny: only admit replies to flows the router opened, on
+    // interfaces whose peers may not open connections themselves
+    if let Some(params) = conntrack
+        && params.default_deny != 0
+        && upstream
+        && !conntrack_allows(&ctx, meta, params)
+    {
+        drop_packet(meta, pkt_len, DropReason::Conntrack, 0);
+        with_stats(|stats| stats.conntrack_dropped += 1);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Fast path: forward established flows without the kernel stack
+    if let Some(params) = conntrack
+        && fast_path::enabled()
+    {
+        if let Some(action) = fast_path::forward(&ctx, meta, params) {
+            // Redirected packets skip TC egress, so count their egress here
+            with_stats(|stats| {
+                stats.ingress.count_passed(pkt_len);
+                stats.egress.count_total(pkt_len);
+                stats.egress.count_passed(pkt_len);
+                stats.fast_path += 1;
+            });
+            return Ok(action);
         }
+        with_stats(|stats| stats.slow_path += 1);
     }
 
     // Update passed counter
+    with_stats(|stats| stats.ingress.count_passed(pkt_len));
+
+    Ok(xdp_action::XDP_PASS)
+}
+
+/// Decides whether an unsolicited-looking packet may pass under default-deny.
+///
+/// Admits packets of established flows, ICMP errors about them, and the
+/// link-local control traffic the router needs to stay configured (IPv6
+/// neighbor discovery and DHCP replies).
+#[inline(always)]
+fn conntrack_allows(ctx: &XdpContext, meta: &PacketMeta, params: &ConntrackParams) -> bool {
+    if conntrack::is_established(&meta.inbound_flow(), params) {
+        return true;
+    }
+
+    match meta.proto {
+        IPPROTO_ICMPV6 if (ICMPV6_NDP_FIRST..=ICMPV6_NDP_LAST).contains(&meta.icmp_type) => true,
+        IPPROTO_UDP => matches!(
+            (meta.src_port, meta.dst_port),
+            (DHCP_SERVER_PORT, DHCP_CLIENT_PORT) | (DHCPV6_SERVER_PORT, DHCPV6_CLIENT_PORT)
+        ),
+        _ if meta.is_icmp_error() => packet::related_flow(ctx.data(), ctx.data_end(), meta)
+            .is_some_and(|flow| conntrack::is_established(&flow, params)),
+        _ => false,
+    }
+}
+
+/// Counts an ingress drop and reports it to userspace.
+#[inline(always)]
+fn drop_packet(meta: &PacketMeta, pkt_len: u64, reason: DropReason, rule: u32) {
+    with_stats(|stats| stats.ingress.count_dropped(pkt_len));
+    events::report_drop(meta, reason, rule);
+}
+
+#[inline(always)]
+pub(crate) fn with_stats(f: impl FnOnce(&mut Stats)) {
     if let Some(stats) = STATS.get_ptr_mut(0) {
-        unsafe { (*stats).packets_passed += 1 };
+        f(unsafe { &mut *stats });
     }
+}
 
-    Ok(xdp_action::XDP_PASS)
+/// Bumps the hit counters of the map entry that matched this packet.
+///
+/// Entries are shared by all CPUs, so the counters are updated atomically.
+#[inline(always)]
+pub(crate) fn record_hit(entry: &RuleEntry, bytes: u64) {
+    let entry = entry as *const RuleEntry as *mut RuleEntry;
+    unsafe {
+        atomic_xadd::<_, _, { AtomicOrdering::Relaxed }>(&raw mut (*entry).packets, 1u64);
+        atomic_xadd::<_, _, { AtomicOrdering::Relaxed }>(&raw mut (*entry).bytes, bytes);
+    }
 }
 
 #[panic_handler]
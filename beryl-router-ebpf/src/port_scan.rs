@@ -0,0 +1,58 @@
+use aya_ebpf::{
+    helpers::bpf_ktime_get_ns,
+    macros::map,
+    maps::{Array, LruHashMap},
+};
+use beryl_common::{
+    Addr128, ConntrackParams, IPPROTO_TCP, IPPROTO_UDP, PortScanParams, ScanState,
+    packet::PacketMeta,
+};
+
+/// Port-scan detection settings (index 0), written by userspace on config load
+#[map]
+static PORT_SCAN_CONFIG: Array<PortScanParams> = Array::pinned(1, 0);
+
+/// Distinct destination ports per source, keyed by IPv6 or IPv4-mapped IPv6 address
+#[map]
+static PORT_SCAN: LruHashMap<Addr128, ScanState> = LruHashMap::pinned(16384, 0);
+
+/// Counts a connection attempt towards its source's distinct ports and
+/// returns whether the source has exceeded the port-scan threshold.
+///
+/// Only TCP SYNs are counted, plus UDP packets outside tracked flows when
+/// connection tracking is enabled, so replies to the router's own
+/// connections never look like probes.
+#[inline(always)]
+pub(crate) fn is_scanning(meta: &PacketMeta, conntrack: Option<&ConntrackParams>) -> bool {
+    let Some(params) = PORT_SCAN_CONFIG.get(0) else {
+        return false;
+    };
+    if params.threshold == 0 || meta.fragment {
+        return false;
+    }
+    let probe = match meta.proto {
+        IPPROTO_TCP => meta.is_syn(),
+        IPPROTO_UDP => conntrack.is_some_and(|conntrack| {
+            !crate::conntrack::is_established(&meta.inbound_flow(), conntrack)
+        }),
+        _ => false,
+    };
+    if !probe {
+        return false;
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let Some(state) = PORT_SCAN.get_ptr_mut(&meta.src) else {
+        let mut state = ScanState {
+            window_start_ns: now,
+            ..ScanState::default()
+        };
+        state.observe(meta.dst_port, now, params.window_ns);
+        let _ = PORT_SCAN.insert(&meta.src, &state, 0);
+        return false;
+    };
+
+    // Shared across CPUs without locking; a lost update only delays detection
+    let state = unsafe { &mut *state };
+    state.observe(meta.dst_port, now, params.window_ns) > params.threshold
+}
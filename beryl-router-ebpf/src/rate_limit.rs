@@ -0,0 +1,96 @@
+use aya_ebpf::{
+    helpers::bpf_ktime_get_ns,
+    macros::map,
+    maps::{Array, LruHashMap},
+};
+use beryl_common::{Addr128, RateLimitParams, TokenBucket};
+
+/// Nanoseconds per second; also the token cost of a single packet
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// Longest idle period credited on refill. Together with `MAX_RATE_PPS` this
+/// bounds `elapsed * rate` well below `u64::MAX`.
+const MAX_REFILL_NS: u64 = 60 * NS_PER_SEC;
+
+/// Rate limiter settings (index 0), written by userspace on config load
+#[map]
+static RATE_LIMIT_CONFIG: Array<RateLimitParams> = Array::pinned(1, 0);
+
+/// Per-source packet buckets, keyed by IPv6 or IPv4-mapped IPv6 address
+#[map]
+static RATE_LIMIT: LruHashMap<Addr128, TokenBucket> = LruHashMap::pinned(16384, 0);
+
+/// Per-source TCP SYN buckets, keyed like `RATE_LIMIT`
+#[map]
+static SYN_RATE_LIMIT: LruHashMap<Addr128, TokenBucket> = LruHashMap::pinned(16384, 0);
+
+/// Outcome of running a packet through the rate limiters.
+pub(crate) enum Verdict {
+    Allow,
+    RateLimited,
+    SynLimited,
+}
+
+/// Charges one packet (and one SYN, if `is_syn`) against the source's buckets.
+#[inline(always)]
+pub(crate) fn check(src: &Addr128, is_syn: bool) -> Verdict {
+    let Some(params) = RATE_LIMIT_CONFIG.get(0) else {
+        return Verdict::Allow;
+    };
+    if params.pps == 0 && params.syn_pps == 0 {
+        return Verdict::Allow;
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if params.pps != 0 && !take_token(&RATE_LIMIT, src, params.pps, params.burst, now) {
+        return Verdict::RateLimited;
+    }
+    if is_syn
+        && params.syn_pps != 0
+        && !take_token(&SYN_RATE_LIMIT, src, params.syn_pps, params.syn_burst, now)
+    {
+        return Verdict::SynLimited;
+    }
+
+    Verdict::Allow
+}
+
+/// Refills the bucket for `src` and takes one token from it.
+///
+/// Returns `false` when the bucket is empty. Buckets are shared across CPUs
+/// without locking, so concurrent updates may occasionally let an extra packet
+/// through; that is acceptable for flood protection.
+#[inline(always)]
+pub(crate) fn take_token(
+    buckets: &LruHashMap<Addr128, TokenBucket>,
+    src: &Addr128,
+    rate: u32,
+    burst: u32,
+    now: u64,
+) -> bool {
+    let capacity = (burst as u64).max(1) * NS_PER_SEC;
+
+    let Some(bucket) = buckets.get_ptr_mut(src) else {
+        // First packet from this source: start with a full bucket minus this packet
+        let bucket = TokenBucket {
+            tokens: capacity - NS_PER_SEC,
+            last_ns: now,
+        };
+        let _ = buckets.insert(src, &bucket, 0);
+        return true;
+    };
+    let bucket = unsafe { &mut *bucket };
+
+    let elapsed = now.saturating_sub(bucket.last_ns).min(MAX_REFILL_NS);
+    let tokens = (bucket.tokens + elapsed * rate as u64).min(capacity);
+    bucket.last_ns = now;
+
+    if tokens < NS_PER_SEC {
+        bucket.tokens = tokens;
+        return false;
+    }
+
+    bucket.tokens = tokens - NS_PER_SEC;
+    true
+}
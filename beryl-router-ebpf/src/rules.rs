@@ -0,0 +1,65 @@
+use aya_ebpf::{
+    macros::map,
+    maps::{Array, lpm_trie::Key},
+};
+use beryl_common::{
+    FilterRule, MAX_FILTER_RULES, PORT_KEY_BITS, RuleEntry, RuleSet,
+    packet::{PacketMeta, RuleTables},
+    port_key,
+};
+use core::mem;
+
+use crate::{BLOCKLIST, BLOCKLIST_V6, PORT_BLOCKLIST};
+
+/// Ordered 5-tuple (+ VLAN) rule table, sorted by priority in userspace.
+/// Holds two banks of `MAX_FILTER_RULES` entries; `RULE_SET` selects one.
+#[map]
+static RULES: Array<FilterRule> = Array::pinned(2 * MAX_FILTER_RULES, 0);
+
+/// Active `RULES` bank and rule count (index 0, see `RuleSet`)
+#[map]
+static RULE_SET: Array<u32> = Array::pinned(1, 0);
+
+/// The stateless classifier's view of the rule and blocklist maps.
+pub(crate) struct Tables {
+    rules: RuleSet,
+}
+
+impl Tables {
+    /// Snapshots the active rule bank, so a packet is classified against a
+    /// single ruleset even if userspace switches banks meanwhile.
+    #[inline(always)]
+    pub(crate) fn active() -> Self {
+        let bits = RULE_SET.get(0).copied().unwrap_or(0);
+        Self {
+            rules: RuleSet::from_bits(bits),
+        }
+    }
+}
+
+impl RuleTables for Tables {
+    #[inline(always)]
+    fn rule_count(&self) -> u32 {
+        self.rules.count
+    }
+
+    #[inline(always)]
+    fn rule(&self, index: u32) -> Option<&FilterRule> {
+        RULES.get(self.rules.base() + index)
+    }
+
+    #[inline(always)]
+    fn source_blocklist(&self, meta: &PacketMeta) -> Option<&RuleEntry> {
+        if meta.is_ipv6 {
+            let src: [u8; 16] = unsafe { mem::transmute(meta.src) };
+            BLOCKLIST_V6.get(&Key::new(128, src))
+        } else {
+            BLOCKLIST.get(&Key::new(32, meta.src[3]))
+        }
+    }
+
+    #[inline(always)]
+    fn port_blocklist(&self, proto: u8, port: u16) -> Option<&RuleEntry> {
+        PORT_BLOCKLIST.get(&Key::new(PORT_KEY_BITS, port_key(proto, port)))
+    }
+}
@@ -1,19 +1,37 @@
 use aya_ebpf::{
+    bindings::BPF_F_NO_PREALLOC,
     macros::{classifier, map},
-    maps::HashMap,
+    maps::{LpmTrie, lpm_trie::Key},
     programs::TcContext,
 };
-use aya_log_ebpf::info;
-use beryl_common::PacketAction;
-use core::mem;
-use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::Ipv4Hdr,
+use beryl_common::{
+    DropReason, PacketAction, RuleEntry,
+    packet::{self, PacketMeta},
 };
 
-/// Egress blocklist: destination IP -> action (0 = pass, 1 = drop)
+use crate::{capture, conntrack, events, interfaces, record_hit, with_stats};
+use core::mem;
+
+/// Egress blocklist: destination IPv4 prefix (network byte order) -> action and hit counters
+#[map]
+static EGRESS_BLOCK: LpmTrie<u32, RuleEntry> = LpmTrie::pinned(4096, BPF_F_NO_PREALLOC);
+
+/// Egress IPv6 blocklist: destination IPv6 prefix -> action and hit counters
 #[map]
-static EGRESS_BLOCK: HashMap<u32, u32> = HashMap::with_max_entries(4096, 0);
+static EGRESS_BLOCK_V6: LpmTrie<[u8; 16], RuleEntry> = LpmTrie::pinned(4096, BPF_F_NO_PREALLOC);
+
+/// Returns the egress blocklist entry dropping packets to the destination,
+/// if any (longest prefix match on the destination address).
+#[inline(always)]
+pub(crate) fn blocked(meta: &PacketMeta) -> Option<&'static RuleEntry> {
+    let entry = if meta.is_ipv6 {
+        let dst: [u8; 16] = unsafe { mem::transmute(meta.dst) };
+        EGRESS_BLOCK_V6.get(&Key::new(128, dst))
+    } else {
+        EGRESS_BLOCK.get(&Key::new(32, meta.dst[3]))
+    }?;
+    (entry.action == PacketAction::Drop as u32).then_some(entry)
+}
 
 #[classifier]
 pub fn tc_egress(ctx: TcContext) -> i32 {
@@ -23,40 +41,40 @@ pub fn tc_egress(ctx: TcContext) -> i32 {
     }
 }
 
-#[inline(always)]
-fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*const T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
-
-    if start + offset + len > end {
-        return Err(());
-    }
-
-    Ok((start + offset) as *const T)
-}
-
 fn try_tc_egress(ctx: TcContext) -> Result<i32, ()> {
-    // Parse Ethernet header
-    let eth_hdr: *const EthHdr = ptr_at(&ctx, 0)?;
-    let eth_type = unsafe { (*eth_hdr).ether_type };
+    let pkt_len = ctx.len() as u64;
+    with_stats(|stats| stats.egress.count_total(pkt_len));
 
-    // Only process IPv4
-    if eth_type != EtherType::Ipv4 {
+    // Parse Ethernet, IP and transport headers; egress is not filtered for
+    // malformed packets, so unparseable ones pass too
+    let Ok(Some(meta)) = packet::parse_packet(ctx.data(), ctx.data_end()) else {
+        with_stats(|stats| stats.egress.count_passed(pkt_len));
         return Ok(0); // TC_ACT_OK
-    }
+    };
+    capture::sample(ctx.data(), ctx.data_end(), &meta, pkt_len);
 
-    // Parse IPv4 header
-    let ipv4_hdr: *const Ipv4Hdr = ptr_at(&ctx, EthHdr::LEN)?;
-    let dst_ip = u32::from_be(unsafe { (*ipv4_hdr).dst_addr });
+    // The egress blocklist and flow tracking cover traffic leaving towards
+    // upstream networks; LAN and guest egress only carries replies and
+    // forwarded traffic
+    if !interfaces::role(unsafe { (*ctx.skb.skb).ifindex }).is_upstream() {
+        with_stats(|stats| stats.egress.count_passed(pkt_len));
+        return Ok(0); // TC_ACT_OK
+    }
 
     // Check egress blocklist
-    if let Some(&action) = unsafe { EGRESS_BLOCK.get(&dst_ip) } {
-        if action == PacketAction::Drop as u32 {
-            info!(&ctx, "TC DROP: blocked egress IP {:i}", dst_ip);
-            return Ok(2); // TC_ACT_SHOT (Drop)
-        }
+    if let Some(entry) = blocked(&meta) {
+        record_hit(entry, pkt_len);
+        with_stats(|stats| stats.egress.count_dropped(pkt_len));
+        events::report_drop(&meta, DropReason::EgressBlocklist, entry.rule);
+        return Ok(2); // TC_ACT_SHOT (Drop)
+    }
+
+    // Remember the flow for default-deny and the XDP fast path (non-initial
+    // fragments have no ports to key it on)
+    if !meta.fragment && conntrack::params().is_some() {
+        conntrack::track(&meta.outbound_flow());
     }
 
+    with_stats(|stats| stats.egress.count_passed(pkt_len));
     Ok(0) // TC_ACT_OK
 }
@@ -4,10 +4,18 @@
 
 #![cfg_attr(feature = "ebpf", no_std)]
 
-/// Packet action in blocklist maps.
+pub mod packet;
+
+/// Packet action in blocklist and rule maps.
+///
+/// In the ordered rule table, `Pass` is an explicit allow: a matching packet
+/// skips every later check (blocklists, port rules, rate limiting).
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum PacketAction {
+    #[cfg_attr(feature = "serde", serde(alias = "allow", alias = "accept"))]
     Pass = 0,
     Drop = 1,
 }
@@ -21,41 +29,1897 @@ impl From<u32> for PacketAction {
     }
 }
 
-/// Per-CPU statistics.
+/// Value stored in blocklist maps: the entry's action plus hit counters.
+///
+/// The counters are shared across CPUs and updated atomically by the eBPF
+/// programs whenever the entry decides a packet's fate.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuleEntry {
+    pub action: u32,
+    /// Index of the configured rule this entry was compiled from, for maps
+    /// where one rule expands into several entries (e.g. port ranges)
+    pub rule: u32,
+    pub packets: u64,
+    pub bytes: u64,
+    /// `bpf_ktime_get_ns` time at which the daemon removes a temporary entry,
+    /// or 0 for entries owned by the configuration
+    pub expires_ns: u64,
+}
+
+impl RuleEntry {
+    pub const fn new(action: PacketAction) -> Self {
+        Self {
+            action: action as u32,
+            rule: 0,
+            packets: 0,
+            bytes: 0,
+            expires_ns: 0,
+        }
+    }
+
+    pub const fn with_rule(mut self, rule: u32) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    pub const fn with_expiry(mut self, expires_ns: u64) -> Self {
+        self.expires_ns = expires_ns;
+        self
+    }
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for RuleEntry {}
+
+/// Packet and byte counters for one traffic direction.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-pub struct Stats {
+pub struct DirectionStats {
     pub packets_total: u64,
     pub packets_passed: u64,
     pub packets_dropped: u64,
+    pub bytes_total: u64,
+    pub bytes_passed: u64,
+    pub bytes_dropped: u64,
+}
+
+impl DirectionStats {
+    #[inline(always)]
+    pub fn count_total(&mut self, bytes: u64) {
+        self.packets_total += 1;
+        self.bytes_total += bytes;
+    }
+
+    #[inline(always)]
+    pub fn count_passed(&mut self, bytes: u64) {
+        self.packets_passed += 1;
+        self.bytes_passed += bytes;
+    }
+
+    #[inline(always)]
+    pub fn count_dropped(&mut self, bytes: u64) {
+        self.packets_dropped += 1;
+        self.bytes_dropped += bytes;
+    }
+}
+
+impl core::ops::AddAssign for DirectionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.packets_total += other.packets_total;
+        self.packets_passed += other.packets_passed;
+        self.packets_dropped += other.packets_dropped;
+        self.bytes_total += other.bytes_total;
+        self.bytes_passed += other.bytes_passed;
+        self.bytes_dropped += other.bytes_dropped;
+    }
+}
+
+/// IP protocol number for TCP.
+pub const IPPROTO_TCP: u8 = 6;
+/// IP protocol number for UDP.
+pub const IPPROTO_UDP: u8 = 17;
+
+/// IP protocol number for ICMP.
+pub const IPPROTO_ICMP: u8 = 1;
+/// IP protocol number for ICMPv6.
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// ICMP echo reply type.
+pub const ICMP_ECHO_REPLY: u8 = 0;
+/// ICMP destination unreachable type.
+pub const ICMP_DEST_UNREACH: u8 = 3;
+/// ICMP destination unreachable code for "fragmentation needed" (path MTU discovery).
+pub const ICMP_FRAG_NEEDED: u8 = 4;
+/// ICMP echo request type.
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+/// ICMP time exceeded type.
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+/// ICMP parameter problem type.
+pub const ICMP_PARAM_PROBLEM: u8 = 12;
+/// ICMPv6 destination unreachable type.
+pub const ICMPV6_DEST_UNREACH: u8 = 1;
+/// ICMPv6 packet too big type.
+pub const ICMPV6_PKT_TOOBIG: u8 = 2;
+/// ICMPv6 time exceeded type.
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+/// ICMPv6 parameter problem type.
+pub const ICMPV6_PARAM_PROBLEM: u8 = 4;
+/// ICMPv6 echo request type.
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+/// ICMPv6 echo reply type.
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+/// First and last ICMPv6 neighbor discovery types (router solicitation to redirect).
+pub const ICMPV6_NDP_FIRST: u8 = 133;
+pub const ICMPV6_NDP_LAST: u8 = 137;
+
+/// Prefix length of a fully specified port key: 8 protocol bits + 16 port bits.
+pub const PORT_KEY_BITS: u32 = 24;
+
+/// Builds the `PORT_BLOCKLIST` LPM key data for a protocol and port.
+///
+/// The protocol comes first so that a prefix can never span protocols, followed
+/// by the port in network byte order. The last byte is padding and is never
+/// covered by a prefix.
+#[inline(always)]
+pub const fn port_key(proto: u8, port: u16) -> [u8; 4] {
+    let port = port.to_be_bytes();
+    [proto, port[0], port[1], 0]
+}
+
+/// Splits an inclusive port range into the minimal set of aligned prefix
+/// blocks, yielding `(first_port, port_prefix_bits)` for each.
+///
+/// Any range decomposes into at most 30 blocks, so `PORT_BLOCKLIST` holds
+/// ranges without one entry per port.
+#[derive(Clone, Debug)]
+pub struct PortRangePrefixes {
+    next: u32,
+    end: u32,
+}
+
+impl PortRangePrefixes {
+    pub fn new(start: u16, end: u16) -> Self {
+        Self {
+            next: start as u32,
+            end: end as u32,
+        }
+    }
+}
+
+impl Iterator for PortRangePrefixes {
+    type Item = (u16, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.end {
+            return None;
+        }
+
+        // Largest power-of-two block aligned at `next` that stays within the range
+        let mut size = if self.next == 0 {
+            1 << 16
+        } else {
+            self.next & self.next.wrapping_neg()
+        };
+        while self.next + size - 1 > self.end {
+            size >>= 1;
+        }
+
+        let start = self.next as u16;
+        self.next += size;
+        Some((start, 16 - size.trailing_zeros()))
+    }
+}
+
+/// Highest valid 802.1Q VLAN ID (4095 is reserved)
+pub const MAX_VLAN_ID: u16 = 4094;
+
+/// Maximum number of rules in the ordered rule table. The `RULES` map holds
+/// two banks of this size (see [`RuleSet`]).
+pub const MAX_FILTER_RULES: u32 = 64;
+
+/// The active bank of the double-buffered `RULES` table and its rule count.
+///
+/// Userspace writes a new ruleset into the inactive bank and then switches
+/// banks by updating `RULE_SET`, a single `u32`, so the datapath always sees
+/// either the old or the new ruleset in full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuleSet {
+    /// 0 or 1
+    pub bank: u32,
+    pub count: u32,
+}
+
+impl RuleSet {
+    const BANK_SHIFT: u32 = 16;
+    const COUNT_MASK: u32 = (1 << Self::BANK_SHIFT) - 1;
+
+    /// Decodes a `RULE_SET` value.
+    #[inline(always)]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self {
+            bank: (bits >> Self::BANK_SHIFT) & 1,
+            count: bits & Self::COUNT_MASK,
+        }
+    }
+
+    pub const fn to_bits(self) -> u32 {
+        ((self.bank & 1) << Self::BANK_SHIFT) | (self.count & Self::COUNT_MASK)
+    }
+
+    /// `RULES` index of the first rule in the bank.
+    #[inline(always)]
+    pub const fn base(self) -> u32 {
+        (self.bank & 1) * MAX_FILTER_RULES
+    }
+}
+
+/// Addresses as four 32-bit words in network byte order. IPv4 addresses are
+/// stored IPv4-mapped (`::ffff:a.b.c.d`) so both families share one layout.
+pub type Addr128 = [u32; 4];
+
+/// Converts an IPv4 address (as read from the header, network byte order) to
+/// its IPv4-mapped [`Addr128`] form.
+#[inline(always)]
+pub const fn ipv4_mapped(addr: u32) -> Addr128 {
+    [0, 0, u32::from_ne_bytes([0, 0, 0xff, 0xff]), addr]
+}
+
+/// One compiled entry of the ordered 5-tuple rule table.
+///
+/// A zero mask, a zero protocol, a zero VLAN ID, a zero interface role and a
+/// full port range each act as wildcards.
+/// Entries are evaluated in array order and the first match decides.
+///
+/// Non-initial IP fragments carry no transport header, so only rules without
+/// a port range can match them; addresses, protocol, VLAN and interface role
+/// are matched as usual.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilterRule {
+    pub src_addr: Addr128,
+    pub src_mask: Addr128,
+    pub dst_addr: Addr128,
+    pub dst_mask: Addr128,
+    pub src_port_min: u16,
+    pub src_port_max: u16,
+    pub dst_port_min: u16,
+    pub dst_port_max: u16,
+    /// Outer VLAN ID, or 0 for any (including untagged)
+    pub vlan: u16,
+    /// IP protocol number, or 0 for any
+    pub proto: u8,
+    /// Role of the ingress interface (`InterfaceRole`), or 0 for any
+    pub role: u8,
+    pub _pad: [u8; 4],
+    /// Action (`Pass` or `Drop`), config index and hit counters
+    pub entry: RuleEntry,
+}
+
+impl FilterRule {
+    /// Returns whether this rule applies to packets arriving on an interface
+    /// with the given role.
+    #[inline(always)]
+    pub fn applies_to(&self, role: InterfaceRole) -> bool {
+        self.role == 0 || self.role as u32 == role as u32
+    }
+
+    /// Returns whether the rule only matches some source or destination ports.
+    #[inline(always)]
+    pub fn has_ports(&self) -> bool {
+        self.src_port_min != 0
+            || self.src_port_max != u16::MAX
+            || self.dst_port_min != 0
+            || self.dst_port_max != u16::MAX
+    }
+
+    /// Returns whether a packet's 5-tuple and VLAN ID (0 if untagged) match
+    /// this rule.
+    #[inline(always)]
+    pub fn matches(
+        &self,
+        src: &Addr128,
+        dst: &Addr128,
+        proto: u8,
+        src_port: u16,
+        dst_port: u16,
+        vlan: u16,
+    ) -> bool {
+        (self.proto == 0 || self.proto == proto)
+            && (self.vlan == 0 || self.vlan == vlan)
+            && src_port >= self.src_port_min
+            && src_port <= self.src_port_max
+            && dst_port >= self.dst_port_min
+            && dst_port <= self.dst_port_max
+            && addr_matches(src, &self.src_addr, &self.src_mask)
+            && addr_matches(dst, &self.dst_addr, &self.dst_mask)
+    }
+}
+
+#[inline(always)]
+fn addr_matches(addr: &Addr128, net: &Addr128, mask: &Addr128) -> bool {
+    (addr[0] & mask[0]) == net[0]
+        && (addr[1] & mask[1]) == net[1]
+        && (addr[2] & mask[2]) == net[2]
+        && (addr[3] & mask[3]) == net[3]
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for FilterRule {}
+
+/// Connection tracking key, oriented from the router's side of the flow.
+///
+/// The TC egress program records outbound packets as `local = src`,
+/// `remote = dst`; the XDP program looks up inbound packets with the roles
+/// reversed. For ICMP echo the identifier stands in for the local port.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlowKey {
+    pub local: Addr128,
+    pub remote: Addr128,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub proto: u8,
+    pub _pad: [u8; 3],
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for FlowKey {}
+
+/// Connection tracking state for one flow.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlowState {
+    /// `bpf_ktime_get_ns` timestamp of the last packet in either direction
+    pub last_seen_ns: u64,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for FlowState {}
+
+/// Connection tracking settings read by the eBPF programs (single-entry array map).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConntrackParams {
+    /// Non-zero to drop unsolicited inbound traffic
+    pub default_deny: u32,
+    /// Non-zero to record outbound flows (needed by default-deny and the fast path)
+    pub track: u32,
+    pub tcp_timeout_ns: u64,
+    pub udp_timeout_ns: u64,
+    /// Timeout for ICMP and any other protocol
+    pub other_timeout_ns: u64,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for ConntrackParams {}
+
+/// XDP fast-path settings read by the eBPF program (single-entry array map).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FastPathParams {
+    /// Non-zero to redirect established flows with `bpf_redirect`
+    pub enabled: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for FastPathParams {}
+
+/// Anti-spoofing settings read by the XDP program (single-entry array map).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AntiSpoofParams {
+    /// Non-zero to drop bogon and LAN sources on upstream ingress (see
+    /// [`InterfaceRole::is_upstream`])
+    pub enabled: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for AntiSpoofParams {}
+
+/// Source prefixes that are never valid on WAN ingress: private, shared,
+/// loopback, link-local (IPv4), documentation, benchmarking, multicast and
+/// reserved ranges. IPv6 link-local sources are legitimate on WAN (neighbor
+/// discovery, DHCPv6) and are not listed.
+pub const BOGON_PREFIXES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "100::/64",
+    "2001:db8::/32",
+    "3fff::/20",
+    "fc00::/7",
+    "ff00::/8",
+];
+
+/// Role of an interface the firewall is attached to, which selects the checks
+/// its traffic goes through and the ordered rules that apply to it.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum InterfaceRole {
+    /// Not in the interface configuration (e.g. attached with `--interface`);
+    /// checked like an upstream interface, but only rules without an
+    /// interface role apply
+    #[default]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Unassigned = 0,
+    Wan = 1,
+    Lan = 2,
+    Guest = 3,
+    Vpn = 4,
+}
+
+impl From<u32> for InterfaceRole {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => InterfaceRole::Wan,
+            2 => InterfaceRole::Lan,
+            3 => InterfaceRole::Guest,
+            4 => InterfaceRole::Vpn,
+            _ => InterfaceRole::Unassigned,
+        }
+    }
+}
+
+impl InterfaceRole {
+    /// Whether the interface faces networks that may not open connections to
+    /// the router: default-deny applies to its ingress, and its egress
+    /// records flows and applies the egress blocklist. LAN and guest clients
+    /// open connections themselves, so neither applies to them.
+    #[inline(always)]
+    pub const fn is_upstream(self) -> bool {
+        matches!(
+            self,
+            InterfaceRole::Unassigned | InterfaceRole::Wan | InterfaceRole::Vpn
+        )
+    }
+}
+
+/// Handling of non-initial IP fragments, which carry no transport header,
+/// on upstream interfaces once no ordered rule matched them.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FragmentPolicy {
+    /// Check the source blocklist, then skip the port-based checks
+    #[default]
+    SourceOnly = 0,
+    Pass = 1,
+    Drop = 2,
+}
+
+impl From<u32> for FragmentPolicy {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => FragmentPolicy::Pass,
+            2 => FragmentPolicy::Drop,
+            _ => FragmentPolicy::SourceOnly,
+        }
+    }
+}
+
+/// Handling of packets whose headers are truncated or invalid.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MalformedPolicy {
+    #[default]
+    Drop = 0,
+    Pass = 1,
+}
+
+impl From<u32> for MalformedPolicy {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => MalformedPolicy::Pass,
+            _ => MalformedPolicy::Drop,
+        }
+    }
+}
+
+/// Packet validation settings read by the XDP program (single-entry array map).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidationParams {
+    /// A `FragmentPolicy`
+    pub fragments: u32,
+    /// A `MalformedPolicy`
+    pub malformed: u32,
+    /// Non-zero to drop TCP packets with invalid flag combinations
+    pub tcp_flags: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for ValidationParams {}
+
+/// Per-CPU statistics, split by direction (XDP ingress, TC egress).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    pub ingress: DirectionStats,
+    pub egress: DirectionStats,
+    /// Ingress packets dropped by the per-source rate limiter
+    pub rate_limited: u64,
+    /// Ingress TCP SYNs dropped by the per-source SYN limiter
+    pub syn_limited: u64,
+    /// Unsolicited ingress packets dropped by the default-deny policy
+    pub conntrack_dropped: u64,
+    /// Ingress packets forwarded by the XDP fast path
+    pub fast_path: u64,
+    /// Ingress packets left to the kernel stack while the fast path is enabled
+    pub slow_path: u64,
+    /// Ingress packets with truncated or invalid headers
+    pub malformed: u64,
+    /// Ingress packets dropped from sources detected as port scanning
+    pub port_scan_dropped: u64,
+    /// WAN ingress packets dropped for a bogon or spoofed LAN source address
+    pub spoofed: u64,
+    /// Ingress TCP packets dropped for carrying no flags
+    pub tcp_null: u64,
+    /// Ingress TCP packets dropped for carrying FIN, PSH and URG
+    pub tcp_xmas: u64,
+    /// Ingress TCP packets dropped for carrying both SYN and FIN
+    pub tcp_syn_fin: u64,
+    /// Ingress ICMP/ICMPv6 packets dropped by an ICMP rule
+    pub icmp_dropped: u64,
+    /// Ingress ICMP/ICMPv6 packets dropped by the ICMP rate limit
+    pub icmp_rate_limited: u64,
+}
+
+impl core::ops::AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        self.ingress += other.ingress;
+        self.egress += other.egress;
+        self.rate_limited += other.rate_limited;
+        self.syn_limited += other.syn_limited;
+        self.conntrack_dropped += other.conntrack_dropped;
+        self.fast_path += other.fast_path;
+        self.slow_path += other.slow_path;
+        self.malformed += other.malformed;
+        self.port_scan_dropped += other.port_scan_dropped;
+        self.spoofed += other.spoofed;
+        self.tcp_null += other.tcp_null;
+        self.tcp_xmas += other.tcp_xmas;
+        self.tcp_syn_fin += other.tcp_syn_fin;
+        self.icmp_dropped += other.icmp_dropped;
+        self.icmp_rate_limited += other.icmp_rate_limited;
+    }
 }
 
 #[cfg(feature = "aya")]
 unsafe impl aya::Pod for Stats {}
 
-/// Configuration for a firewall rule.
+/// Why a packet was dropped, as reported in a `DropEvent`.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DropReason {
+    Unknown = 0,
+    /// A drop rule in the ordered rule table (`DropEvent::rule` is its index)
+    Rule = 1,
+    /// Source address blocklist
+    Blocklist = 2,
+    /// Destination port blocklist (`DropEvent::rule` is the port rule index)
+    PortBlocklist = 3,
+    /// Egress destination blocklist
+    EgressBlocklist = 4,
+    /// Per-source packet rate limit
+    RateLimited = 5,
+    /// Per-source SYN rate limit
+    SynLimited = 6,
+    /// Default-deny: no tracked flow
+    Conntrack = 7,
+    /// Non-initial IP fragment under the `drop` fragment policy
+    Fragment = 8,
+    /// Source probed more distinct ports than the port-scan threshold
+    PortScan = 9,
+    /// Bogon or LAN source address on WAN ingress
+    Spoofed = 10,
+    /// Invalid TCP flag combination (NULL, XMAS or SYN+FIN)
+    TcpFlags = 11,
+    /// An ICMP/ICMPv6 rule with the `drop` action
+    Icmp = 12,
+    /// Per-source rate limit of ICMP rules with the `rate_limit` action
+    IcmpRateLimited = 13,
+}
+
+impl From<u32> for DropReason {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => DropReason::Rule,
+            2 => DropReason::Blocklist,
+            3 => DropReason::PortBlocklist,
+            4 => DropReason::EgressBlocklist,
+            5 => DropReason::RateLimited,
+            6 => DropReason::SynLimited,
+            7 => DropReason::Conntrack,
+            8 => DropReason::Fragment,
+            9 => DropReason::PortScan,
+            10 => DropReason::Spoofed,
+            11 => DropReason::TcpFlags,
+            12 => DropReason::Icmp,
+            13 => DropReason::IcmpRateLimited,
+            _ => DropReason::Unknown,
+        }
+    }
+}
+
+/// A dropped packet, pushed by the eBPF programs into the `EVENTS` ring buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropEvent {
+    /// `bpf_ktime_get_ns` timestamp (CLOCK_MONOTONIC)
+    pub timestamp_ns: u64,
+    pub src: Addr128,
+    pub dst: Addr128,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    pub is_ipv6: u8,
+    pub _pad: [u8; 2],
+    /// A `DropReason`
+    pub reason: u32,
+    /// Config index of the matching rule, where the reason has one
+    pub rule: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for DropEvent {}
+
+/// Largest number of bytes copied from each captured packet
+pub const CAPTURE_SNAPLEN_MAX: usize = 256;
+
+/// Packet capture filter read by the eBPF programs (single-entry array map).
+///
+/// A zero mask, port or protocol acts as a wildcard; the address and port
+/// match either direction.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CaptureParams {
+    /// Non-zero while a capture is running
+    pub enabled: u32,
+    /// Capture roughly one in `sample_rate` matching packets (0 or 1 for all)
+    pub sample_rate: u32,
+    /// Bytes copied per packet, at most `CAPTURE_SNAPLEN_MAX`
+    pub snaplen: u32,
+    pub addr: Addr128,
+    pub addr_mask: Addr128,
+    pub port: u16,
+    /// IP protocol number, or 0 for any
+    pub proto: u8,
+    pub _pad: u8,
+}
+
+impl CaptureParams {
+    /// Returns whether a packet matches the capture filter.
+    #[inline(always)]
+    pub fn matches(
+        &self,
+        src: &Addr128,
+        dst: &Addr128,
+        proto: u8,
+        src_port: u16,
+        dst_port: u16,
+    ) -> bool {
+        (self.proto == 0 || self.proto == proto)
+            && (self.port == 0 || self.port == src_port || self.port == dst_port)
+            && (addr_matches(src, &self.addr, &self.addr_mask)
+                || addr_matches(dst, &self.addr, &self.addr_mask))
+    }
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for CaptureParams {}
+
+/// A captured packet, pushed by the eBPF programs into the `CAPTURE` ring buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureRecord {
+    /// `bpf_ktime_get_ns` timestamp (CLOCK_MONOTONIC)
+    pub timestamp_ns: u64,
+    /// Length of the packet on the wire
+    pub orig_len: u32,
+    /// Number of valid bytes in `data`
+    pub cap_len: u32,
+    pub data: [u8; CAPTURE_SNAPLEN_MAX],
+}
+
+/// Upper bound on configured rates, keeping the token bucket arithmetic in
+/// the eBPF program free of overflow.
+pub const MAX_RATE_PPS: u32 = 1_000_000;
+
+/// Rate limiter settings read by the XDP program (single-entry array map).
+///
+/// A rate of zero disables the corresponding limiter.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimitParams {
+    /// Sustained packets per second allowed from a single source
+    pub pps: u32,
+    /// Packets a source may send in a burst above `pps`
+    pub burst: u32,
+    /// Sustained TCP SYNs per second allowed from a single source
+    pub syn_pps: u32,
+    /// SYNs a source may send in a burst above `syn_pps`
+    pub syn_burst: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for RateLimitParams {}
+
+/// Token bucket state for one source address.
+///
+/// Tokens are scaled by 10^9 so that refilling is a plain multiplication of
+/// elapsed nanoseconds by the configured rate.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokenBucket {
+    pub tokens: u64,
+    pub last_ns: u64,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for TokenBucket {}
+
+/// Per-source rate limiting for WAN ingress.
 #[cfg(feature = "serde")]
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
-pub struct FirewallConfig {
-    /// Blocked IP addresses (IPv4 as dotted-decimal strings)
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RateLimitConfig {
+    /// Sustained packets per second per source (0 = unlimited)
     #[serde(default)]
-    pub blocked_ips: Vec<String>,
-    /// Blocked destination ports
+    pub packets_per_second: u32,
+    /// Burst size in packets (defaults to `packets_per_second`)
     #[serde(default)]
-    pub blocked_ports: Vec<u16>,
-    /// Blocked egress IP addresses (LAN -> WAN)
+    pub burst: Option<u32>,
+    /// Sustained TCP SYNs per second per source (0 = unlimited)
     #[serde(default)]
-    pub blocked_egress_ips: Vec<String>,
+    pub syn_per_second: u32,
+    /// SYN burst size (defaults to `syn_per_second`)
+    #[serde(default)]
+    pub syn_burst: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl RateLimitConfig {
+    /// Converts to the eBPF representation, rejecting rates above [`MAX_RATE_PPS`].
+    pub fn to_params(&self) -> Result<RateLimitParams, String> {
+        if self.packets_per_second > MAX_RATE_PPS || self.syn_per_second > MAX_RATE_PPS {
+            return Err(format!(
+                "rate limits must not exceed {MAX_RATE_PPS} packets per second"
+            ));
+        }
+
+        Ok(RateLimitParams {
+            pps: self.packets_per_second,
+            burst: self.burst.unwrap_or(self.packets_per_second),
+            syn_pps: self.syn_per_second,
+            syn_burst: self.syn_burst.unwrap_or(self.syn_per_second),
+        })
+    }
+}
+
+/// Highest distinct-port threshold for port-scan detection. Ports are
+/// tracked in a [`SCAN_PORT_BITS`]-bit bitmap, so counts saturate well above it.
+pub const MAX_SCAN_THRESHOLD: u32 = 256;
+
+/// Size of the per-source bitmap of destination ports seen in a window
+pub const SCAN_PORT_BITS: u32 = 1024;
+
+/// Longest port-scan detection window in seconds
+pub const MAX_SCAN_WINDOW_SECS: u64 = 3600;
+
+/// Port-scan detection settings read by the XDP program (single-entry array map).
+///
+/// A threshold of zero disables detection.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortScanParams {
+    /// Distinct destination ports a source may probe per window
+    pub threshold: u32,
+    pub _pad: u32,
+    pub window_ns: u64,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for PortScanParams {}
+
+/// Distinct destination ports probed by one source.
+///
+/// Ports are hashed into a bitmap per fixed window. The count over the
+/// sliding window is estimated from the current window plus the previous
+/// one, weighted by how much of it the sliding window still covers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanState {
+    pub window_start_ns: u64,
+    /// Distinct ports seen in the current window
+    pub count: u32,
+    /// Distinct ports seen in the previous window
+    pub prev_count: u32,
+    pub ports: [u64; (SCAN_PORT_BITS / 64) as usize],
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for ScanState {}
+
+impl ScanState {
+    /// Records a probe of `port` at `now_ns` and returns the estimated number
+    /// of distinct ports probed over the last `window_ns`.
+    #[inline(always)]
+    pub fn observe(&mut self, port: u16, now_ns: u64, window_ns: u64) -> u32 {
+        let window_ns = window_ns.max(1);
+        let mut elapsed = now_ns.saturating_sub(self.window_start_ns);
+        if elapsed >= window_ns {
+            // Only the window just ended still overlaps the sliding window
+            self.prev_count = if elapsed < 2 * window_ns {
+                self.count
+            } else {
+                0
+            };
+            self.count = 0;
+            self.ports = [0; (SCAN_PORT_BITS / 64) as usize];
+            self.window_start_ns = now_ns - elapsed % window_ns;
+            elapsed %= window_ns;
+        }
+
+        // Ports that collide share a bit; sequential sweeps never do
+        let bit = port as usize % SCAN_PORT_BITS as usize;
+        let mask = 1u64 << (bit % 64);
+        let word = &mut self.ports[(bit / 64) % self.ports.len()];
+        if *word & mask == 0 {
+            *word |= mask;
+            self.count += 1;
+        }
+
+        let carried = self.prev_count as u64 * (window_ns - elapsed) / window_ns;
+        self.count + carried as u32
+    }
+}
+
+/// Port-scan detection on WAN ingress.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct PortScanConfig {
+    /// Distinct destination ports a source may probe within the window
+    /// before it is treated as scanning (0 = disabled)
+    #[serde(default)]
+    pub threshold: u32,
+    /// Sliding window over which distinct ports are counted
+    #[serde(default = "default_scan_window_secs")]
+    pub window_secs: u64,
+    /// How long a scanning source is blocked (0 = only drop its probes for
+    /// the rest of the window)
+    #[serde(default = "default_scan_block_secs")]
+    pub block_secs: u64,
+}
+
+#[cfg(feature = "serde")]
+fn default_scan_window_secs() -> u64 {
+    10
+}
+
+#[cfg(feature = "serde")]
+fn default_scan_block_secs() -> u64 {
+    3600
 }
 
 #[cfg(feature = "serde")]
-impl Default for FirewallConfig {
+impl Default for PortScanConfig {
     fn default() -> Self {
         Self {
-            blocked_ips: Vec::new(),
-            blocked_ports: Vec::new(),
-            blocked_egress_ips: Vec::new(),
+            threshold: 0,
+            window_secs: default_scan_window_secs(),
+            block_secs: default_scan_block_secs(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl PortScanConfig {
+    /// Converts to the eBPF representation, rejecting thresholds above
+    /// [`MAX_SCAN_THRESHOLD`] and windows outside 1..=[`MAX_SCAN_WINDOW_SECS`].
+    pub fn to_params(&self) -> Result<PortScanParams, String> {
+        if self.threshold > MAX_SCAN_THRESHOLD {
+            return Err(format!(
+                "threshold must not exceed {MAX_SCAN_THRESHOLD} ports"
+            ));
         }
+        if !(1..=MAX_SCAN_WINDOW_SECS).contains(&self.window_secs) {
+            return Err(format!(
+                "window_secs must be between 1 and {MAX_SCAN_WINDOW_SECS}"
+            ));
+        }
+
+        Ok(PortScanParams {
+            threshold: self.threshold,
+            _pad: 0,
+            window_ns: self.window_secs * 1_000_000_000,
+        })
+    }
+}
+
+/// Maximum number of ICMP/ICMPv6 rules (size of the `ICMP_RULES` map).
+pub const MAX_ICMP_RULES: u32 = 64;
+
+/// `IcmpKey::code` of a rule matching every code of its type.
+pub const ICMP_CODE_ANY: u8 = 0xff;
+
+/// What happens to ICMP/ICMPv6 messages matching a rule.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum IcmpAction {
+    #[cfg_attr(feature = "serde", serde(alias = "pass", alias = "accept"))]
+    Allow = 0,
+    Drop = 1,
+    /// Allow within the per-source ICMP rate limit
+    RateLimit = 2,
+}
+
+impl From<u32> for IcmpAction {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => IcmpAction::Drop,
+            2 => IcmpAction::RateLimit,
+            _ => IcmpAction::Allow,
+        }
+    }
+}
+
+/// Key of the `ICMP_RULES` map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IcmpKey {
+    /// `IPPROTO_ICMP` or `IPPROTO_ICMPV6`
+    pub proto: u8,
+    pub icmp_type: u8,
+    /// Message code, or [`ICMP_CODE_ANY`]
+    pub code: u8,
+    pub _pad: u8,
+}
+
+impl IcmpKey {
+    pub const fn new(proto: u8, icmp_type: u8, code: u8) -> Self {
+        Self {
+            proto,
+            icmp_type,
+            code,
+            _pad: 0,
+        }
+    }
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for IcmpKey {}
+
+/// ICMP rate limit read by the XDP program (single-entry array map).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IcmpParams {
+    /// Sustained ICMP messages per second allowed from a single source
+    pub pps: u32,
+    /// Messages a source may send in a burst above `pps`
+    pub burst: u32,
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for IcmpParams {}
+
+/// ICMP version matched by an ICMP rule.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IcmpProtocol {
+    Icmp,
+    Icmpv6,
+}
+
+#[cfg(feature = "serde")]
+impl IcmpProtocol {
+    pub fn number(self) -> u8 {
+        match self {
+            IcmpProtocol::Icmp => IPPROTO_ICMP,
+            IcmpProtocol::Icmpv6 => IPPROTO_ICMPV6,
+        }
+    }
+}
+
+/// An ICMP/ICMPv6 rule by message type and, optionally, code.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct IcmpRuleConfig {
+    pub proto: IcmpProtocol,
+    #[serde(rename = "type")]
+    pub icmp_type: u8,
+    /// Matches every code of the type when unset
+    #[serde(default)]
+    pub code: Option<u8>,
+    pub action: IcmpAction,
+}
+
+/// ICMP/ICMPv6 filtering on ingress.
+///
+/// Messages without a matching rule are allowed. Rules with a specific code
+/// take precedence over rules for the whole type. Path MTU messages (ICMP
+/// "fragmentation needed" and ICMPv6 "packet too big") are always allowed.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct IcmpConfig {
+    #[serde(default)]
+    pub rules: Vec<IcmpRuleConfig>,
+    /// Sustained messages per second per source for `rate_limit` rules
+    #[serde(default)]
+    pub packets_per_second: u32,
+    /// Burst size in messages (defaults to `packets_per_second`)
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl IcmpConfig {
+    /// Converts to the eBPF representation: the `ICMP_RULES` entries and the
+    /// rate limit. Later rules for the same type and code replace earlier ones.
+    pub fn compile(&self) -> Result<(Vec<(IcmpKey, IcmpAction)>, IcmpParams), String> {
+        if self.packets_per_second > MAX_RATE_PPS {
+            return Err(format!("packets_per_second must not exceed {MAX_RATE_PPS}"));
+        }
+        if self.rules.len() > MAX_ICMP_RULES as usize {
+            return Err(format!(
+                "too many ICMP rules: {} (maximum {MAX_ICMP_RULES})",
+                self.rules.len()
+            ));
+        }
+
+        let mut entries = Vec::with_capacity(self.rules.len());
+        for rule in &self.rules {
+            let code = rule.code.unwrap_or(ICMP_CODE_ANY);
+            if rule.code == Some(ICMP_CODE_ANY) {
+                return Err(format!("ICMP code {ICMP_CODE_ANY} is reserved"));
+            }
+            let key = IcmpKey::new(rule.proto.number(), rule.icmp_type, code);
+            if rule.action != IcmpAction::Allow && key.covers_pmtu() {
+                return Err(format!(
+                    "{:?} type {} carries path MTU messages, which are always allowed",
+                    rule.proto, rule.icmp_type
+                ));
+            }
+            if rule.action == IcmpAction::RateLimit && self.packets_per_second == 0 {
+                return Err("rate_limit rules need packets_per_second".into());
+            }
+            entries.retain(|(existing, _)| *existing != key);
+            entries.push((key, rule.action));
+        }
+
+        let params = IcmpParams {
+            pps: self.packets_per_second,
+            burst: self.burst.unwrap_or(self.packets_per_second),
+        };
+        Ok((entries, params))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl IcmpKey {
+    /// Whether a rule with this key would match path MTU messages.
+    fn covers_pmtu(&self) -> bool {
+        let codes = |code| self.code == ICMP_CODE_ANY || self.code == code;
+        match (self.proto, self.icmp_type) {
+            (IPPROTO_ICMP, ICMP_DEST_UNREACH) => codes(ICMP_FRAG_NEEDED),
+            (IPPROTO_ICMPV6, ICMPV6_PKT_TOOBIG) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Stateful connection tracking for default-deny ingress.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ConntrackConfig {
+    /// Drop inbound packets that do not belong to a tracked outbound flow
+    #[serde(default)]
+    pub default_deny: bool,
+    /// Maximum tracked flows (applied when the eBPF maps are created at startup)
+    #[serde(default = "default_conntrack_entries")]
+    pub max_entries: u32,
+    /// Idle timeout for TCP flows
+    #[serde(default = "default_tcp_timeout")]
+    pub tcp_timeout_secs: u64,
+    /// Idle timeout for UDP flows
+    #[serde(default = "default_udp_timeout")]
+    pub udp_timeout_secs: u64,
+    /// Idle timeout for ICMP and other protocols
+    #[serde(default = "default_other_timeout")]
+    pub other_timeout_secs: u64,
+}
+
+#[cfg(feature = "serde")]
+fn default_conntrack_entries() -> u32 {
+    65536
+}
+#[cfg(feature = "serde")]
+fn default_tcp_timeout() -> u64 {
+    3600
+}
+#[cfg(feature = "serde")]
+fn default_udp_timeout() -> u64 {
+    120
+}
+#[cfg(feature = "serde")]
+fn default_other_timeout() -> u64 {
+    30
+}
+
+#[cfg(feature = "serde")]
+impl Default for ConntrackConfig {
+    fn default() -> Self {
+        Self {
+            default_deny: false,
+            max_entries: default_conntrack_entries(),
+            tcp_timeout_secs: default_tcp_timeout(),
+            udp_timeout_secs: default_udp_timeout(),
+            other_timeout_secs: default_other_timeout(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ConntrackConfig {
+    pub fn to_params(&self) -> ConntrackParams {
+        const NS_PER_SEC: u64 = 1_000_000_000;
+        ConntrackParams {
+            default_deny: self.default_deny as u32,
+            track: self.default_deny as u32,
+            tcp_timeout_ns: self.tcp_timeout_secs.saturating_mul(NS_PER_SEC),
+            udp_timeout_ns: self.udp_timeout_secs.saturating_mul(NS_PER_SEC),
+            other_timeout_ns: self.other_timeout_secs.saturating_mul(NS_PER_SEC),
+        }
+    }
+}
+
+/// Capacities of the size-limited eBPF maps, applied when the maps are created
+/// at startup (or by a datapath upgrade). Changing a size migrates the existing
+/// entries into the resized map.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct MapSizesConfig {
+    /// Prefixes in the ingress blocklist, per address family, shared by
+    /// `blocked_ips` and temporary blocks
+    #[serde(default = "default_blocklist_entries")]
+    pub blocklist: u32,
+    /// Prefixes in the egress blocklist, per address family
+    #[serde(default = "default_blocklist_entries")]
+    pub egress_blocklist: u32,
+    /// Protocol and port prefixes in the port blocklist (a port range takes
+    /// one entry per aligned block, per protocol)
+    #[serde(default = "default_port_blocklist_entries")]
+    pub port_blocklist: u32,
+    /// Sources tracked by each rate limiter (packet, SYN and ICMP)
+    #[serde(default = "default_tracked_sources")]
+    pub rate_limit: u32,
+    /// Sources tracked by port-scan detection
+    #[serde(default = "default_tracked_sources")]
+    pub port_scan: u32,
+}
+
+#[cfg(feature = "serde")]
+fn default_blocklist_entries() -> u32 {
+    4096
+}
+#[cfg(feature = "serde")]
+fn default_port_blocklist_entries() -> u32 {
+    1024
+}
+#[cfg(feature = "serde")]
+fn default_tracked_sources() -> u32 {
+    16384
+}
+
+#[cfg(feature = "serde")]
+impl MapSizesConfig {
+    /// Checks that every map can hold at least one entry.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, size) in [
+            ("blocklist", self.blocklist),
+            ("egress_blocklist", self.egress_blocklist),
+            ("port_blocklist", self.port_blocklist),
+            ("rate_limit", self.rate_limit),
+            ("port_scan", self.port_scan),
+        ] {
+            if size == 0 {
+                return Err(format!("{name} must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Default for MapSizesConfig {
+    fn default() -> Self {
+        Self {
+            blocklist: default_blocklist_entries(),
+            egress_blocklist: default_blocklist_entries(),
+            port_blocklist: default_port_blocklist_entries(),
+            rate_limit: default_tracked_sources(),
+            port_scan: default_tracked_sources(),
+        }
+    }
+}
+
+/// XDP fast-path forwarding of established flows.
+///
+/// Packets of tracked flows are routed with `bpf_fib_lookup` and sent straight
+/// to the egress interface; anything else (including NATed traffic addressed
+/// to the router) continues through the kernel stack. Destinations on the
+/// egress blocklist, and all traffic while a capture runs, also take the
+/// kernel stack so the TC egress program sees them.
+///
+/// Redirected packets bypass netfilter, so they are neither NATed nor
+/// filtered by nftables: do not enable the fast path on a router that
+/// masquerades forwarded traffic.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct FastPathConfig {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[cfg(feature = "serde")]
+impl FastPathConfig {
+    pub fn to_params(&self) -> FastPathParams {
+        FastPathParams {
+            enabled: self.enabled as u32,
+        }
+    }
+}
+
+/// Handling of IP fragments and malformed headers in the XDP program.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ValidationConfig {
+    /// Non-initial fragments: `source_only` (default), `pass` or `drop`
+    #[serde(default)]
+    pub fragments: FragmentPolicy,
+    /// Truncated or invalid headers: `drop` (default) or `pass`
+    #[serde(default)]
+    pub malformed: MalformedPolicy,
+    /// Drop TCP packets with NULL, XMAS or SYN+FIN flag combinations
+    #[serde(default)]
+    pub tcp_flags: bool,
+}
+
+#[cfg(feature = "serde")]
+impl ValidationConfig {
+    pub fn to_params(&self) -> ValidationParams {
+        ValidationParams {
+            fragments: self.fragments as u32,
+            malformed: self.malformed as u32,
+            tcp_flags: self.tcp_flags as u32,
+        }
+    }
+}
+
+/// Anti-spoofing on upstream (WAN and VPN) ingress.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct AntiSpoofConfig {
+    /// Drop upstream packets from [`BOGON_PREFIXES`] or the LAN prefix
+    #[serde(default)]
+    pub enabled: bool,
+    /// Bogon prefixes to accept anyway, such as the upstream network when
+    /// the WAN sits behind another router (address or CIDR)
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[cfg(feature = "serde")]
+impl AntiSpoofConfig {
+    /// Source prefixes for the bogon maps, paired with whether they are
+    /// dropped: the built-in bogons and `lan` (the LAN interface address in
+    /// CIDR form) are, the `allow` exceptions are not. Exceptions are looked
+    /// up by longest prefix, so they carve holes into the wider bogon ranges.
+    pub fn prefixes<'a>(&'a self, lan: Option<&'a str>) -> impl Iterator<Item = (&'a str, bool)> {
+        BOGON_PREFIXES
+            .iter()
+            .copied()
+            .chain(lan)
+            .map(|prefix| (prefix, true))
+            .chain(self.allow.iter().map(|prefix| (prefix.as_str(), false)))
+    }
+}
+
+/// Transport protocol matched by a port rule.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+    #[default]
+    Both,
+}
+
+#[cfg(feature = "serde")]
+impl PortProtocol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PortProtocol::Tcp => "tcp",
+            PortProtocol::Udp => "udp",
+            PortProtocol::Both => "both",
+        }
+    }
+
+    /// IP protocol numbers covered by this selector.
+    pub fn protocols(self) -> &'static [u8] {
+        match self {
+            PortProtocol::Tcp => &[IPPROTO_TCP],
+            PortProtocol::Udp => &[IPPROTO_UDP],
+            PortProtocol::Both => &[IPPROTO_TCP, IPPROTO_UDP],
+        }
+    }
+}
+
+/// Inclusive destination port range, written as `53` or `"6000-6100"`.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "PortSpec", into = "PortSpec")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[cfg(feature = "serde")]
+impl PortRange {
+    /// Aligned prefix blocks covering this range, see [`PortRangePrefixes`].
+    pub fn prefixes(&self) -> PortRangePrefixes {
+        PortRangePrefixes::new(self.start, self.end)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Serialized form of [`PortRange`].
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Single(u16),
+    Range(String),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<PortSpec> for PortRange {
+    type Error = String;
+
+    fn try_from(spec: PortSpec) -> Result<Self, Self::Error> {
+        let (start, end) = match spec {
+            PortSpec::Single(port) => (port, port),
+            PortSpec::Range(s) => {
+                let parse = |p: &str| {
+                    p.trim()
+                        .parse::<u16>()
+                        .map_err(|_| format!("invalid port range: {s}"))
+                };
+                match s.split_once('-') {
+                    Some((start, end)) => (parse(start)?, parse(end)?),
+                    None => {
+                        let port = parse(&s)?;
+                        (port, port)
+                    }
+                }
+            }
+        };
+
+        if start > end {
+            return Err(format!("port range start {start} is after end {end}"));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<PortRange> for PortSpec {
+    fn from(range: PortRange) -> Self {
+        if range.start == range.end {
+            PortSpec::Single(range.start)
+        } else {
+            PortSpec::Range(range.to_string())
+        }
+    }
+}
+
+/// Parses an IPv4/IPv6 address or CIDR prefix (`10.0.0.0/8`, `2001:db8::/32`).
+///
+/// A bare address is treated as a host route (/32 or /128). Host bits beyond
+/// the prefix are masked off.
+#[cfg(feature = "serde")]
+pub fn parse_cidr(s: &str) -> Option<(std::net::IpAddr, u32)> {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, len)) => (addr.trim(), Some(len.trim().parse::<u32>().ok()?)),
+        None => (s.trim(), None),
+    };
+
+    match addr.parse::<IpAddr>().ok()? {
+        IpAddr::V4(addr) => {
+            let prefix_len = prefix_len.unwrap_or(32);
+            if prefix_len > 32 {
+                return None;
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            Some((Ipv4Addr::from(u32::from(addr) & mask).into(), prefix_len))
+        }
+        IpAddr::V6(addr) => {
+            let prefix_len = prefix_len.unwrap_or(128);
+            if prefix_len > 128 {
+                return None;
+            }
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            Some((Ipv6Addr::from(u128::from(addr) & mask).into(), prefix_len))
+        }
+    }
+}
+
+/// Drop rule for destination ports on TCP, UDP or both.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct PortRule {
+    #[serde(default)]
+    pub proto: PortProtocol,
+    pub ports: PortRange,
+}
+
+/// IP protocol matched by an ordered firewall rule.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+}
+
+#[cfg(feature = "serde")]
+impl RuleProtocol {
+    pub fn number(self) -> u8 {
+        match self {
+            RuleProtocol::Tcp => IPPROTO_TCP,
+            RuleProtocol::Udp => IPPROTO_UDP,
+            RuleProtocol::Icmp => IPPROTO_ICMP,
+            RuleProtocol::Icmpv6 => IPPROTO_ICMPV6,
+        }
+    }
+}
+
+/// An ordered 5-tuple firewall rule. Unset fields match anything.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct FilterRuleConfig {
+    /// Label used in logs and hit counters
+    #[serde(default)]
+    pub name: Option<String>,
+    pub action: PacketAction,
+    /// Lower values are evaluated first; equal priorities keep config order
+    #[serde(default)]
+    pub priority: i32,
+    /// Source address or CIDR prefix
+    #[serde(default)]
+    pub src: Option<String>,
+    /// Destination address or CIDR prefix
+    #[serde(default)]
+    pub dst: Option<String>,
+    #[serde(default)]
+    pub proto: Option<RuleProtocol>,
+    #[serde(default)]
+    pub src_port: Option<PortRange>,
+    #[serde(default)]
+    pub dst_port: Option<PortRange>,
+    /// Outer 802.1Q/802.1ad VLAN ID (1-4094); untagged packets never match
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    /// Role of the interface the packet arrives on (`wan`, `lan`, `guest`
+    /// or `vpn`)
+    #[serde(default)]
+    pub interface: Option<InterfaceRole>,
+}
+
+#[cfg(feature = "serde")]
+impl FilterRuleConfig {
+    /// Compiles the rule into its eBPF representation, tagging hit counters
+    /// with `index`.
+    pub fn compile(&self, index: u32) -> Result<FilterRule, String> {
+        let src = self.src.as_deref().map(parse_rule_addr).transpose()?;
+        let dst = self.dst.as_deref().map(parse_rule_addr).transpose()?;
+        if let (Some((_, _, src_v4)), Some((_, _, dst_v4))) = (src, dst)
+            && src_v4 != dst_v4
+        {
+            return Err("src and dst must be the same address family".into());
+        }
+
+        let (src_addr, src_mask, _) = src.unwrap_or_default();
+        let (dst_addr, dst_mask, _) = dst.unwrap_or_default();
+        let src_port = self.src_port.unwrap_or(PortRange {
+            start: 0,
+            end: u16::MAX,
+        });
+        let dst_port = self.dst_port.unwrap_or(PortRange {
+            start: 0,
+            end: u16::MAX,
+        });
+        if let Some(vlan) = self.vlan
+            && !(1..=MAX_VLAN_ID).contains(&vlan)
+        {
+            return Err(format!("invalid VLAN ID {vlan} (expected 1-{MAX_VLAN_ID})"));
+        }
+
+        Ok(FilterRule {
+            src_addr,
+            src_mask,
+            dst_addr,
+            dst_mask,
+            src_port_min: src_port.start,
+            src_port_max: src_port.end,
+            dst_port_min: dst_port.start,
+            dst_port_max: dst_port.end,
+            vlan: self.vlan.unwrap_or(0),
+            proto: self.proto.map_or(0, RuleProtocol::number),
+            role: self.interface.map_or(0, |role| role as u8),
+            _pad: [0; 4],
+            entry: RuleEntry::new(self.action).with_rule(index),
+        })
+    }
+
+    /// Label for logs and hit counters.
+    pub fn label(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("rule-{index}"))
+    }
+}
+
+/// Packet capture filter, as requested through the API. Unset fields match
+/// anything.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct CaptureFilter {
+    /// Address or CIDR prefix matched against source and destination
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Port matched against source and destination
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub proto: Option<RuleProtocol>,
+    /// Capture roughly one in `sample_rate` matching packets
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    /// Bytes captured per packet (default and maximum `CAPTURE_SNAPLEN_MAX`)
+    #[serde(default)]
+    pub snaplen: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl CaptureFilter {
+    /// Compiles the filter into its eBPF representation (enabled).
+    pub fn to_params(&self) -> Result<CaptureParams, String> {
+        let (addr, addr_mask, _) = self
+            .host
+            .as_deref()
+            .map(parse_rule_addr)
+            .transpose()?
+            .unwrap_or_default();
+        let snaplen = self.snaplen.unwrap_or(CAPTURE_SNAPLEN_MAX as u32);
+        if snaplen == 0 || snaplen > CAPTURE_SNAPLEN_MAX as u32 {
+            return Err(format!(
+                "snaplen must be between 1 and {CAPTURE_SNAPLEN_MAX}"
+            ));
+        }
+
+        Ok(CaptureParams {
+            enabled: 1,
+            sample_rate: self.sample_rate.unwrap_or(1),
+            snaplen,
+            addr,
+            addr_mask,
+            port: self.port.unwrap_or(0),
+            proto: self.proto.map_or(0, RuleProtocol::number),
+            _pad: 0,
+        })
+    }
+}
+
+/// Parses a rule address into its IPv4-mapped network, mask and family flag.
+#[cfg(feature = "serde")]
+fn parse_rule_addr(s: &str) -> Result<(Addr128, Addr128, bool), String> {
+    let (addr, prefix_len) = parse_cidr(s).ok_or_else(|| format!("invalid address: {s}"))?;
+
+    let (bytes, prefix_len, is_v4) = match addr {
+        std::net::IpAddr::V4(v4) => (v4.to_ipv6_mapped().octets(), prefix_len + 96, true),
+        std::net::IpAddr::V6(v6) => (v6.octets(), prefix_len, false),
+    };
+    let mask = u128::MAX
+        .checked_shl(128 - prefix_len)
+        .unwrap_or(0)
+        .to_be_bytes();
+
+    let words = |b: [u8; 16]| {
+        core::array::from_fn(|i| {
+            u32::from_ne_bytes([b[4 * i], b[4 * i + 1], b[4 * i + 2], b[4 * i + 3]])
+        })
+    };
+    Ok((words(bytes), words(mask), is_v4))
+}
+
+/// Configuration for a firewall rule.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct FirewallConfig {
+    /// Blocked source addresses (IPv4/IPv6 address or CIDR, e.g. `10.0.0.0/8`, `2001:db8::/32`)
+    #[serde(default)]
+    pub blocked_ips: Vec<String>,
+    /// Blocked destination ports (TCP and UDP, both address families)
+    #[serde(default)]
+    pub blocked_ports: Vec<u16>,
+    /// Protocol-aware destination port rules, optionally covering ranges
+    #[serde(default)]
+    pub port_rules: Vec<PortRule>,
+    /// Blocked egress destination addresses (LAN -> WAN, IPv4/IPv6 address or CIDR)
+    #[serde(default)]
+    pub blocked_egress_ips: Vec<String>,
+    /// Per-source rate limiting and SYN flood protection
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Port-scan detection and automatic blocking
+    #[serde(default)]
+    pub port_scan: PortScanConfig,
+    /// ICMP/ICMPv6 filtering and rate limiting by type and code
+    #[serde(default)]
+    pub icmp: IcmpConfig,
+    /// Ordered 5-tuple rules, evaluated before the blocklists
+    #[serde(default)]
+    pub rules: Vec<FilterRuleConfig>,
+    /// Stateful connection tracking
+    #[serde(default)]
+    pub conntrack: ConntrackConfig,
+    /// XDP fast-path forwarding between the WAN and LAN interfaces
+    #[serde(default)]
+    pub fast_path: FastPathConfig,
+    /// Fragment and malformed-packet handling
+    #[serde(default)]
+    pub validation: ValidationConfig,
+    /// Bogon and spoofed-source filtering on WAN ingress
+    #[serde(default)]
+    pub anti_spoof: AntiSpoofConfig,
+    /// Capacities of the blocklist and per-source tracking maps
+    #[serde(default)]
+    pub map_sizes: MapSizesConfig,
+}
+
+#[cfg(feature = "serde")]
+impl FirewallConfig {
+    /// All port rules in map order: legacy `blocked_ports` (both protocols)
+    /// followed by `port_rules`.
+    pub fn all_port_rules(&self) -> impl Iterator<Item = PortRule> + '_ {
+        let legacy = self.blocked_ports.iter().map(|&port| PortRule {
+            proto: PortProtocol::Both,
+            ports: PortRange {
+                start: port,
+                end: port,
+            },
+        });
+        legacy.chain(self.port_rules.iter().cloned())
+    }
+
+    /// The `PORT_BLOCKLIST` entries of all port rules, in map order: a
+    /// `(proto, port, bits, entry)` prefix per protocol and aligned block of
+    /// each rule's range, dropping with the rule's index as `entry.rule`.
+    pub fn port_entries(&self) -> impl Iterator<Item = (u8, u16, u32, RuleEntry)> + '_ {
+        self.all_port_rules().enumerate().flat_map(|(index, rule)| {
+            let entry = RuleEntry::new(PacketAction::Drop).with_rule(index as u32);
+            rule.proto.protocols().iter().flat_map(move |&proto| {
+                rule.ports
+                    .prefixes()
+                    .map(move |(port, bits)| (proto, port, bits, entry))
+            })
+        })
+    }
+
+    /// Ordered rules sorted for evaluation (by priority, then config order),
+    /// paired with their config index.
+    pub fn ordered_rules(&self) -> Vec<(usize, &FilterRuleConfig)> {
+        let mut rules: Vec<_> = self.rules.iter().enumerate().collect();
+        rules.sort_by_key(|(_, rule)| rule.priority);
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn rule_set_bits_round_trip() {
+        for set in [
+            RuleSet { bank: 0, count: 0 },
+            RuleSet { bank: 1, count: 1 },
+            RuleSet {
+                bank: 1,
+                count: MAX_FILTER_RULES,
+            },
+        ] {
+            assert_eq!(RuleSet::from_bits(set.to_bits()), set);
+        }
+        assert_eq!(RuleSet::from_bits(0), RuleSet::default());
+    }
+
+    #[test]
+    fn rule_set_base() {
+        assert_eq!(RuleSet { bank: 0, count: 5 }.base(), 0);
+        assert_eq!(RuleSet { bank: 1, count: 5 }.base(), MAX_FILTER_RULES);
+    }
+
+    /// Expands prefixes back into the ports they cover.
+    fn covered(prefixes: PortRangePrefixes) -> Vec<u32> {
+        prefixes
+            .flat_map(|(port, bits)| {
+                let port = port as u32;
+                assert!(bits <= 16);
+                let size = 1 << (16 - bits);
+                assert_eq!(port % size, 0, "block at {port} is not aligned");
+                port..port + size
+            })
+            .collect()
+    }
+
+    #[test]
+    fn port_range_prefixes_single_port() {
+        let prefixes: Vec<_> = PortRangePrefixes::new(23, 23).collect();
+        assert_eq!(prefixes, [(23, 16)]);
+    }
+
+    #[test]
+    fn port_range_prefixes_full_range() {
+        let prefixes: Vec<_> = PortRangePrefixes::new(0, u16::MAX).collect();
+        assert_eq!(prefixes, [(0, 0)]);
+    }
+
+    #[test]
+    fn port_range_prefixes_aligned_block() {
+        let prefixes: Vec<_> = PortRangePrefixes::new(1024, 2047).collect();
+        assert_eq!(prefixes, [(1024, 6)]);
+    }
+
+    #[test]
+    fn port_range_prefixes_cover_range_exactly() {
+        for (start, end) in [
+            (5000, 5010),
+            (1, 65534),
+            (1, u16::MAX),
+            (0, 1),
+            (65535, 65535),
+        ] {
+            let ports = covered(PortRangePrefixes::new(start, end));
+            assert_eq!(ports, (start as u32..=end as u32).collect::<Vec<_>>());
+        }
+        assert!(PortRangePrefixes::new(1, 65534).count() <= 30);
+    }
+
+    #[test]
+    fn port_range_prefixes_empty_when_reversed() {
+        assert_eq!(PortRangePrefixes::new(10, 9).count(), 0);
+    }
+
+    fn cidr(s: &str) -> Option<(String, u32)> {
+        parse_cidr(s).map(|(addr, len)| (addr.to_string(), len))
+    }
+
+    #[test]
+    fn parse_cidr_prefix_lengths() {
+        assert_eq!(cidr("0.0.0.0/0"), Some(("0.0.0.0".into(), 0)));
+        assert_eq!(cidr("10.1.2.3/32"), Some(("10.1.2.3".into(), 32)));
+        assert_eq!(cidr("::/0"), Some(("::".into(), 0)));
+        assert_eq!(cidr("2001:db8::1/128"), Some(("2001:db8::1".into(), 128)));
+    }
+
+    #[test]
+    fn parse_cidr_bare_address_is_host_route() {
+        assert_eq!(cidr("192.168.8.1"), Some(("192.168.8.1".into(), 32)));
+        assert_eq!(cidr("2001:db8::1"), Some(("2001:db8::1".into(), 128)));
+    }
+
+    #[test]
+    fn parse_cidr_masks_host_bits() {
+        assert_eq!(cidr("192.168.8.77/24"), Some(("192.168.8.0".into(), 24)));
+        assert_eq!(cidr("255.255.255.255/0"), Some(("0.0.0.0".into(), 0)));
+        assert_eq!(
+            cidr("2001:db8:bad::1/48"),
+            Some(("2001:db8:bad::".into(), 48))
+        );
+        assert_eq!(cidr("2001:db8::1/0"), Some(("::".into(), 0)));
+    }
+
+    #[test]
+    fn parse_cidr_trims_whitespace() {
+        let parsed = parse_cidr(" 10.0.0.0 / 8 ");
+        assert_eq!(parsed, Some((IpAddr::from([10, 0, 0, 0]), 8)));
+    }
+
+    #[test]
+    fn parse_cidr_rejects_bad_input() {
+        for s in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/x",
+            "10.0.0/8",
+            "",
+            "example.com",
+        ] {
+            assert_eq!(parse_cidr(s), None, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn port_entries_expand_rules_in_map_order() {
+        let config = FirewallConfig {
+            blocked_ports: vec![23],
+            port_rules: vec![PortRule {
+                proto: PortProtocol::Udp,
+                ports: PortRange {
+                    start: 5000,
+                    end: 5010,
+                },
+            }],
+            ..Default::default()
+        };
+        let entries: Vec<_> = config.port_entries().collect();
+
+        let drop = |rule| RuleEntry::new(PacketAction::Drop).with_rule(rule);
+        assert_eq!(entries[0], (IPPROTO_TCP, 23, 16, drop(0)));
+        assert_eq!(entries[1], (IPPROTO_UDP, 23, 16, drop(0)));
+        let range: Vec<_> = entries[2..]
+            .iter()
+            .map(|&(proto, port, bits, entry)| {
+                assert_eq!((proto, entry), (IPPROTO_UDP, drop(1)));
+                (port, bits)
+            })
+            .collect();
+        assert_eq!(
+            range,
+            PortRangePrefixes::new(5000, 5010).collect::<Vec<_>>()
+        );
     }
 }
@@ -0,0 +1,695 @@
+//! Packet parsing and stateless classification, shared by the eBPF programs
+//! and host-side tests.
+//!
+//! Parsing works on a `start..end` address range so the same code runs over
+//! XDP/TC packet pointers, where every access must be bounds-checked for the
+//! verifier, and over ordinary byte slices. Header fields are byte arrays, so
+//! headers may sit at any alignment.
+
+use crate::{
+    Addr128, DropReason, FilterRule, FlowKey, FragmentPolicy, ICMP_CODE_ANY, ICMP_DEST_UNREACH,
+    ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST, ICMP_FRAG_NEEDED, ICMP_PARAM_PROBLEM, ICMP_TIME_EXCEEDED,
+    ICMPV6_DEST_UNREACH, ICMPV6_ECHO_REPLY, ICMPV6_ECHO_REQUEST, ICMPV6_PARAM_PROBLEM,
+    ICMPV6_PKT_TOOBIG, ICMPV6_TIME_EXCEEDED, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP,
+    IPPROTO_UDP, IcmpAction, IcmpKey, InterfaceRole, MAX_FILTER_RULES, PacketAction, RuleEntry,
+    ipv4_mapped,
+};
+use core::mem;
+
+pub const ETH_P_IPV4: u16 = 0x0800;
+pub const ETH_P_IPV6: u16 = 0x86dd;
+/// 802.1Q customer tag
+pub const ETH_P_8021Q: u16 = 0x8100;
+/// 802.1ad service tag (QinQ outer tag)
+pub const ETH_P_8021AD: u16 = 0x88a8;
+/// Pre-standard QinQ outer tag
+pub const ETH_P_QINQ: u16 = 0x9100;
+
+/// IPv6 extension header protocol numbers
+pub const IPPROTO_HOPOPTS: u8 = 0;
+pub const IPPROTO_ROUTING: u8 = 43;
+pub const IPPROTO_FRAGMENT: u8 = 44;
+pub const IPPROTO_AH: u8 = 51;
+pub const IPPROTO_DSTOPTS: u8 = 60;
+
+/// TCP header flag bits
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+/// The six original TCP flags (ECE and CWR excluded)
+const TCP_FLAGS_MASK: u8 = 0x3f;
+
+/// Flags of an XMAS scan probe
+const TCP_XMAS: u8 = TCP_FIN | TCP_PSH | TCP_URG;
+
+/// Maximum number of stacked VLAN tags parsed (802.1ad QinQ)
+const MAX_VLAN_DEPTH: usize = 2;
+
+/// VLAN ID bits of the tag control information
+const VLAN_VID_MASK: u16 = 0x0fff;
+
+/// Maximum number of IPv6 extension headers walked; longer chains are malformed
+const MAX_IPV6_EXT_HDRS: usize = 6;
+
+/// Largest header offset `ptr_in` accepts. The verifier only gives packet
+/// pointers a range if offset plus access stay within 64 KiB, and offsets read
+/// back from map memory are otherwise unbounded.
+const MAX_HEADER_OFFSET: usize = 0x7fff;
+
+/// Fragment offset bits of the IPv4 flags/fragment offset field
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+/// Fragment offset bits of the IPv6 fragment header's offset/flags field
+const IPV6_FRAG_OFFSET_MASK: u16 = 0xfff8;
+
+/// Smallest valid IPv4 header length, in 32-bit words
+const IPV4_MIN_IHL: u8 = 5;
+
+#[repr(C)]
+pub struct EthHdr {
+    pub dst_addr: [u8; 6],
+    pub src_addr: [u8; 6],
+    pub ether_type: [u8; 2],
+}
+
+impl EthHdr {
+    pub const LEN: usize = mem::size_of::<EthHdr>();
+}
+
+/// 802.1Q/802.1ad tag, following the MAC addresses in place of the EtherType.
+#[repr(C)]
+pub struct VlanHdr {
+    pub tci: [u8; 2],
+    pub ether_type: [u8; 2],
+}
+
+#[repr(C)]
+pub struct Ipv4Hdr {
+    pub version_ihl: u8,
+    pub tos: u8,
+    pub tot_len: [u8; 2],
+    pub id: [u8; 2],
+    pub frag_off: [u8; 2],
+    pub ttl: u8,
+    pub proto: u8,
+    pub check: [u8; 2],
+    pub src_addr: [u8; 4],
+    pub dst_addr: [u8; 4],
+}
+
+impl Ipv4Hdr {
+    pub const LEN: usize = mem::size_of::<Ipv4Hdr>();
+
+    #[inline(always)]
+    pub fn version(&self) -> u8 {
+        self.version_ihl >> 4
+    }
+
+    /// Header length in 32-bit words
+    #[inline(always)]
+    pub fn ihl(&self) -> u8 {
+        self.version_ihl & 0x0f
+    }
+}
+
+#[repr(C)]
+pub struct Ipv6Hdr {
+    /// Version, traffic class and flow label
+    pub vtc_flow: [u8; 4],
+    pub payload_len: [u8; 2],
+    pub next_hdr: u8,
+    pub hop_limit: u8,
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+}
+
+impl Ipv6Hdr {
+    pub const LEN: usize = mem::size_of::<Ipv6Hdr>();
+
+    #[inline(always)]
+    pub fn version(&self) -> u8 {
+        self.vtc_flow[0] >> 4
+    }
+}
+
+#[repr(C)]
+pub struct TcpHdr {
+    pub source: [u8; 2],
+    pub dest: [u8; 2],
+    pub seq: [u8; 4],
+    pub ack_seq: [u8; 4],
+    /// Data offset (upper four bits) and reserved bits
+    pub doff: u8,
+    /// `TCP_*` flag bits
+    pub flags: u8,
+    pub window: [u8; 2],
+    pub check: [u8; 2],
+    pub urg_ptr: [u8; 2],
+}
+
+#[repr(C)]
+pub struct UdpHdr {
+    pub source: [u8; 2],
+    pub dest: [u8; 2],
+    pub len: [u8; 2],
+    pub check: [u8; 2],
+}
+
+/// Leading fields of an ICMP/ICMPv6 header, including the echo identifier.
+#[repr(C)]
+pub struct IcmpHdr {
+    pub type_: u8,
+    pub code: u8,
+    pub checksum: [u8; 2],
+    pub id: [u8; 2],
+    pub seq: [u8; 2],
+}
+
+impl IcmpHdr {
+    /// Length of the header preceding an error's embedded packet
+    pub const LEN: usize = mem::size_of::<IcmpHdr>();
+}
+
+/// Leading fields shared by IPv6 hop-by-hop, routing, destination options and
+/// authentication extension headers.
+#[repr(C)]
+struct Ipv6ExtHdr {
+    next_hdr: u8,
+    hdr_ext_len: u8,
+}
+
+/// IPv6 fragment extension header (RFC 8200 section 4.5).
+#[repr(C)]
+struct Ipv6FragHdr {
+    next_hdr: u8,
+    _reserved: u8,
+    frag_off: [u8; 2],
+    _ident: [u8; 4],
+}
+
+/// Source and destination ports at the start of a TCP/UDP header.
+#[repr(C)]
+struct PortPair {
+    src: [u8; 2],
+    dst: [u8; 2],
+}
+
+/// Parsed L3/L4 fields the firewall stages match on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketMeta {
+    /// Outer VLAN ID, or 0 if untagged
+    pub vlan_id: u16,
+    /// Offset of the IP header from the start of the frame
+    pub l3_offset: usize,
+    pub is_ipv6: bool,
+    /// Source address (IPv4-mapped for IPv4)
+    pub src: Addr128,
+    /// Destination address (IPv4-mapped for IPv4)
+    pub dst: Addr128,
+    /// Transport protocol
+    pub proto: u8,
+    /// TCP/UDP source port, or the identifier of an ICMP echo request
+    pub src_port: u16,
+    /// TCP/UDP destination port, or the identifier of an ICMP echo reply
+    pub dst_port: u16,
+    /// `TCP_*` flag bits (TCP only)
+    pub tcp_flags: u8,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    /// Offset of the transport header from the start of the frame
+    pub transport_offset: usize,
+    /// Non-initial IP fragment: there is no transport header, so the ports,
+    /// TCP flags and ICMP type and code are left at 0
+    pub fragment: bool,
+}
+
+impl PacketMeta {
+    /// Whether this is a TCP connection request (SYN without ACK).
+    #[inline(always)]
+    pub fn is_syn(&self) -> bool {
+        self.proto == IPPROTO_TCP && self.tcp_flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+    }
+
+    /// Connection tracking key for this packet travelling router -> remote.
+    #[inline(always)]
+    pub fn outbound_flow(&self) -> FlowKey {
+        FlowKey {
+            local: self.src,
+            remote: self.dst,
+            local_port: self.src_port,
+            remote_port: self.dst_port,
+            proto: self.proto,
+            _pad: [0; 3],
+        }
+    }
+
+    /// Connection tracking key for this packet travelling remote -> router.
+    #[inline(always)]
+    pub fn inbound_flow(&self) -> FlowKey {
+        FlowKey {
+            local: self.dst,
+            remote: self.src,
+            local_port: self.dst_port,
+            remote_port: self.src_port,
+            proto: self.proto,
+            _pad: [0; 3],
+        }
+    }
+
+    /// Whether this is an ICMP/ICMPv6 error message carrying an embedded packet.
+    #[inline(always)]
+    pub fn is_icmp_error(&self) -> bool {
+        match self.proto {
+            IPPROTO_ICMP => matches!(
+                self.icmp_type,
+                ICMP_DEST_UNREACH | ICMP_TIME_EXCEEDED | ICMP_PARAM_PROBLEM
+            ),
+            IPPROTO_ICMPV6 => matches!(
+                self.icmp_type,
+                ICMPV6_DEST_UNREACH
+                    | ICMPV6_PKT_TOOBIG
+                    | ICMPV6_TIME_EXCEEDED
+                    | ICMPV6_PARAM_PROBLEM
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether this is a path MTU discovery message (ICMP "fragmentation
+    /// needed" or ICMPv6 "packet too big").
+    #[inline(always)]
+    pub fn is_pmtu(&self) -> bool {
+        match self.proto {
+            IPPROTO_ICMP => {
+                self.icmp_type == ICMP_DEST_UNREACH && self.icmp_code == ICMP_FRAG_NEEDED
+            }
+            IPPROTO_ICMPV6 => self.icmp_type == ICMPV6_PKT_TOOBIG,
+            _ => false,
+        }
+    }
+
+    /// The invalid TCP flag combination this packet carries, if any.
+    #[inline(always)]
+    pub fn tcp_flag_violation(&self) -> Option<TcpFlagViolation> {
+        if self.proto != IPPROTO_TCP || self.fragment {
+            return None;
+        }
+        // ECE and CWR are ignored; only the original six flags are checked
+        let flags = self.tcp_flags & TCP_FLAGS_MASK;
+        if flags == 0 {
+            Some(TcpFlagViolation::Null)
+        } else if flags & TCP_XMAS == TCP_XMAS {
+            Some(TcpFlagViolation::Xmas)
+        } else if flags & (TCP_SYN | TCP_FIN) == TCP_SYN | TCP_FIN {
+            Some(TcpFlagViolation::SynFin)
+        } else {
+            None
+        }
+    }
+}
+
+/// TCP flag combinations no conforming stack sends, used by stealth scans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpFlagViolation {
+    /// No flags set
+    Null,
+    /// FIN, PSH and URG set
+    Xmas,
+    /// SYN and FIN set
+    SynFin,
+}
+
+/// Looks up the ICMP rule for an ICMP/ICMPv6 packet: a rule for its exact
+/// type and code, then one for the whole type. Returns `None` for other
+/// packets, non-initial fragments, path MTU messages and unmatched messages.
+#[inline(always)]
+pub fn icmp_action(
+    meta: &PacketMeta,
+    rules: impl Fn(&IcmpKey) -> Option<IcmpAction>,
+) -> Option<IcmpAction> {
+    if !matches!(meta.proto, IPPROTO_ICMP | IPPROTO_ICMPV6) || meta.fragment || meta.is_pmtu() {
+        return None;
+    }
+    rules(&IcmpKey::new(meta.proto, meta.icmp_type, meta.icmp_code))
+        .or_else(|| rules(&IcmpKey::new(meta.proto, meta.icmp_type, ICMP_CODE_ANY)))
+}
+
+/// Why a frame could not be parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A header extends past the end of the frame
+    Truncated,
+    /// A header field is invalid (IP version mismatch, IPv4 IHL below 5)
+    Malformed,
+}
+
+/// Returns a pointer to a `T` at `offset` if it lies entirely within `start..end`.
+#[inline(always)]
+pub fn ptr_in<T>(start: usize, end: usize, offset: usize) -> Result<*const T, ParseError> {
+    let len = mem::size_of::<T>();
+
+    if offset > MAX_HEADER_OFFSET || start + offset + len > end {
+        return Err(ParseError::Truncated);
+    }
+
+    Ok((start + offset) as *const T)
+}
+
+#[inline(always)]
+fn be16(bytes: [u8; 2]) -> u16 {
+    u16::from_be_bytes(bytes)
+}
+
+/// Network-order address words of an IPv6 address.
+#[inline(always)]
+fn addr_words(bytes: &[u8; 16]) -> Addr128 {
+    [
+        u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        u32::from_ne_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        u32::from_ne_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+    ]
+}
+
+/// Parses the Ethernet, IP and transport headers of the frame in `start..end`.
+///
+/// Up to two 802.1Q/802.1ad VLAN tags are skipped. Returns `None` for frames
+/// that are neither IPv4 nor IPv6. Non-initial fragments are flagged and their
+/// payload is not read as a transport header.
+#[inline(always)]
+pub fn parse_packet(start: usize, end: usize) -> Result<Option<PacketMeta>, ParseError> {
+    let eth_hdr: *const EthHdr = ptr_in(start, end, 0)?;
+    let mut eth_type = be16(unsafe { (*eth_hdr).ether_type });
+    let mut l3_offset = EthHdr::LEN;
+    let mut vlan_id = 0;
+
+    // Skip up to two VLAN tags, remembering the outer VLAN ID
+    for _ in 0..MAX_VLAN_DEPTH {
+        if !matches!(eth_type, ETH_P_8021Q | ETH_P_8021AD | ETH_P_QINQ) {
+            break;
+        }
+        let vlan_hdr: *const VlanHdr = ptr_in(start, end, l3_offset)?;
+        if vlan_id == 0 {
+            vlan_id = be16(unsafe { (*vlan_hdr).tci }) & VLAN_VID_MASK;
+        }
+        eth_type = be16(unsafe { (*vlan_hdr).ether_type });
+        l3_offset += mem::size_of::<VlanHdr>();
+    }
+
+    // Resolve addresses, the transport protocol and its header offset
+    let mut meta = match eth_type {
+        ETH_P_IPV4 => {
+            let ipv4_hdr: *const Ipv4Hdr = ptr_in(start, end, l3_offset)?;
+            let ihl = unsafe { (*ipv4_hdr).ihl() };
+            if unsafe { (*ipv4_hdr).version() } != 4 || ihl < IPV4_MIN_IHL {
+                return Err(ParseError::Malformed);
+            }
+            let ip_hdr_len = ihl as usize * 4;
+            if start + l3_offset + ip_hdr_len > end {
+                return Err(ParseError::Truncated);
+            }
+            let frag_off = be16(unsafe { (*ipv4_hdr).frag_off });
+            PacketMeta {
+                vlan_id,
+                l3_offset,
+                is_ipv6: false,
+                src: ipv4_mapped(u32::from_ne_bytes(unsafe { (*ipv4_hdr).src_addr })),
+                dst: ipv4_mapped(u32::from_ne_bytes(unsafe { (*ipv4_hdr).dst_addr })),
+                proto: unsafe { (*ipv4_hdr).proto },
+                transport_offset: l3_offset + ip_hdr_len,
+                fragment: frag_off & IPV4_FRAG_OFFSET_MASK != 0,
+                ..Default::default()
+            }
+        }
+        ETH_P_IPV6 => {
+            let ipv6_hdr: *const Ipv6Hdr = ptr_in(start, end, l3_offset)?;
+            if unsafe { (*ipv6_hdr).version() } != 6 {
+                return Err(ParseError::Malformed);
+            }
+            let mut meta = PacketMeta {
+                vlan_id,
+                l3_offset,
+                is_ipv6: true,
+                src: addr_words(unsafe { &(*ipv6_hdr).src_addr }),
+                dst: addr_words(unsafe { &(*ipv6_hdr).dst_addr }),
+                proto: unsafe { (*ipv6_hdr).next_hdr },
+                transport_offset: l3_offset + Ipv6Hdr::LEN,
+                ..Default::default()
+            };
+            ipv6_transport(start, end, &mut meta)?;
+            meta
+        }
+        _ => return Ok(None),
+    };
+
+    if meta.fragment {
+        return Ok(Some(meta));
+    }
+
+    // Parse ports (or the ICMP type and echo identifier)
+    match meta.proto {
+        IPPROTO_TCP => {
+            let tcp_hdr: *const TcpHdr = ptr_in(start, end, meta.transport_offset)?;
+            meta.src_port = be16(unsafe { (*tcp_hdr).source });
+            meta.dst_port = be16(unsafe { (*tcp_hdr).dest });
+            meta.tcp_flags = unsafe { (*tcp_hdr).flags };
+        }
+        IPPROTO_UDP => {
+            let udp_hdr: *const UdpHdr = ptr_in(start, end, meta.transport_offset)?;
+            meta.src_port = be16(unsafe { (*udp_hdr).source });
+            meta.dst_port = be16(unsafe { (*udp_hdr).dest });
+        }
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+            let icmp_hdr: *const IcmpHdr = ptr_in(start, end, meta.transport_offset)?;
+            meta.icmp_type = unsafe { (*icmp_hdr).type_ };
+            meta.icmp_code = unsafe { (*icmp_hdr).code };
+            let id = be16(unsafe { (*icmp_hdr).id });
+            match (meta.proto, meta.icmp_type) {
+                (IPPROTO_ICMP, ICMP_ECHO_REQUEST) | (IPPROTO_ICMPV6, ICMPV6_ECHO_REQUEST) => {
+                    meta.src_port = id
+                }
+                (IPPROTO_ICMP, ICMP_ECHO_REPLY) | (IPPROTO_ICMPV6, ICMPV6_ECHO_REPLY) => {
+                    meta.dst_port = id
+                }
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+
+    Ok(Some(meta))
+}
+
+/// Builds the outbound flow key of the packet embedded in an ICMP error.
+///
+/// The embedded packet is one the router sent, so its source is the local
+/// side. Returns `None` if the embedded headers are truncated.
+#[inline(always)]
+pub fn related_flow(start: usize, end: usize, meta: &PacketMeta) -> Option<FlowKey> {
+    let inner_offset = meta.transport_offset + IcmpHdr::LEN;
+
+    let (local, remote, proto, l4_offset) = if meta.is_ipv6 {
+        let inner: *const Ipv6Hdr = ptr_in(start, end, inner_offset).ok()?;
+        unsafe {
+            (
+                addr_words(&(*inner).src_addr),
+                addr_words(&(*inner).dst_addr),
+                (*inner).next_hdr,
+                inner_offset + Ipv6Hdr::LEN,
+            )
+        }
+    } else {
+        let inner: *const Ipv4Hdr = ptr_in(start, end, inner_offset).ok()?;
+        unsafe {
+            (
+                ipv4_mapped(u32::from_ne_bytes((*inner).src_addr)),
+                ipv4_mapped(u32::from_ne_bytes((*inner).dst_addr)),
+                (*inner).proto,
+                inner_offset + (*inner).ihl() as usize * 4,
+            )
+        }
+    };
+
+    let (local_port, remote_port) = match proto {
+        IPPROTO_TCP | IPPROTO_UDP => {
+            let ports: *const PortPair = ptr_in(start, end, l4_offset).ok()?;
+            unsafe { (be16((*ports).src), be16((*ports).dst)) }
+        }
+        _ => (0, 0),
+    };
+
+    Some(FlowKey {
+        local,
+        remote,
+        local_port,
+        remote_port,
+        proto,
+        _pad: [0; 3],
+    })
+}
+
+/// Walks the IPv6 extension header chain from `meta.proto` at
+/// `meta.transport_offset`, leaving them at the upper-layer protocol and its
+/// offset.
+///
+/// Non-initial fragments are flagged in `meta.fragment`; their payload is not
+/// a transport header. A chain longer than `MAX_IPV6_EXT_HDRS` is rejected.
+#[inline(always)]
+fn ipv6_transport(start: usize, end: usize, meta: &mut PacketMeta) -> Result<(), ParseError> {
+    for _ in 0..MAX_IPV6_EXT_HDRS {
+        let offset = meta.transport_offset;
+        match meta.proto {
+            IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                // Length is in 8-octet units, not including the first 8 octets
+                let ext: *const Ipv6ExtHdr = ptr_in(start, end, offset)?;
+                meta.proto = unsafe { (*ext).next_hdr };
+                meta.transport_offset += (unsafe { (*ext).hdr_ext_len } as usize + 1) * 8;
+            }
+            IPPROTO_AH => {
+                // Length is in 4-octet units, minus 2
+                let ext: *const Ipv6ExtHdr = ptr_in(start, end, offset)?;
+                meta.proto = unsafe { (*ext).next_hdr };
+                meta.transport_offset += (unsafe { (*ext).hdr_ext_len } as usize + 2) * 4;
+            }
+            IPPROTO_FRAGMENT => {
+                // Only the first fragment (offset 0) carries the transport header
+                let frag: *const Ipv6FragHdr = ptr_in(start, end, offset)?;
+                meta.proto = unsafe { (*frag).next_hdr };
+                meta.transport_offset += mem::size_of::<Ipv6FragHdr>();
+                if be16(unsafe { (*frag).frag_off }) & IPV6_FRAG_OFFSET_MASK != 0 {
+                    meta.fragment = true;
+                    return Ok(());
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+
+    // Still inside the extension headers
+    Err(ParseError::Malformed)
+}
+
+/// Lookups the stateless classifier needs: eBPF maps in the datapath,
+/// in-memory tables in tests.
+pub trait RuleTables {
+    /// Number of populated entries in the ordered rule table
+    fn rule_count(&self) -> u32;
+
+    fn rule(&self, index: u32) -> Option<&FilterRule>;
+
+    /// Longest-prefix match of the packet's source address in the blocklist
+    fn source_blocklist(&self, meta: &PacketMeta) -> Option<&RuleEntry>;
+
+    /// Longest-prefix match of protocol and destination port in the port
+    /// blocklist (see `port_key`)
+    fn port_blocklist(&self, proto: u8, port: u16) -> Option<&RuleEntry>;
+}
+
+/// Outcome of the stateless ingress checks.
+#[derive(Clone, Copy, Debug)]
+pub enum Verdict<'a> {
+    /// An ordered rule allowed the packet; later checks are skipped
+    Allow(&'a RuleEntry),
+    /// Dropped by the given stage; the entry's counters, if any, record the hit
+    Drop(DropReason, Option<&'a RuleEntry>),
+    /// Nothing matched; the stateful checks (rate limiting, conntrack) decide
+    Continue,
+}
+
+/// Runs the ordered rule table, then the source and port blocklists, for a
+/// packet that arrived on an interface with the given role. The blocklists
+/// and fragment policy guard the router from upstream networks, so packets
+/// from LAN and guest interfaces only go through the ordered rules.
+///
+/// Non-initial fragments only match ordered rules without ports and skip the
+/// blocklists, since every port-based check would see zero ports: on upstream
+/// interfaces `fragments` decides whether they are dropped, checked against
+/// the source blocklist only, or passed on. Fragments that are not dropped
+/// still go through the stateful checks.
+#[inline(always)]
+pub fn classify<'a, T: RuleTables>(
+    tables: &'a T,
+    meta: &PacketMeta,
+    role: InterfaceRole,
+    fragments: FragmentPolicy,
+) -> Verdict<'a> {
+    // Ordered rule table: the first matching rule decides, and an explicit
+    // allow skips every later check
+    if let Some(entry) = first_match(tables, meta, role) {
+        if entry.action == PacketAction::Drop as u32 {
+            return Verdict::Drop(DropReason::Rule, Some(entry));
+        }
+        return Verdict::Allow(entry);
+    }
+    if !role.is_upstream() {
+        return Verdict::Continue;
+    }
+
+    if meta.fragment {
+        return match fragments {
+            FragmentPolicy::Pass => Verdict::Continue,
+            FragmentPolicy::Drop => Verdict::Drop(DropReason::Fragment, None),
+            FragmentPolicy::SourceOnly => match tables.source_blocklist(meta) {
+                Some(entry) if entry.action == PacketAction::Drop as u32 => {
+                    Verdict::Drop(DropReason::Blocklist, Some(entry))
+                }
+                _ => Verdict::Continue,
+            },
+        };
+    }
+
+    // Check source blocklist (longest prefix match)
+    if let Some(entry) = tables.source_blocklist(meta)
+        && entry.action == PacketAction::Drop as u32
+    {
+        return Verdict::Drop(DropReason::Blocklist, Some(entry));
+    }
+
+    // Check port blocklist for TCP/UDP
+    if matches!(meta.proto, IPPROTO_TCP | IPPROTO_UDP)
+        && let Some(entry) = tables.port_blocklist(meta.proto, meta.dst_port)
+        && entry.action == PacketAction::Drop as u32
+    {
+        return Verdict::Drop(DropReason::PortBlocklist, Some(entry));
+    }
+
+    Verdict::Continue
+}
+
+/// Returns the entry of the first ordered rule matching the packet, if any.
+/// Non-initial fragments only match rules without a port range.
+#[inline(always)]
+pub fn first_match<'a, T: RuleTables>(
+    tables: &'a T,
+    meta: &PacketMeta,
+    role: InterfaceRole,
+) -> Option<&'a RuleEntry> {
+    let count = tables.rule_count();
+
+    for i in 0..MAX_FILTER_RULES {
+        if i >= count {
+            break;
+        }
+        let rule = tables.rule(i)?;
+        // A non-initial fragment's ports are unknown, not zero
+        if rule.applies_to(role)
+            && !(meta.fragment && rule.has_ports())
+            && rule.matches(
+                &meta.src,
+                &meta.dst,
+                meta.proto,
+                meta.src_port,
+                meta.dst_port,
+                meta.vlan_id,
+            )
+        {
+            return Some(&rule.entry);
+        }
+    }
+
+    None
+}
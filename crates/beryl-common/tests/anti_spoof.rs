@@ -0,0 +1,69 @@
+//! Bogon prefixes and exceptions loaded into the anti-spoofing maps.
+
+use beryl_common::{AntiSpoofConfig, BOGON_PREFIXES, parse_cidr};
+use std::net::IpAddr;
+
+/// Whether `addr` is dropped, by longest matching prefix as in the LPM maps.
+fn dropped(config: &AntiSpoofConfig, lan: Option<&str>, addr: &str) -> bool {
+    let addr: IpAddr = addr.parse().unwrap();
+    config
+        .prefixes(lan)
+        .filter_map(|(prefix, drop)| {
+            let (net, len) = parse_cidr(prefix).unwrap();
+            let (net, addr, bits) = match (net, addr) {
+                (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                    (u128::from(u32::from(net)), u128::from(u32::from(addr)), 32)
+                }
+                (IpAddr::V6(net), IpAddr::V6(addr)) => (u128::from(net), u128::from(addr), 128),
+                _ => return None,
+            };
+            let shift = bits - len;
+            (shift == 128 || net >> shift == addr >> shift).then_some((len, drop))
+        })
+        // Equal prefixes overwrite each other in the map, so the last one wins
+        .max_by_key(|(len, _)| *len)
+        .is_some_and(|(_, drop)| drop)
+}
+
+#[test]
+fn bogon_prefixes_parse() {
+    for prefix in BOGON_PREFIXES {
+        assert!(parse_cidr(prefix).is_some(), "{prefix}");
+    }
+}
+
+#[test]
+fn drops_bogons_and_lan_sources() {
+    let config = AntiSpoofConfig {
+        enabled: true,
+        allow: Vec::new(),
+    };
+    let lan = Some("2001:db8:1::1/64");
+
+    for addr in [
+        "10.1.2.3",
+        "127.0.0.1",
+        "224.0.0.5",
+        "255.255.255.255",
+        "fd00::1",
+    ] {
+        assert!(dropped(&config, lan, addr), "{addr}");
+    }
+    assert!(dropped(&config, lan, "2001:db8:1::99"));
+    for addr in ["8.8.8.8", "2606:4700::1111", "fe80::1"] {
+        assert!(!dropped(&config, lan, addr), "{addr}");
+    }
+}
+
+#[test]
+fn exceptions_carve_out_bogons() {
+    let config = AntiSpoofConfig {
+        enabled: true,
+        allow: vec!["192.168.1.0/24".into(), "10.0.0.0/8".into()],
+    };
+
+    assert!(!dropped(&config, None, "192.168.1.1"));
+    assert!(dropped(&config, None, "192.168.2.1"));
+    // An exception matching a bogon exactly replaces it
+    assert!(!dropped(&config, None, "10.9.9.9"));
+}
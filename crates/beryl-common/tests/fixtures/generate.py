@@ -0,0 +1,185 @@
+#!/usr/bin/env python3
+"""Regenerates the replay fixtures: one pcap per scenario plus the expected
+verdict of each packet (`<name>.expect`, one line per packet).
+
+Run from this directory: `python3 generate.py`.
+"""
+
+import ipaddress
+import struct
+
+MAC_DST = bytes.fromhex("020000000001")
+MAC_SRC = bytes.fromhex("020000000002")
+
+
+def eth(ether_type, payload, vlans=()):
+    hdr = MAC_DST + MAC_SRC
+    for tpid, vid in vlans:
+        hdr += struct.pack("!HH", tpid, vid)
+    return hdr + struct.pack("!H", ether_type) + payload
+
+
+def ipv4(src, dst, proto, payload, ihl=5, frag_off=0):
+    options = b"\x01" * ((ihl - 5) * 4)
+    total = 20 + len(options) + len(payload)
+    hdr = struct.pack(
+        "!BBHHHBBH4s4s",
+        0x40 | ihl,
+        0,
+        total,
+        0x1234,
+        frag_off,
+        64,
+        proto,
+        0,
+        ipaddress.IPv4Address(src).packed,
+        ipaddress.IPv4Address(dst).packed,
+    )
+    return eth(0x0800, hdr + options + payload)
+
+
+def ipv6(src, dst, next_hdr, payload, vlans=()):
+    hdr = struct.pack(
+        "!IHBB16s16s",
+        0x60000000,
+        len(payload),
+        next_hdr,
+        64,
+        ipaddress.IPv6Address(src).packed,
+        ipaddress.IPv6Address(dst).packed,
+    )
+    return eth(0x86DD, hdr + payload, vlans)
+
+
+def tcp(sport, dport, flags=0x02):
+    return struct.pack("!HHIIBBHHH", sport, dport, 1, 0, 0x50, flags, 65535, 0, 0)
+
+
+def udp(sport, dport, data=b""):
+    return struct.pack("!HHHH", sport, dport, 8 + len(data), 0) + data
+
+
+def icmp(type_, code=0, ident=0, seq=0, data=b""):
+    return struct.pack("!BBHHH", type_, code, 0, ident, seq) + data
+
+
+def pcap(path, frames):
+    with open(path, "wb") as f:
+        f.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, 1))
+        for i, frame in enumerate(frames):
+            f.write(struct.pack("<IIII", 1_700_000_000, i, len(frame), len(frame)))
+            f.write(frame)
+
+
+def write(name, cases):
+    pcap(f"{name}.pcap", [frame for frame, _, _ in cases])
+    with open(f"{name}.expect", "w") as f:
+        f.write(f"# Expected verdicts for {name}.pcap, one line per packet\n")
+        f.write("# (regenerate both with generate.py)\n")
+        for _, verdict, comment in cases:
+            f.write(f"{verdict:<24} # {comment}\n")
+
+
+def vlan_frame(vlans, payload_frame):
+    # Re-tag an untagged frame built by ipv4()/ipv6()
+    ether_type = struct.unpack("!H", payload_frame[12:14])[0]
+    return eth(ether_type, payload_frame[14:], vlans)
+
+
+IPV4 = [
+    (ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 22)),
+     "allow 0", "LAN SSH hits the allow rule"),
+    (ipv4("203.0.113.7", "192.168.8.1", 6, tcp(40000, 22)),
+     "drop blocklist 0", "blocked source prefix"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 23)),
+     "drop port_blocklist 0", "telnet on the legacy port list"),
+    (ipv4("198.51.100.1", "192.168.8.1", 17, udp(40000, 23)),
+     "drop port_blocklist 0", "legacy port list covers UDP too"),
+    (ipv4("198.51.100.1", "192.168.8.1", 17, udp(40000, 5007)),
+     "drop port_blocklist 1", "inside the UDP port range"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 5007)),
+     "continue", "port range is UDP only"),
+    (ipv4("198.51.100.1", "192.168.8.1", 17, udp(40000, 5011)),
+     "continue", "just past the port range"),
+    (vlan_frame([(0x8100, 20)], ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 443))),
+     "drop rule 1", "guest VLAN rule"),
+    (vlan_frame([(0x88A8, 20), (0x8100, 100)],
+                ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 443))),
+     "drop rule 1", "QinQ matches on the outer VLAN ID"),
+    (vlan_frame([(0x8100, 30)], ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 22))),
+     "allow 0", "other VLANs fall through to later rules"),
+    (ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 22), ihl=6),
+     "allow 0", "IP options shift the TCP header"),
+    (ipv4("198.51.100.1", "192.168.8.1", 1, icmp(8, ident=7)),
+     "continue", "ICMP echo request"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 23), frag_off=0x2000),
+     "drop port_blocklist 0", "first fragment (MF set) is classified normally"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 23), frag_off=0x00b9),
+     "continue", "non-initial fragment skips port checks"),
+    (ipv4("203.0.113.7", "192.168.8.1", 6, tcp(40000, 443), frag_off=0x00b9),
+     "drop blocklist 0", "non-initial fragment still checks the source"),
+    (ipv4("203.0.113.9", "192.168.8.1", 17, udp(53, 53000))[:40],
+     "truncated", "UDP header cut short"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 443), ihl=3),
+     "malformed", "IHL below the minimum header length"),
+    (ipv4("198.51.100.1", "192.168.8.1", 6, tcp(40000, 443), ihl=15)[:50],
+     "truncated", "IHL points past the end of the frame"),
+    (eth(0x0806, bytes(28)),
+     "pass", "ARP is not classified"),
+    (ipv4("198.51.100.66", "192.168.8.1", 6, tcp(40000, 443), frag_off=0x00b9),
+     "drop rule 4", "non-initial fragment matches an address-only rule"),
+    (ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 22), frag_off=0x00b9),
+     "continue", "but not rules with ports"),
+]
+
+IPV6 = [
+    (ipv6("2001:db8:bad::1", "2001:db8:1::1", 6, tcp(40000, 443)),
+     "drop blocklist 0", "blocked IPv6 source prefix"),
+    (ipv6("2001:db8:1::5", "2001:db8:1::1", 17, udp(40000, 53)),
+     "drop rule 2", "IPv6 DNS drop rule"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 17, udp(40000, 53)),
+     "continue", "outside the rule's source prefix"),
+    (ipv6("2001:db8:1::5", "2001:db8:1::1", 0,
+          struct.pack("!BB6s", 17, 0, bytes(6)) + udp(40000, 53)),
+     "drop rule 2", "ports found behind a hop-by-hop header"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 44,
+          struct.pack("!BBHI", 6, 0, 0x0001, 1) + tcp(40000, 23)),
+     "drop port_blocklist 0", "first fragment carries the TCP header"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 44,
+          struct.pack("!BBHI", 6, 0, 0x0100, 1) + bytes(16)),
+     "continue", "non-initial fragment skips port checks"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 58, icmp(128, ident=9)),
+     "continue", "ICMPv6 echo request"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 6, tcp(40000, 443), vlans=[(0x8100, 20)]),
+     "drop rule 1", "guest VLAN rule applies to IPv6"),
+    (ipv6("2001:db8:2::5", "2001:db8:1::1", 60,
+          b"".join(struct.pack("!BB6s", 60, 0, bytes(6)) for _ in range(6))
+          + struct.pack("!BB6s", 17, 0, bytes(6)) + udp(40000, 53)),
+     "malformed", "extension header chain longer than the parser follows"),
+]
+
+# Packets arriving on a LAN interface: only the ordered rules apply, the
+# blocklists guard the router from upstream networks
+LAN = [
+    (ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 22)),
+     "allow 0", "LAN SSH hits the allow rule"),
+    (ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 23)),
+     "continue", "port blocklist does not apply to LAN clients"),
+    (ipv4("192.168.8.10", "192.168.8.1", 17, udp(40000, 5007)),
+     "continue", "nor do port range rules"),
+    (ipv4("203.0.113.7", "198.51.100.1", 6, tcp(40000, 443)),
+     "continue", "source blocklist does not apply either"),
+    (ipv6("2001:db8:bad::1", "2001:db8:1::1", 6, tcp(40000, 443)),
+     "continue", "same for the IPv6 source blocklist"),
+    (vlan_frame([(0x8100, 20)], ipv4("192.168.8.10", "192.168.8.1", 6, tcp(40000, 443))),
+     "drop rule 1", "ordered rules still apply"),
+    (ipv4("203.0.113.7", "198.51.100.1", 6, tcp(40000, 443), frag_off=0x00b9),
+     "continue", "non-initial fragment skips the fragment policy"),
+    (ipv4("198.51.100.66", "192.168.8.1", 6, tcp(40000, 443), frag_off=0x00b9),
+     "drop rule 4", "but not the ordered rules"),
+]
+
+if __name__ == "__main__":
+    write("ipv4", IPV4)
+    write("ipv6", IPV6)
+    write("lan", LAN)
@@ -0,0 +1,51 @@
+//! Sliding-window port counting used by the XDP port-scan detector.
+
+use beryl_common::{FirewallConfig, PortScanConfig, ScanState};
+
+const SEC: u64 = 1_000_000_000;
+const WINDOW: u64 = 10 * SEC;
+
+#[test]
+fn counts_distinct_ports() {
+    let mut state = ScanState::default();
+    assert_eq!(state.observe(22, SEC, WINDOW), 1);
+    assert_eq!(state.observe(22, 2 * SEC, WINDOW), 1);
+    assert_eq!(state.observe(80, 3 * SEC, WINDOW), 2);
+
+    let count = (100..200).fold(0, |_, port| state.observe(port, 4 * SEC, WINDOW));
+    assert_eq!(count, 102);
+}
+
+#[test]
+fn previous_window_fades_out() {
+    let mut state = ScanState::default();
+    for port in 1..=40 {
+        state.observe(port, 0, WINDOW);
+    }
+
+    // A quarter into the next window, three quarters of the old count remain
+    assert_eq!(state.observe(8080, WINDOW + WINDOW / 4, WINDOW), 1 + 30);
+    assert_eq!(state.observe(8080, 2 * WINDOW - 1, WINDOW), 1);
+
+    // After a full idle window nothing carries over
+    assert_eq!(state.observe(443, 5 * WINDOW, WINDOW), 1);
+}
+
+#[test]
+fn port_scan_config_defaults_and_limits() {
+    let config: FirewallConfig = toml::from_str("[port_scan]\nthreshold = 20\n").unwrap();
+    let params = config.port_scan.to_params().unwrap();
+    assert_eq!((params.threshold, params.window_ns), (20, WINDOW));
+    assert_eq!(config.port_scan.block_secs, 3600);
+
+    let too_many = PortScanConfig {
+        threshold: 1000,
+        ..PortScanConfig::default()
+    };
+    assert!(too_many.to_params().is_err());
+    let no_window = PortScanConfig {
+        window_secs: 0,
+        ..PortScanConfig::default()
+    };
+    assert!(no_window.to_params().is_err());
+}
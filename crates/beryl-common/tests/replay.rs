@@ -0,0 +1,447 @@
+//! Replays pcap fixtures through the shared parser and classifier and checks
+//! each packet's verdict against the fixture's `.expect` file.
+//!
+//! The ruleset in `fixtures/firewall.toml` is compiled the same way
+//! `beryl-routerd` fills the eBPF maps, into in-memory tables standing in for
+//! the maps. Fixtures are regenerated with `fixtures/generate.py`.
+
+use beryl_common::{
+    Addr128, FilterRule, FirewallConfig, FragmentPolicy, ICMP_CODE_ANY, ICMP_DEST_UNREACH,
+    ICMPV6_PKT_TOOBIG, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP, IcmpAction,
+    IcmpConfig, IcmpKey, IcmpProtocol, IcmpRuleConfig, InterfaceRole, PacketAction, RuleEntry,
+    ipv4_mapped,
+    packet::{
+        self, PacketMeta, ParseError, RuleTables, TCP_ACK, TCP_FIN, TCP_PSH, TCP_SYN, TCP_URG,
+        TcpFlagViolation, Verdict,
+    },
+    parse_cidr,
+};
+use std::{collections::HashMap, fs, net::IpAddr, path::PathBuf};
+
+/// A blocklist prefix over IPv4-mapped addresses.
+struct Prefix {
+    addr: Addr128,
+    len: u32,
+    entry: RuleEntry,
+}
+
+/// A port blocklist prefix (see `port_key`).
+struct PortPrefix {
+    proto: u8,
+    port: u16,
+    bits: u32,
+    entry: RuleEntry,
+}
+
+/// In-memory stand-in for the rule and blocklist maps.
+#[derive(Default)]
+struct TestTables {
+    rules: Vec<FilterRule>,
+    blocklist: Vec<Prefix>,
+    ports: Vec<PortPrefix>,
+}
+
+impl TestTables {
+    /// Compiles a firewall config the way `apply_firewall_config` does.
+    fn from_config(config: &FirewallConfig) -> Self {
+        let mut tables = TestTables::default();
+
+        for (index, rule) in config.ordered_rules() {
+            tables.rules.push(rule.compile(index as u32).unwrap());
+        }
+        for ip in &config.blocked_ips {
+            let (addr, len) = parse_cidr(ip).unwrap();
+            let (addr, len) = match addr {
+                IpAddr::V4(addr) => (ipv4_mapped(u32::from(addr).to_be()), len + 96),
+                IpAddr::V6(addr) => (words(addr.octets()), len),
+            };
+            tables.blocklist.push(Prefix {
+                addr,
+                len,
+                entry: RuleEntry::new(PacketAction::Drop),
+            });
+        }
+        for (proto, port, bits, entry) in config.port_entries() {
+            tables.ports.push(PortPrefix {
+                proto,
+                port,
+                bits,
+                entry,
+            });
+        }
+
+        tables
+    }
+}
+
+impl RuleTables for TestTables {
+    fn rule_count(&self) -> u32 {
+        self.rules.len() as u32
+    }
+
+    fn rule(&self, index: u32) -> Option<&FilterRule> {
+        self.rules.get(index as usize)
+    }
+
+    fn source_blocklist(&self, meta: &PacketMeta) -> Option<&RuleEntry> {
+        let src = u128::from_be_bytes(bytes(meta.src));
+        self.blocklist
+            .iter()
+            .filter(|prefix| {
+                let mask = u128::MAX.checked_shl(128 - prefix.len).unwrap_or(0);
+                src & mask == u128::from_be_bytes(bytes(prefix.addr)) & mask
+            })
+            .max_by_key(|prefix| prefix.len)
+            .map(|prefix| &prefix.entry)
+    }
+
+    fn port_blocklist(&self, proto: u8, port: u16) -> Option<&RuleEntry> {
+        self.ports
+            .iter()
+            .filter(|prefix| {
+                let mask = u16::MAX.checked_shl(16 - prefix.bits).unwrap_or(0);
+                prefix.proto == proto && port & mask == prefix.port & mask
+            })
+            .max_by_key(|prefix| prefix.bits)
+            .map(|prefix| &prefix.entry)
+    }
+}
+
+/// Network-order address words of an IPv6 address.
+fn words(octets: [u8; 16]) -> Addr128 {
+    std::array::from_fn(|i| u32::from_ne_bytes(octets[i * 4..i * 4 + 4].try_into().unwrap()))
+}
+
+fn bytes(addr: Addr128) -> [u8; 16] {
+    std::array::from_fn(|i| addr[i / 4].to_ne_bytes()[i % 4])
+}
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Reads the frames of a little-endian, microsecond pcap file.
+fn read_pcap(name: &str) -> Vec<Vec<u8>> {
+    let data = fs::read(fixture(name)).unwrap();
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    assert_eq!(u32_at(0), 0xa1b2_c3d4, "{name}: unsupported pcap format");
+    assert_eq!(u32_at(20), 1, "{name}: not an Ethernet capture");
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let cap_len = u32_at(offset + 8) as usize;
+        offset += 16;
+        frames.push(data[offset..offset + cap_len].to_vec());
+        offset += cap_len;
+    }
+    frames
+}
+
+/// Reads expected verdicts, dropping comments and blank lines.
+fn read_expect(name: &str) -> Vec<String> {
+    fs::read_to_string(fixture(name))
+        .unwrap()
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+fn parse(frame: &[u8]) -> Result<Option<PacketMeta>, ParseError> {
+    let start = frame.as_ptr() as usize;
+    packet::parse_packet(start, start + frame.len())
+}
+
+/// Parses and classifies a frame, rendering the verdict as in `.expect` files.
+fn verdict(
+    tables: &TestTables,
+    frame: &[u8],
+    role: InterfaceRole,
+    fragments: FragmentPolicy,
+) -> String {
+    let meta = match parse(frame) {
+        Ok(Some(meta)) => meta,
+        Ok(None) => return "pass".into(),
+        Err(ParseError::Truncated) => return "truncated".into(),
+        Err(ParseError::Malformed) => return "malformed".into(),
+    };
+    let rule = |entry: Option<&RuleEntry>| entry.map(|entry| format!(" {}", entry.rule));
+    match packet::classify(tables, &meta, role, fragments) {
+        Verdict::Allow(entry) => format!("allow {}", entry.rule),
+        Verdict::Drop(reason, entry) => {
+            let reason = toml::Value::try_from(reason).unwrap();
+            let reason = reason.as_str().unwrap();
+            format!("drop {reason}{}", rule(entry).unwrap_or_default())
+        }
+        Verdict::Continue => "continue".into(),
+    }
+}
+
+fn load_config() -> FirewallConfig {
+    toml::from_str(&fs::read_to_string(fixture("firewall.toml")).unwrap()).unwrap()
+}
+
+/// Replays a fixture as if its packets arrived on an interface with `role`.
+fn replay(name: &str, role: InterfaceRole) {
+    let config = load_config();
+    let tables = TestTables::from_config(&config);
+    let frames = read_pcap(&format!("{name}.pcap"));
+    let expected = read_expect(&format!("{name}.expect"));
+    assert_eq!(frames.len(), expected.len(), "{name}: packet/verdict count");
+
+    let failures: Vec<_> = frames
+        .iter()
+        .zip(&expected)
+        .enumerate()
+        .filter_map(|(i, (frame, expected))| {
+            let actual = verdict(&tables, frame, role, config.validation.fragments);
+            (actual != *expected)
+                .then(|| format!("packet {}: expected {expected}, got {actual}", i + 1))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{name}:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn replay_ipv4() {
+    replay("ipv4", InterfaceRole::Unassigned);
+}
+
+#[test]
+fn replay_ipv6() {
+    replay("ipv6", InterfaceRole::Unassigned);
+}
+
+#[test]
+fn replay_lan() {
+    replay("lan", InterfaceRole::Lan);
+}
+
+#[test]
+fn parses_vlan_ports_and_icmp() {
+    let frames = read_pcap("ipv4.pcap");
+
+    // QinQ: the outer tag wins, the IP header follows both tags
+    let meta = parse(&frames[8]).unwrap().unwrap();
+    assert_eq!((meta.vlan_id, meta.l3_offset), (20, 22));
+    assert_eq!((meta.src_port, meta.dst_port), (40000, 443));
+    assert!(meta.is_syn());
+
+    // IP options push the transport header back
+    let meta = parse(&frames[10]).unwrap().unwrap();
+    assert_eq!(meta.transport_offset, 14 + 24);
+
+    // Echo requests carry their identifier as the source port
+    let meta = parse(&frames[11]).unwrap().unwrap();
+    assert_eq!(
+        (meta.proto, meta.icmp_type, meta.src_port),
+        (IPPROTO_ICMP, 8, 7)
+    );
+}
+
+#[test]
+fn parses_ipv6_extension_headers() {
+    let frames = read_pcap("ipv6.pcap");
+
+    let meta = parse(&frames[3]).unwrap().unwrap();
+    assert_eq!((meta.proto, meta.dst_port), (IPPROTO_UDP, 53));
+    assert_eq!(meta.transport_offset, 14 + 40 + 8);
+
+    let meta = parse(&frames[5]).unwrap().unwrap();
+    assert!(meta.fragment);
+    assert_eq!((meta.proto, meta.dst_port), (IPPROTO_TCP, 0));
+
+    let meta = parse(&frames[6]).unwrap().unwrap();
+    assert_eq!((meta.proto, meta.src_port), (IPPROTO_ICMPV6, 9));
+}
+
+#[test]
+fn fragment_policies() {
+    let tables = TestTables::from_config(&load_config());
+    let frames = read_pcap("ipv4.pcap");
+    let (first, later, blocked) = (&frames[12], &frames[13], &frames[14]);
+    let ruled = &frames[19];
+
+    // Only non-initial fragments are affected, and only once no ordered rule
+    // matched them
+    let verdicts = |policy| {
+        [first, later, blocked, ruled]
+            .map(|frame| verdict(&tables, frame, InterfaceRole::Wan, policy))
+    };
+    assert_eq!(
+        verdicts(FragmentPolicy::Pass),
+        [
+            "drop port_blocklist 0",
+            "continue",
+            "continue",
+            "drop rule 4"
+        ]
+    );
+    assert_eq!(
+        verdicts(FragmentPolicy::Drop),
+        [
+            "drop port_blocklist 0",
+            "drop fragment",
+            "drop fragment",
+            "drop rule 4"
+        ]
+    );
+
+    let meta = parse(later).unwrap().unwrap();
+    assert!(meta.fragment);
+    assert_eq!(
+        (meta.proto, meta.src_port, meta.dst_port),
+        (IPPROTO_TCP, 0, 0)
+    );
+}
+
+#[test]
+fn interface_role_rules() {
+    let tables = TestTables::from_config(&load_config());
+    let guest_to_lan = PacketMeta {
+        proto: IPPROTO_TCP,
+        src: ipv4_mapped(u32::from_ne_bytes([192, 168, 9, 20])),
+        dst: ipv4_mapped(u32::from_ne_bytes([192, 168, 8, 10])),
+        dst_port: 445,
+        ..PacketMeta::default()
+    };
+
+    // The guest isolation rule only applies to packets from guest interfaces
+    let rule = |role| match packet::classify(&tables, &guest_to_lan, role, FragmentPolicy::Pass) {
+        Verdict::Drop(_, Some(entry)) => Some(entry.rule),
+        _ => None,
+    };
+    assert_eq!(rule(InterfaceRole::Guest), Some(3));
+    assert_eq!(rule(InterfaceRole::Lan), None);
+    assert_eq!(rule(InterfaceRole::Unassigned), None);
+
+    assert!(InterfaceRole::Wan.is_upstream());
+    assert!(InterfaceRole::Unassigned.is_upstream());
+    assert!(!InterfaceRole::Guest.is_upstream());
+}
+
+#[test]
+fn fragments_only_match_rules_without_ports() {
+    let config: FirewallConfig = toml::from_str(
+        r#"
+        [[rules]]
+        action = "drop"
+        proto = "tcp"
+        dst_port = "0-1023"
+
+        [[rules]]
+        action = "drop"
+        proto = "tcp"
+        "#,
+    )
+    .unwrap();
+    let tables = TestTables::from_config(&config);
+    let tcp = |dst_port, fragment| PacketMeta {
+        proto: IPPROTO_TCP,
+        dst_port,
+        fragment,
+        ..PacketMeta::default()
+    };
+
+    // A fragment's ports read as 0, which falls inside the first rule's range
+    let rule = |meta| packet::first_match(&tables, &meta, InterfaceRole::Wan).map(|e| e.rule);
+    assert_eq!(rule(tcp(80, false)), Some(0));
+    assert_eq!(rule(tcp(0, true)), Some(1));
+}
+
+#[test]
+fn tcp_flag_violations() {
+    let tcp = |tcp_flags| PacketMeta {
+        proto: IPPROTO_TCP,
+        tcp_flags,
+        ..PacketMeta::default()
+    };
+
+    assert_eq!(tcp(0).tcp_flag_violation(), Some(TcpFlagViolation::Null));
+    let xmas = TCP_FIN | TCP_PSH | TCP_URG;
+    assert_eq!(tcp(xmas).tcp_flag_violation(), Some(TcpFlagViolation::Xmas));
+    let syn_fin = TCP_SYN | TCP_FIN;
+    assert_eq!(
+        tcp(syn_fin).tcp_flag_violation(),
+        Some(TcpFlagViolation::SynFin)
+    );
+    for flags in [
+        TCP_SYN,
+        TCP_SYN | TCP_ACK,
+        TCP_FIN | TCP_ACK,
+        TCP_ACK | TCP_PSH,
+    ] {
+        assert_eq!(tcp(flags).tcp_flag_violation(), None, "{flags:#x}");
+    }
+
+    // Non-initial fragments carry no TCP header to check
+    let frames = read_pcap("ipv4.pcap");
+    let meta = parse(&frames[13]).unwrap().unwrap();
+    assert_eq!(meta.tcp_flag_violation(), None);
+}
+
+#[test]
+fn icmp_rules() {
+    let (entries, params) = load_config().icmp.compile().unwrap();
+    assert_eq!((params.pps, params.burst), (5, 5));
+    let rules: HashMap<_, _> = entries.into_iter().collect();
+    let action = |meta: &PacketMeta| packet::icmp_action(meta, |key| rules.get(key).copied());
+
+    let v4 = read_pcap("ipv4.pcap");
+    let v6 = read_pcap("ipv6.pcap");
+    assert_eq!(
+        action(&parse(&v4[11]).unwrap().unwrap()),
+        Some(IcmpAction::Drop)
+    );
+    assert_eq!(
+        action(&parse(&v6[6]).unwrap().unwrap()),
+        Some(IcmpAction::RateLimit)
+    );
+    assert_eq!(action(&parse(&v4[0]).unwrap().unwrap()), None);
+
+    // Code-specific rules only match their code
+    let unreach = |icmp_code| PacketMeta {
+        proto: IPPROTO_ICMP,
+        icmp_type: ICMP_DEST_UNREACH,
+        icmp_code,
+        ..PacketMeta::default()
+    };
+    assert_eq!(action(&unreach(3)), Some(IcmpAction::RateLimit));
+    assert_eq!(action(&unreach(1)), None);
+    assert!(unreach(4).is_pmtu());
+}
+
+#[test]
+fn icmp_rules_keep_path_mtu_messages() {
+    // Rules that would drop or limit them are rejected
+    let rule = |proto, icmp_type, code| IcmpConfig {
+        rules: vec![IcmpRuleConfig {
+            proto,
+            icmp_type,
+            code,
+            action: IcmpAction::Drop,
+        }],
+        ..IcmpConfig::default()
+    };
+
+    assert!(rule(IcmpProtocol::Icmp, 3, None).compile().is_err());
+    assert!(rule(IcmpProtocol::Icmp, 3, Some(4)).compile().is_err());
+    assert!(rule(IcmpProtocol::Icmpv6, 2, None).compile().is_err());
+    assert!(rule(IcmpProtocol::Icmp, 3, Some(1)).compile().is_ok());
+
+    // The datapath never consults the rules for them either
+    let key = IcmpKey::new(IPPROTO_ICMPV6, ICMPV6_PKT_TOOBIG, ICMP_CODE_ANY);
+    let rules = HashMap::from([(key, IcmpAction::Drop)]);
+    let too_big = PacketMeta {
+        proto: IPPROTO_ICMPV6,
+        icmp_type: ICMPV6_PKT_TOOBIG,
+        ..PacketMeta::default()
+    };
+    assert_eq!(
+        packet::icmp_action(&too_big, |key| rules.get(key).copied()),
+        None
+    );
+}
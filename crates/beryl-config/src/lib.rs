@@ -1,4 +1,4 @@
-use beryl_common::FirewallConfig;
+use beryl_common::{FirewallConfig, InterfaceRole};
 use beryl_dhcp::{ClientConfig as DhcpClientConfig, ServerConfig as DhcpServerConfig};
 use beryl_dns::DnsConfig;
 use serde::{Deserialize, Serialize};
@@ -97,6 +97,26 @@ pub enum OperatingMode {
 pub struct InterfacesConfig {
     pub wan: InterfaceConfig,
     pub lan: InterfaceConfig,
+    /// Isolated guest network
+    #[serde(default)]
+    pub guest: Option<InterfaceConfig>,
+    /// VPN tunnel, such as WireGuard
+    #[serde(default)]
+    pub vpn: Option<InterfaceConfig>,
+}
+
+impl InterfacesConfig {
+    /// Configured interfaces with their roles, WAN first.
+    pub fn roles(&self) -> impl Iterator<Item = (InterfaceRole, &InterfaceConfig)> {
+        [
+            (InterfaceRole::Wan, Some(&self.wan)),
+            (InterfaceRole::Lan, Some(&self.lan)),
+            (InterfaceRole::Guest, self.guest.as_ref()),
+            (InterfaceRole::Vpn, self.vpn.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(role, iface)| Some((role, iface?)))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -101,17 +101,17 @@ impl RequestHandler for Forwarder {
         let builder = MessageResponseBuilder::from_message_request(request);
 
         // 1. Try Local Resolution first (for A records)
-        if query_type == RecordType::A {
-            if let Some(records) = self.resolve_local(&name).await {
-                let response = builder.build(header, records.iter(), &[], &[], &[]);
-                return match response_handle.send_response(response).await {
-                    Ok(info) => info,
-                    Err(e) => {
-                        tracing::error!("Failed to send local response: {}", e);
-                        ResponseInfo::from(header)
-                    }
-                };
-            }
+        if query_type == RecordType::A
+            && let Some(records) = self.resolve_local(&name).await
+        {
+            let response = builder.build(header, records.iter(), &[], &[], &[]);
+            return match response_handle.send_response(response).await {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::error!("Failed to send local response: {}", e);
+                    ResponseInfo::from(header)
+                }
+            };
         }
 
         // 2. Forward to upstream
@@ -0,0 +1,472 @@
+//! Map layouts the daemon relies on, taken from the `beryl-common` types, and
+//! migration of map contents between differently sized maps.
+
+use anyhow::{Context, Result};
+use aya::{
+    Pod,
+    maps::{Array, HashMap, LpmTrie, Map, MapData, lpm_trie::Key},
+};
+use aya_obj::generated::{BPF_F_NO_PREALLOC, bpf_map_type};
+use beryl_common::{
+    Addr128, AntiSpoofParams, CaptureParams, ConntrackParams, FastPathParams, FilterRule, FlowKey,
+    FlowState, IcmpKey, IcmpParams, MAX_FILTER_RULES, PortScanParams, RateLimitParams, RuleEntry,
+    ScanState, Stats, TokenBucket, ValidationParams,
+};
+use std::mem::size_of;
+
+use crate::MapError;
+
+/// Copies the entries of a retired map into its replacement, returning how
+/// many were copied.
+type Migrate = fn(MapData, &mut Map) -> Result<usize>;
+
+/// Type, key and value sizes of a map as the daemon accesses it.
+pub(crate) struct MapLayout {
+    pub name: &'static str,
+    pub map_type: bpf_map_type,
+    pub key_size: u32,
+    pub value_size: u32,
+    /// Flags the daemon's updates rely on (`BPF_F_NO_PREALLOC` for tries)
+    pub map_flags: u32,
+    /// Capacity of maps the daemon addresses by index, which a migration
+    /// would not fix
+    pub max_entries: Option<u32>,
+    /// How to carry entries over when the map is recreated with a different
+    /// capacity; `None` for maps whose contents are not worth keeping
+    pub migrate: Option<Migrate>,
+}
+
+const fn array<V: Pod>(name: &'static str, max_entries: u32) -> MapLayout {
+    MapLayout {
+        name,
+        map_type: bpf_map_type::BPF_MAP_TYPE_ARRAY,
+        key_size: size_of::<u32>() as u32,
+        value_size: size_of::<V>() as u32,
+        map_flags: 0,
+        max_entries: Some(max_entries),
+        migrate: Some(migrate_array::<V>),
+    }
+}
+
+const fn hash<K: Pod, V: Pod>(name: &'static str) -> MapLayout {
+    MapLayout {
+        name,
+        map_type: bpf_map_type::BPF_MAP_TYPE_HASH,
+        key_size: size_of::<K>() as u32,
+        value_size: size_of::<V>() as u32,
+        map_flags: 0,
+        max_entries: None,
+        migrate: Some(migrate_hash::<K, V>),
+    }
+}
+
+const fn lru_hash<K: Pod, V: Pod>(name: &'static str) -> MapLayout {
+    MapLayout {
+        map_type: bpf_map_type::BPF_MAP_TYPE_LRU_HASH,
+        ..hash::<K, V>(name)
+    }
+}
+
+const fn lpm<K: Pod, V: Pod>(name: &'static str) -> MapLayout {
+    MapLayout {
+        name,
+        map_type: bpf_map_type::BPF_MAP_TYPE_LPM_TRIE,
+        key_size: size_of::<Key<K>>() as u32,
+        value_size: size_of::<V>() as u32,
+        map_flags: BPF_F_NO_PREALLOC,
+        max_entries: None,
+        migrate: Some(migrate_lpm::<K, V>),
+    }
+}
+
+/// Ring buffers, whose key and value sizes are 0, and other maps that start
+/// out empty when recreated.
+const fn unmigrated(
+    name: &'static str,
+    map_type: bpf_map_type,
+    key_size: u32,
+    value_size: u32,
+    max_entries: Option<u32>,
+) -> MapLayout {
+    MapLayout {
+        name,
+        map_type,
+        key_size,
+        value_size,
+        map_flags: 0,
+        max_entries,
+        migrate: None,
+    }
+}
+
+/// Every map the daemon reads or writes.
+pub(crate) const MAPS: &[MapLayout] = &[
+    lpm::<u32, RuleEntry>("BLOCKLIST"),
+    lpm::<[u8; 16], RuleEntry>("BLOCKLIST_V6"),
+    lpm::<[u8; 4], RuleEntry>("PORT_BLOCKLIST"),
+    lpm::<u32, RuleEntry>("EGRESS_BLOCK"),
+    lpm::<[u8; 16], RuleEntry>("EGRESS_BLOCK_V6"),
+    lpm::<u32, RuleEntry>("BOGONS"),
+    lpm::<[u8; 16], RuleEntry>("BOGONS_V6"),
+    array::<FilterRule>("RULES", 2 * MAX_FILTER_RULES),
+    array::<u32>("RULE_SET", 1),
+    hash::<IcmpKey, u32>("ICMP_RULES"),
+    hash::<u32, u32>("IFACE_ROLES"),
+    hash::<u32, u32>("FAST_PATH_IFACES"),
+    lru_hash::<FlowKey, FlowState>("CONNTRACK"),
+    lru_hash::<Addr128, TokenBucket>("RATE_LIMIT"),
+    lru_hash::<Addr128, TokenBucket>("SYN_RATE_LIMIT"),
+    lru_hash::<Addr128, TokenBucket>("ICMP_RATE_LIMIT"),
+    lru_hash::<Addr128, ScanState>("PORT_SCAN"),
+    array::<ValidationParams>("VALIDATION_CONFIG", 1),
+    array::<RateLimitParams>("RATE_LIMIT_CONFIG", 1),
+    array::<ConntrackParams>("CONNTRACK_CONFIG", 1),
+    array::<FastPathParams>("FAST_PATH_CONFIG", 1),
+    array::<AntiSpoofParams>("ANTI_SPOOF_CONFIG", 1),
+    array::<PortScanParams>("PORT_SCAN_CONFIG", 1),
+    array::<IcmpParams>("ICMP_CONFIG", 1),
+    array::<CaptureParams>("CAPTURE_CONFIG", 1),
+    unmigrated(
+        "STATS",
+        bpf_map_type::BPF_MAP_TYPE_PERCPU_ARRAY,
+        size_of::<u32>() as u32,
+        size_of::<Stats>() as u32,
+        Some(1),
+    ),
+    unmigrated("EVENTS", bpf_map_type::BPF_MAP_TYPE_RINGBUF, 0, 0, None),
+    unmigrated("CAPTURE", bpf_map_type::BPF_MAP_TYPE_RINGBUF, 0, 0, None),
+];
+
+/// Returns the expected layout of a map, if the daemon uses it.
+pub(crate) fn find(name: &str) -> Option<&'static MapLayout> {
+    MAPS.iter().find(|layout| layout.name == name)
+}
+
+/// Checks that an object defines every map the daemon uses, with the type,
+/// key and value sizes of the `beryl-common` types and the flags and fixed
+/// capacities the daemon relies on. Runs before any pin is touched, so an
+/// unusable object leaves the pinned maps alone.
+pub(crate) fn verify(object: &aya_obj::Object) -> Result<(), MapError> {
+    for layout in MAPS {
+        let Some(map) = object.maps.get(layout.name) else {
+            return Err(MapError::Missing(layout.name));
+        };
+        check(layout, map)?;
+    }
+    Ok(())
+}
+
+fn check(layout: &MapLayout, map: &aya_obj::Map) -> Result<(), MapError> {
+    let mismatch = |detail| MapError::Mismatch {
+        name: layout.name,
+        detail,
+    };
+    if map.map_type() != layout.map_type as u32 {
+        return Err(mismatch(format!(
+            "map type is {}, expected {} ({:?})",
+            map.map_type(),
+            layout.map_type as u32,
+            layout.map_type
+        )));
+    }
+    if (map.key_size(), map.value_size()) != (layout.key_size, layout.value_size) {
+        return Err(mismatch(format!(
+            "key/value sizes are {}/{}, expected {}/{}",
+            map.key_size(),
+            map.value_size(),
+            layout.key_size,
+            layout.value_size
+        )));
+    }
+    if map.map_flags() != layout.map_flags {
+        return Err(mismatch(format!(
+            "flags are {:#x}, expected {:#x}",
+            map.map_flags(),
+            layout.map_flags
+        )));
+    }
+    if let Some(max_entries) = layout.max_entries
+        && map.max_entries() != max_entries
+    {
+        return Err(mismatch(format!(
+            "holds {} entries, expected {max_entries}",
+            map.max_entries()
+        )));
+    }
+    Ok(())
+}
+
+fn migrate_array<V: Pod>(old: MapData, new: &mut Map) -> Result<usize> {
+    let old: Array<_, V> = Array::try_from(Map::Array(old))?;
+    let mut new: Array<_, V> = Array::try_from(new)?;
+
+    let len = old.len().min(new.len());
+    for index in 0..len {
+        new.set(index, old.get(&index, 0)?, 0)?;
+    }
+    Ok(len as usize)
+}
+
+/// Also covers LRU hash maps; if the new map is smaller, the copy stops once
+/// it is full (or, for LRU maps, evicts the older entries).
+fn migrate_hash<K: Pod, V: Pod>(old: MapData, new: &mut Map) -> Result<usize> {
+    let old: HashMap<_, K, V> = HashMap::try_from(Map::HashMap(old))?;
+    let mut new: HashMap<_, K, V> = HashMap::try_from(new)?;
+
+    let mut copied = 0;
+    for entry in old.iter() {
+        let (key, value) = entry?;
+        if new.insert(key, value, 0).is_err() {
+            break;
+        }
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// If the new trie is smaller, the copy stops once it is full.
+fn migrate_lpm<K: Pod, V: Pod>(old: MapData, new: &mut Map) -> Result<usize> {
+    let old: LpmTrie<_, K, V> = LpmTrie::try_from(Map::LpmTrie(old))?;
+    let mut new: LpmTrie<_, K, V> = LpmTrie::try_from(new)?;
+
+    let mut copied = 0;
+    for entry in old.iter() {
+        let (key, value) = entry?;
+        if new.insert(&key, value, 0).is_err() {
+            break;
+        }
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Migrates a retired map into the map of the same name in a freshly loaded
+/// object.
+pub(crate) fn migrate(name: &str, old: MapData, new: &mut Map) -> Result<Option<usize>> {
+    let Some(migrate) = find(name).and_then(|layout| layout.migrate) else {
+        return Ok(None);
+    };
+    migrate(old, new)
+        .with_context(|| format!("Failed to migrate {name}"))
+        .map(Some)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use aya_obj::{
+        EbpfSectionKind,
+        generated::bpf_map_type,
+        maps::{LegacyMap, PinningType, bpf_map_def},
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn map_names_are_unique() {
+        let mut names = HashSet::new();
+        for layout in MAPS {
+            assert!(names.insert(layout.name), "{} listed twice", layout.name);
+        }
+    }
+
+    #[test]
+    fn find_returns_layout_by_name() {
+        let layout = find("BLOCKLIST").unwrap();
+        assert_eq!(layout.key_size, size_of::<Key<u32>>() as u32);
+        assert_eq!(layout.value_size, size_of::<RuleEntry>() as u32);
+        assert!(find("NO_SUCH_MAP").is_none());
+    }
+
+    /// A map definition as parsed from an object.
+    fn definition(
+        map_type: bpf_map_type,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+        map_flags: u32,
+    ) -> aya_obj::Map {
+        aya_obj::Map::Legacy(LegacyMap {
+            def: bpf_map_def {
+                map_type: map_type as u32,
+                key_size: key_size as u32,
+                value_size: value_size as u32,
+                max_entries,
+                map_flags,
+                id: 0,
+                pinning: PinningType::None,
+            },
+            section_index: 0,
+            section_kind: EbpfSectionKind::Maps,
+            symbol_index: None,
+            data: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn check_compares_type_flags_and_fixed_capacities() {
+        let rules = find("RULES").unwrap();
+        let rule_size = size_of::<FilterRule>();
+        let rule_array = |max_entries| {
+            definition(
+                bpf_map_type::BPF_MAP_TYPE_ARRAY,
+                4,
+                rule_size,
+                max_entries,
+                0,
+            )
+        };
+        assert!(check(rules, &rule_array(2 * MAX_FILTER_RULES)).is_ok());
+        // The rule banks are addressed by index, so their size is fixed
+        let err = check(rules, &rule_array(MAX_FILTER_RULES)).unwrap_err();
+        assert!(matches!(err, MapError::Mismatch { name: "RULES", .. }));
+        let hash = definition(
+            bpf_map_type::BPF_MAP_TYPE_HASH,
+            4,
+            rule_size,
+            2 * MAX_FILTER_RULES,
+            0,
+        );
+        assert!(check(rules, &hash).is_err());
+
+        // Tries may be resized (and are migrated), but need their flags
+        let blocklist = find("BLOCKLIST").unwrap();
+        let trie = |map_flags| {
+            definition(
+                bpf_map_type::BPF_MAP_TYPE_LPM_TRIE,
+                size_of::<Key<u32>>(),
+                size_of::<RuleEntry>(),
+                100,
+                map_flags,
+            )
+        };
+        assert!(check(blocklist, &trie(BPF_F_NO_PREALLOC)).is_ok());
+        assert!(check(blocklist, &trie(0)).is_err());
+    }
+
+    /// Creates an unpinned map; needs CAP_BPF (or root).
+    fn create(
+        map_type: bpf_map_type,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+    ) -> MapData {
+        let map_flags = match map_type {
+            bpf_map_type::BPF_MAP_TYPE_LPM_TRIE => BPF_F_NO_PREALLOC,
+            _ => 0,
+        };
+        let obj = definition(map_type, key_size, value_size, max_entries, map_flags);
+        MapData::create(obj, "beryl_test", None).expect("creating a map needs CAP_BPF")
+    }
+
+    fn hash_map(max_entries: u32) -> MapData {
+        create(bpf_map_type::BPF_MAP_TYPE_HASH, 4, 4, max_entries)
+    }
+
+    /// Creates an unpinned IPv4 trie of rule entries; needs CAP_BPF.
+    pub(crate) fn trie(max_entries: u32) -> MapData {
+        let key_size = size_of::<Key<u32>>();
+        create(
+            bpf_map_type::BPF_MAP_TYPE_LPM_TRIE,
+            key_size,
+            size_of::<RuleEntry>(),
+            max_entries,
+        )
+    }
+
+    /// Fills a hash map with `key -> key * 10` for each key.
+    fn filled_hash_map(max_entries: u32, keys: u32) -> MapData {
+        let mut map = Map::HashMap(hash_map(max_entries));
+        let mut hash: HashMap<_, u32, u32> = HashMap::try_from(&mut map).unwrap();
+        for key in 0..keys {
+            hash.insert(key, key * 10, 0).unwrap();
+        }
+        let Map::HashMap(data) = map else {
+            unreachable!()
+        };
+        data
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn migrate_hash_copies_every_entry() {
+        let old = filled_hash_map(8, 5);
+        let mut new = Map::HashMap(hash_map(16));
+
+        assert_eq!(migrate_hash::<u32, u32>(old, &mut new).unwrap(), 5);
+        let new: HashMap<_, u32, u32> = HashMap::try_from(&new).unwrap();
+        for key in 0..5 {
+            assert_eq!(new.get(&key, 0).unwrap(), key * 10);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn migrate_hash_stops_when_smaller_map_is_full() {
+        let old = filled_hash_map(8, 8);
+        let mut new = Map::HashMap(hash_map(3));
+
+        assert_eq!(migrate_hash::<u32, u32>(old, &mut new).unwrap(), 3);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn migrate_lpm_keeps_prefixes_and_counters() {
+        let entry = RuleEntry {
+            packets: 7,
+            bytes: 700,
+            ..RuleEntry::default()
+        };
+        let mut old = Map::LpmTrie(trie(8));
+        let mut filled: LpmTrie<_, u32, RuleEntry> = LpmTrie::try_from(&mut old).unwrap();
+        for (len, addr) in [(24, [10, 0, 0, 0]), (32, [10, 0, 0, 1])] {
+            let key = Key::new(len, u32::from_be_bytes(addr));
+            filled.insert(&key, entry, 0).unwrap();
+        }
+        let Map::LpmTrie(old) = old else {
+            unreachable!()
+        };
+        let mut new = Map::LpmTrie(trie(2));
+
+        assert_eq!(migrate_lpm::<u32, RuleEntry>(old, &mut new).unwrap(), 2);
+        let new: LpmTrie<_, u32, RuleEntry> = LpmTrie::try_from(&new).unwrap();
+        let mut prefixes: Vec<_> = new
+            .iter()
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                assert_eq!(value, entry);
+                key.prefix_len()
+            })
+            .collect();
+        prefixes.sort();
+        assert_eq!(prefixes, [24, 32]);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn migrate_array_copies_common_indices() {
+        let array = |len| create(bpf_map_type::BPF_MAP_TYPE_ARRAY, 4, 4, len);
+        let mut old = Map::Array(array(4));
+        let mut filled: Array<_, u32> = Array::try_from(&mut old).unwrap();
+        for index in 0..4 {
+            filled.set(index, index + 1, 0).unwrap();
+        }
+        let Map::Array(old) = old else { unreachable!() };
+        let mut new = Map::Array(array(2));
+
+        assert_eq!(migrate_array::<u32>(old, &mut new).unwrap(), 2);
+        let new: Array<_, u32> = Array::try_from(&new).unwrap();
+        assert_eq!(new.get(&1, 0).unwrap(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn migrate_skips_unmigrated_and_unknown_maps() {
+        let mut new = Map::HashMap(hash_map(1));
+        assert!(migrate("STATS", hash_map(1), &mut new).unwrap().is_none());
+        assert!(
+            migrate("NO_SUCH_MAP", hash_map(1), &mut new)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
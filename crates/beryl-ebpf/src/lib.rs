@@ -1,43 +1,293 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use aya::{
-    Ebpf, include_bytes_aligned,
-    programs::{SchedClassifier, TcAttachType, tc},
+    Ebpf, EbpfLoader, include_bytes_aligned,
+    maps::{MapData, MapInfo},
+    programs::{
+        SchedClassifier, TcAttachType, Xdp,
+        links::{FdLink, PinnedLink},
+        tc::{self, SchedClassifierLink},
+        xdp::XdpLink,
+    },
+    util::KernelVersion,
 };
-use aya_log::EbpfLogger;
-use tracing::{info, warn};
+use aya_obj::maps::PinningType;
+use beryl_common::{Addr128, FlowKey, FlowState, ScanState, TokenBucket};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+mod layout;
+mod maps;
+
+pub use maps::{
+    Blocklist, IcmpRules, InterfaceMap, MapError, MapUsage, Params, PortBlocklist, PortPrefix,
+    Prefix, RuleTable, Settings, StatsMap,
+};
+
+/// bpffs directory holding the pinned maps (`maps/`) and program links
+/// (`links/`), which keep the firewall running across daemon restarts
+pub const PIN_DIR: &str = "/sys/fs/bpf/beryl";
+
+/// Settings that must be fixed before the eBPF object is loaded.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub map_sizes: MapSizes,
+    /// Discard pinned maps and links instead of reusing them
+    pub reset_maps: bool,
+    /// eBPF object file to load instead of the one built into the daemon
+    pub object: Option<PathBuf>,
+}
+
+/// Capacities of the size-limited maps; `None` keeps the size defined in the
+/// object.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapSizes {
+    /// Ingress blocklist prefixes, per address family
+    pub blocklist: Option<u32>,
+    /// Egress blocklist prefixes, per address family
+    pub egress_blocklist: Option<u32>,
+    /// Port blocklist prefixes
+    pub port_blocklist: Option<u32>,
+    /// Tracked connections
+    pub conntrack: Option<u32>,
+    /// Sources tracked by each rate limiter
+    pub rate_limit: Option<u32>,
+    /// Sources tracked by port-scan detection
+    pub port_scan: Option<u32>,
+}
+
+impl MapSizes {
+    /// Map sizes that differ from the object's definitions.
+    fn max_entries(&self) -> Vec<(&'static str, u32)> {
+        let sizes: [(&[&'static str], _); 6] = [
+            (&["BLOCKLIST", "BLOCKLIST_V6"], self.blocklist),
+            (&["EGRESS_BLOCK", "EGRESS_BLOCK_V6"], self.egress_blocklist),
+            (&["PORT_BLOCKLIST"], self.port_blocklist),
+            (&["CONNTRACK"], self.conntrack),
+            (
+                &["RATE_LIMIT", "SYN_RATE_LIMIT", "ICMP_RATE_LIMIT"],
+                self.rate_limit,
+            ),
+            (&["PORT_SCAN"], self.port_scan),
+        ];
+        let mut max_entries = Vec::new();
+        for (names, entries) in sizes {
+            if let Some(entries) = entries {
+                max_entries.extend(names.iter().map(|&name| (name, entries)));
+            }
+        }
+        max_entries
+    }
+}
+
+/// A program attached to an interface, remembered so that
+/// [`BerylEbpf::reload`] can move it to the reloaded programs.
+#[derive(Debug, Clone)]
+enum Attachment {
+    Xdp { iface: String, skb_mode: bool },
+    TcEgress { iface: String },
+}
+
+impl Attachment {
+    fn pin(&self) -> PathBuf {
+        match self {
+            Attachment::Xdp { iface, skb_mode } => xdp_link_pin(iface, *skb_mode),
+            Attachment::TcEgress { iface } => link_pin(&format!("tc-egress-{iface}")),
+        }
+    }
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attachment::Xdp { iface, .. } => write!(f, "XDP program on {iface}"),
+            Attachment::TcEgress { iface } => write!(f, "TC egress program on {iface}"),
+        }
+    }
+}
 
 pub struct BerylEbpf {
     ebpf: Ebpf,
+    blocklist: Blocklist,
+    port_blocklist: PortBlocklist,
+    egress_blocklist: Blocklist,
+    bogons: Blocklist,
+    rules: RuleTable,
+    icmp_rules: IcmpRules,
+    iface_roles: InterfaceMap,
+    fast_path_ifaces: InterfaceMap,
+    settings: Settings,
+    stats: StatsMap,
+    options: LoadOptions,
+    attachments: Vec<Attachment>,
+    /// Link pins attached or replaced by this run
+    links: HashSet<PathBuf>,
 }
 
 impl BerylEbpf {
-    pub fn load() -> Result<Self> {
-        // Load eBPF bytecode
-        #[cfg(debug_assertions)]
-        let mut ebpf = Ebpf::load(include_bytes_aligned!(
-            "../../../beryl-router-ebpf/target/bpfel-unknown-none/debug/beryl-router-ebpf"
-        ))?;
+    pub fn load(options: &LoadOptions) -> Result<Self> {
+        let pin_dir = Path::new(PIN_DIR);
+        if options.reset_maps && pin_dir.exists() {
+            fs::remove_dir_all(pin_dir)
+                .with_context(|| format!("Failed to remove {}", pin_dir.display()))?;
+            info!(path = PIN_DIR, "Discarded pinned maps and links");
+        }
+        Self::open(options)
+    }
 
-        #[cfg(not(debug_assertions))]
-        let mut ebpf = Ebpf::load(include_bytes_aligned!(
-            "../../../beryl-router-ebpf/target/bpfel-unknown-none/release/beryl-router-ebpf"
-        ))?;
+    /// Loads the eBPF object on top of the pinned maps, after checking its
+    /// map layout against the `beryl-common` types.
+    fn open(options: &LoadOptions) -> Result<Self> {
+        let object = match &options.object {
+            Some(path) => Cow::Owned(
+                fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+            ),
+            None => Cow::Borrowed(embedded_object()),
+        };
+        let parsed = aya_obj::Object::parse(&object).context("Failed to parse eBPF object")?;
+        layout::verify(&parsed)?;
+        if let Some((name, _)) = options
+            .map_sizes
+            .max_entries()
+            .into_iter()
+            .find(|&(_, entries)| entries == 0)
+        {
+            bail!("{name} map size must be at least 1");
+        }
+
+        let maps_dir = Path::new(PIN_DIR).join("maps");
+        let retired = prepare_map_pins(&parsed, &maps_dir, options)?;
+
+        let mut loader = EbpfLoader::new();
+        loader.map_pin_path(&maps_dir);
+        for (name, entries) in options.map_sizes.max_entries() {
+            loader.set_max_entries(name, entries);
+        }
+        let mut ebpf = loader.load(&object)?;
 
-        // Initialize eBPF logging
-        if let Err(e) = EbpfLogger::init(&mut ebpf) {
-            warn!("Failed to initialize eBPF logger: {}", e);
+        for (name, old) in retired {
+            let Some(new) = ebpf.map_mut(&name) else {
+                continue;
+            };
+            match layout::migrate(&name, old, new) {
+                Ok(Some(entries)) => info!(map = %name, entries, "Migrated resized map"),
+                Ok(None) => {}
+                Err(e) => warn!(map = %name, "Map contents lost: {:#}", e),
+            }
         }
 
-        Ok(Self { ebpf })
+        Ok(Self {
+            blocklist: Blocklist::take(&mut ebpf, "BLOCKLIST", "BLOCKLIST_V6")?,
+            port_blocklist: PortBlocklist::take(&mut ebpf)?,
+            egress_blocklist: Blocklist::take(&mut ebpf, "EGRESS_BLOCK", "EGRESS_BLOCK_V6")?,
+            bogons: Blocklist::take(&mut ebpf, "BOGONS", "BOGONS_V6")?,
+            rules: RuleTable::take(&mut ebpf)?,
+            icmp_rules: IcmpRules::take(&mut ebpf)?,
+            iface_roles: InterfaceMap::take(&mut ebpf, "IFACE_ROLES")?,
+            fast_path_ifaces: InterfaceMap::take(&mut ebpf, "FAST_PATH_IFACES")?,
+            settings: Settings::take(&mut ebpf)?,
+            stats: StatsMap::take(&mut ebpf)?,
+            ebpf,
+            options: options.clone(),
+            attachments: Vec::new(),
+            links: HashSet::new(),
+        })
     }
 
+    /// Loads the eBPF object again (from [`LoadOptions::object`] if set, so
+    /// a new build of the datapath can be dropped in) and atomically switches
+    /// every attached interface to the new programs.
+    ///
+    /// The new programs share the pinned maps, and maps whose capacity
+    /// changed (in the object or in `map_sizes`) are migrated, so rules and
+    /// flow state carry over. Every attachment must be behind a pinned link,
+    /// or nothing is replaced. If replacing fails partway, the interfaces
+    /// switched so far are put back on the running programs, which keep
+    /// using the maps this handle updates; the pins of resized maps already
+    /// point at the new maps, so the next start picks up their contents as
+    /// of the failed reload.
+    pub fn reload(&mut self, map_sizes: MapSizes) -> Result<()> {
+        if let Some(attachment) = self
+            .attachments
+            .iter()
+            .find(|attachment| !self.links.contains(&attachment.pin()))
+        {
+            bail!("{attachment} has no pinned link and cannot be replaced in place");
+        }
+
+        let options = LoadOptions {
+            map_sizes,
+            ..self.options.clone()
+        };
+        let mut next = Self::open(&options)?;
+        let attachments = std::mem::take(&mut self.attachments);
+        for (switched, attachment) in attachments.iter().enumerate() {
+            if let Err(e) = next.attach(attachment) {
+                for attachment in &attachments[..switched] {
+                    if let Err(e) = self.attach(attachment) {
+                        warn!(%attachment, "Failed to restore the running program: {:#}", e);
+                    }
+                }
+                self.attachments = attachments;
+                return Err(e);
+            }
+        }
+
+        info!(programs = next.attachments.len(), "eBPF programs replaced");
+        *self = next;
+        Ok(())
+    }
+
+    fn attach(&mut self, attachment: &Attachment) -> Result<()> {
+        match attachment {
+            Attachment::Xdp { iface, skb_mode } => self.attach_xdp(iface, *skb_mode),
+            Attachment::TcEgress { iface } => self.attach_tc_egress(iface),
+        }
+    }
+
+    /// Attaches the XDP firewall to `iface`.
+    ///
+    /// If a previous run left a pinned link in the same mode on the
+    /// interface, the new program atomically replaces the old one behind it.
+    /// Otherwise (after detaching a link left in the other mode) the new link
+    /// is pinned so the program stays attached when the daemon exits.
     pub fn attach_xdp(&mut self, iface: &str, skb_mode: bool) -> Result<()> {
-        let program: &mut aya::programs::Xdp = self
+        let program: &mut Xdp = self
             .ebpf
             .program_mut("xdp_firewall")
             .context("XDP program not found")?
             .try_into()?;
-        program.load()?;
+        // One program serves every interface, so it is only loaded once
+        if program.fd().is_err() {
+            program.load()?;
+        }
+
+        // A link keeps the mode it was attached in, so a program left in the
+        // other mode is detached and the new one attached afresh
+        let stale = xdp_link_pin(iface, !skb_mode);
+        if stale.exists() {
+            fs::remove_file(&stale)
+                .with_context(|| format!("Failed to remove {}", stale.display()))?;
+            info!(iface, "Detached XDP program attached in the other mode");
+        }
+
+        let pin = xdp_link_pin(iface, skb_mode);
+        if let Some(link) = open_link(&pin, |link| Ok(XdpLink::try_from(link)?)) {
+            program
+                .attach_to_link(link)
+                .context("Failed to replace XDP program on pinned link")?;
+            self.links.insert(pin);
+            self.attachments.push(Attachment::Xdp {
+                iface: iface.to_string(),
+                skb_mode,
+            });
+            info!(iface, "XDP program replaced on pinned link");
+            return Ok(());
+        }
 
         let flags = if skb_mode {
             aya::programs::XdpFlags::SKB_MODE
@@ -45,10 +295,23 @@ impl BerylEbpf {
             aya::programs::XdpFlags::default()
         };
 
-        program
+        let link_id = program
             .attach(iface, flags)
             .context("Failed to attach XDP program")?;
 
+        // XDP attachments are bpf_links, which can be pinned, from Linux 5.9
+        if kernel_at_least(5, 9) {
+            let link = FdLink::try_from(program.take_link(link_id)?)?;
+            pin_link(link, &pin)?;
+            self.links.insert(pin);
+        } else {
+            warn!(iface, "Kernel too old to pin the XDP link");
+        }
+
+        self.attachments.push(Attachment::Xdp {
+            iface: iface.to_string(),
+            skb_mode,
+        });
         info!(
             iface,
             mode = if skb_mode { "SKB" } else { "Native" },
@@ -57,30 +320,306 @@ impl BerylEbpf {
         Ok(())
     }
 
+    /// Attaches the TC egress program to `iface`, replacing or pinning its
+    /// link like [`BerylEbpf::attach_xdp`].
     pub fn attach_tc_egress(&mut self, iface: &str) -> Result<()> {
-        // Ensure qdisc exists (usually clsact)
-        let _ = tc::qdisc_add_clsact(iface); // Ignore error if already exists
-
         let program: &mut SchedClassifier = self
             .ebpf
             .program_mut("tc_egress")
             .context("TC egress program not found")?
             .try_into()?;
-        program.load()?;
+        // Shared by every interface, like the XDP program
+        if program.fd().is_err() {
+            program.load()?;
+        }
+
+        let pin = link_pin(&format!("tc-egress-{iface}"));
+        if let Some(link) = open_link(&pin, |link| Ok(SchedClassifierLink::try_from(link)?)) {
+            program
+                .attach_to_link(link)
+                .context("Failed to replace TC egress program on pinned link")?;
+            self.links.insert(pin);
+            self.attachments.push(Attachment::TcEgress {
+                iface: iface.to_string(),
+            });
+            info!(iface, "TC egress program replaced on pinned link");
+            return Ok(());
+        }
 
-        program
+        // Ensure qdisc exists (usually clsact)
+        let _ = tc::qdisc_add_clsact(iface); // Ignore error if already exists
+
+        let link_id = program
             .attach(iface, TcAttachType::Egress)
             .context("Failed to attach TC egress program")?;
 
+        // TCX attachments are bpf_links, which can be pinned, from Linux 6.6;
+        // older kernels attach through netlink
+        if kernel_at_least(6, 6) {
+            let link = FdLink::try_from(program.take_link(link_id)?)?;
+            pin_link(link, &pin)?;
+            self.links.insert(pin);
+        } else {
+            warn!(iface, "Kernel too old to pin the TC egress link");
+        }
+
+        self.attachments.push(Attachment::TcEgress {
+            iface: iface.to_string(),
+        });
         info!(iface, "TC egress program attached");
         Ok(())
     }
 
-    pub fn get_map_mut(&mut self, name: &str) -> Option<&mut aya::maps::Map> {
-        self.ebpf.map_mut(name)
+    /// Removes the link pins of interfaces this run did not attach to, which
+    /// detaches the programs a previous run left on them. Call once every
+    /// interface is attached.
+    pub fn release_stale_links(&self) -> Result<()> {
+        let dir = Path::new(PIN_DIR).join("links");
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !self.links.contains(&path) {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                info!(pin = %path.display(), "Released link of an unused interface");
+            }
+        }
+        Ok(())
+    }
+
+    /// Ingress source blocklist (XDP), shared by configured and temporary
+    /// blocks.
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
     }
 
-    pub fn get_map(&self, name: &str) -> Option<&aya::maps::Map> {
-        self.ebpf.map(name)
+    pub fn blocklist_mut(&mut self) -> &mut Blocklist {
+        &mut self.blocklist
     }
+
+    /// Ingress destination port blocklist (XDP).
+    pub fn port_blocklist(&self) -> &PortBlocklist {
+        &self.port_blocklist
+    }
+
+    pub fn port_blocklist_mut(&mut self) -> &mut PortBlocklist {
+        &mut self.port_blocklist
+    }
+
+    /// Egress destination blocklist (TC).
+    pub fn egress_blocklist(&self) -> &Blocklist {
+        &self.egress_blocklist
+    }
+
+    pub fn egress_blocklist_mut(&mut self) -> &mut Blocklist {
+        &mut self.egress_blocklist
+    }
+
+    /// Bogon sources dropped on WAN ingress, with pass entries for exceptions.
+    pub fn bogons_mut(&mut self) -> &mut Blocklist {
+        &mut self.bogons
+    }
+
+    /// Ordered rule table (XDP).
+    pub fn rules(&self) -> &RuleTable {
+        &self.rules
+    }
+
+    pub fn rules_mut(&mut self) -> &mut RuleTable {
+        &mut self.rules
+    }
+
+    /// ICMP/ICMPv6 rules by type and code (XDP).
+    pub fn icmp_rules_mut(&mut self) -> &mut IcmpRules {
+        &mut self.icmp_rules
+    }
+
+    /// Role of each configured interface, by ifindex.
+    pub fn iface_roles_mut(&mut self) -> &mut InterfaceMap {
+        &mut self.iface_roles
+    }
+
+    /// Interfaces the XDP fast path may redirect to, by ifindex.
+    pub fn fast_path_ifaces_mut(&mut self) -> &mut InterfaceMap {
+        &mut self.fast_path_ifaces
+    }
+
+    /// Settings of the individual datapath features.
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    pub fn stats(&self) -> &StatsMap {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut StatsMap {
+        &mut self.stats
+    }
+
+    /// Fill levels of the blocklists and the per-flow and per-source
+    /// tracking tables.
+    pub fn map_usage(&self) -> Result<Vec<MapUsage>, MapError> {
+        let mut usage = Vec::new();
+        usage.extend(self.blocklist.usage()?);
+        usage.push(self.port_blocklist.usage()?);
+        usage.extend(self.egress_blocklist.usage()?);
+        usage.extend(self.bogons.usage()?);
+        usage.push(maps::hash_usage::<FlowKey, FlowState>(
+            &self.ebpf,
+            "CONNTRACK",
+        )?);
+        for name in ["RATE_LIMIT", "SYN_RATE_LIMIT", "ICMP_RATE_LIMIT"] {
+            usage.push(maps::hash_usage::<Addr128, TokenBucket>(&self.ebpf, name)?);
+        }
+        usage.push(maps::hash_usage::<Addr128, ScanState>(
+            &self.ebpf,
+            "PORT_SCAN",
+        )?);
+        Ok(usage)
+    }
+
+    /// Takes ownership of a map, e.g. to consume a ring buffer from a task.
+    pub fn take_map(&mut self, name: &str) -> Option<aya::maps::Map> {
+        self.ebpf.take_map(name)
+    }
+}
+
+fn embedded_object() -> &'static [u8] {
+    #[cfg(debug_assertions)]
+    let object: &[u8] = include_bytes_aligned!(
+        "../../../beryl-router-ebpf/target/bpfel-unknown-none/debug/beryl-router-ebpf"
+    );
+
+    #[cfg(not(debug_assertions))]
+    let object: &[u8] = include_bytes_aligned!(
+        "../../../beryl-router-ebpf/target/bpfel-unknown-none/release/beryl-router-ebpf"
+    );
+
+    object
+}
+
+/// Readies `dir` for the loader, which reuses any map pinned there.
+///
+/// Pins whose layout no longer matches the object (after `options`
+/// overrides), and pins of maps the object no longer has, are removed so the
+/// loader creates those maps afresh. Maps that only changed capacity or flags
+/// are returned, still open, so their contents can be migrated.
+fn prepare_map_pins(
+    object: &aya_obj::Object,
+    dir: &Path,
+    options: &LoadOptions,
+) -> Result<Vec<(String, MapData)>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create {} (is bpffs mounted?)", dir.display()))?;
+
+    let overrides = options.map_sizes.max_entries();
+    let mut pinned = HashSet::new();
+    let mut retired = Vec::new();
+
+    for (name, map) in &object.maps {
+        if map.pinning() != PinningType::ByName {
+            continue;
+        }
+        pinned.insert(name.as_str());
+
+        let path = dir.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let max_entries = overrides
+            .iter()
+            .find(|(map, _)| map == name)
+            .map_or(map.max_entries(), |&(_, entries)| entries);
+        let Ok(info) = MapInfo::from_pin(&path) else {
+            warn!(map = %name, "Unreadable map pin, recreating the map");
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            continue;
+        };
+        let same_layout = info
+            .map_type()
+            .is_ok_and(|map_type| map_type as u32 == map.map_type())
+            && info.key_size() == map.key_size()
+            && info.value_size() == map.value_size();
+
+        if same_layout && info.max_entries() == max_entries && info.map_flags() == map.map_flags() {
+            debug!(map = %name, "Reusing pinned map");
+            continue;
+        }
+        if same_layout {
+            warn!(
+                map = %name,
+                from = info.max_entries(),
+                to = max_entries,
+                "Pinned map resized, migrating its contents"
+            );
+            match MapData::from_pin(&path) {
+                Ok(data) => retired.push((name.clone(), data)),
+                Err(e) => warn!(map = %name, "Map contents lost: {}", e),
+            }
+        } else {
+            warn!(map = %name, "Pinned map layout changed, recreating it");
+        }
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if name.is_some_and(|name| !pinned.contains(name)) {
+            debug!(path = %path.display(), "Removing pin of a retired map");
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(retired)
+}
+
+fn link_pin(name: &str) -> PathBuf {
+    Path::new(PIN_DIR).join("links").join(name)
+}
+
+/// SKB-mode links are pinned under the name iproute2 shows for the mode.
+fn xdp_link_pin(iface: &str, skb_mode: bool) -> PathBuf {
+    if skb_mode {
+        link_pin(&format!("xdpgeneric-{iface}"))
+    } else {
+        link_pin(&format!("xdp-{iface}"))
+    }
+}
+
+/// Opens a link pinned by a previous run. A pin that cannot be used is
+/// removed, which detaches its program.
+fn open_link<L>(pin: &Path, convert: impl FnOnce(FdLink) -> Result<L>) -> Option<L> {
+    if !pin.exists() {
+        return None;
+    }
+    match PinnedLink::from_pin(pin)
+        .map_err(anyhow::Error::from)
+        .and_then(|link| convert(FdLink::from(link)))
+    {
+        Ok(link) => Some(link),
+        Err(e) => {
+            warn!(pin = %pin.display(), "Discarding unusable link pin: {:#}", e);
+            let _ = fs::remove_file(pin);
+            None
+        }
+    }
+}
+
+/// Pins a link so that its program stays attached after the daemon exits.
+fn pin_link(link: FdLink, pin: &Path) -> Result<()> {
+    if let Some(dir) = pin.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    link.pin(pin)
+        .with_context(|| format!("Failed to pin link at {}", pin.display()))?;
+    Ok(())
+}
+
+fn kernel_at_least(major: u8, minor: u8) -> bool {
+    KernelVersion::current().is_ok_and(|version| version >= KernelVersion::new(major, minor, 0))
 }
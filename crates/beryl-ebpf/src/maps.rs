@@ -0,0 +1,1055 @@
+//! Typed handles for the rule, settings and statistics maps.
+//!
+//! The handles take their maps out of the loaded object and check them
+//! against the `beryl-common` types, so a missing or mismatched map fails the
+//! load instead of the first update.
+
+use aya::{
+    Ebpf, Pod,
+    maps::{
+        Array, HashMap, IterableMap, LpmTrie, MapData, PerCpuArray, PerCpuValues, lpm_trie::Key,
+    },
+    sys::SyscallError,
+    util::nr_cpus,
+};
+use beryl_common::{
+    AntiSpoofParams, CaptureParams, ConntrackParams, FastPathParams, FilterRule, IcmpAction,
+    IcmpKey, IcmpParams, MAX_FILTER_RULES, PORT_KEY_BITS, PortScanParams, RateLimitParams,
+    RuleEntry, RuleSet, Stats, ValidationParams, port_key,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use tracing::debug;
+
+/// Why a map could not be opened or updated.
+#[derive(Debug)]
+pub enum MapError {
+    /// The eBPF object has no map of this name
+    Missing(&'static str),
+    /// The map's type or layout differs from the `beryl-common` type
+    Mismatch { name: &'static str, detail: String },
+    /// The map is too small for the entries it was asked to hold
+    Full {
+        name: &'static str,
+        entries: usize,
+        max_entries: u32,
+    },
+    /// A lookup or update of the map failed
+    Map {
+        name: &'static str,
+        source: aya::maps::MapError,
+    },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Missing(name) => write!(f, "{name} map not found"),
+            MapError::Mismatch { name, detail } => write!(f, "{name} map mismatch: {detail}"),
+            MapError::Full {
+                name,
+                entries,
+                max_entries,
+            } => write!(
+                f,
+                "{name} map needs {entries} entries but holds at most {max_entries}; \
+                 raise its map size"
+            ),
+            MapError::Map { name, source } => write!(f, "{name} map: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::Map { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Attaches the map name to a failed map operation.
+fn failed(name: &'static str) -> impl FnOnce(aya::maps::MapError) -> MapError {
+    move |source| MapError::Map { name, source }
+}
+
+/// Whether a map update or removal failed with the given errno, e.g.
+/// `ENOENT` as [`io::ErrorKind::NotFound`].
+fn failed_with(error: &aya::maps::MapError, kind: io::ErrorKind) -> bool {
+    matches!(
+        error,
+        aya::maps::MapError::SyscallError(SyscallError { io_error, .. }) if io_error.kind() == kind
+    )
+}
+
+/// `BPF_NOEXIST`: fail an update whose key is already present.
+const BPF_NOEXIST: u64 = 1;
+
+/// Takes a map out of the object as the typed map `M`.
+fn take<M>(ebpf: &mut Ebpf, name: &'static str) -> Result<M, MapError>
+where
+    M: TryFrom<aya::maps::Map, Error = aya::maps::MapError>,
+{
+    let map = ebpf.take_map(name).ok_or(MapError::Missing(name))?;
+    M::try_from(map).map_err(|e| MapError::Mismatch {
+        name,
+        detail: e.to_string(),
+    })
+}
+
+/// How full a size-limited map is.
+#[derive(Clone, Debug)]
+pub struct MapUsage {
+    pub name: &'static str,
+    pub entries: usize,
+    pub max_entries: u32,
+}
+
+impl MapUsage {
+    /// Counts the entries of a map by walking its keys.
+    fn count<K: Pod, V>(
+        name: &'static str,
+        map: &impl IterableMap<K, V>,
+        mut keys: impl Iterator<Item = Result<K, aya::maps::MapError>>,
+    ) -> Result<Self, MapError> {
+        Ok(Self {
+            name,
+            entries: keys
+                .try_fold(0, |count, key| key.map(|_| count + 1))
+                .map_err(failed(name))?,
+            max_entries: max_entries(name, map)?,
+        })
+    }
+}
+
+fn max_entries<K: Pod, V>(
+    name: &'static str,
+    map: &impl IterableMap<K, V>,
+) -> Result<u32, MapError> {
+    Ok(map.map().info().map_err(failed(name))?.max_entries())
+}
+
+/// Reports the fill level of a hash map still held by the object.
+pub(crate) fn hash_usage<K: Pod, V: Pod>(
+    ebpf: &Ebpf,
+    name: &'static str,
+) -> Result<MapUsage, MapError> {
+    let map = ebpf.map(name).ok_or(MapError::Missing(name))?;
+    let map: HashMap<_, K, V> = HashMap::try_from(map).map_err(|e| MapError::Mismatch {
+        name,
+        detail: e.to_string(),
+    })?;
+    MapUsage::count(name, &map, map.keys())
+}
+
+/// An address or CIDR prefix, as stored in the address blocklists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    pub len: u32,
+}
+
+impl Prefix {
+    /// The prefix covering only `addr`.
+    pub fn host(addr: IpAddr) -> Self {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, len }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// A protocol and destination port prefix in the port blocklist: the ports
+/// sharing the top `bits` bits of `port`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortPrefix {
+    pub proto: u8,
+    pub port: u16,
+    /// 0-16
+    pub bits: u32,
+}
+
+impl PortPrefix {
+    /// First and last port covered.
+    pub fn ports(&self) -> (u16, u16) {
+        let size = 1u32 << (16 - self.bits);
+        (self.port, (self.port as u32 + size - 1) as u16)
+    }
+
+    fn key(&self) -> Key<[u8; 4]> {
+        Key::new(
+            PORT_KEY_BITS - 16 + self.bits,
+            port_key(self.proto, self.port),
+        )
+    }
+
+    fn from_key(key: &Key<[u8; 4]>) -> Self {
+        let [proto, hi, lo, _] = key.data();
+        Self {
+            proto,
+            port: u16::from_be_bytes([hi, lo]),
+            bits: key.prefix_len() + 16 - PORT_KEY_BITS,
+        }
+    }
+}
+
+/// The updates that bring a map from its existing entries to the wanted
+/// ones.
+///
+/// New and changed entries are written; unchanged entries are skipped so
+/// they keep their hit counters. Configured entries that are no longer
+/// wanted are stale, while temporary entries (non-zero `expires_ns`) are
+/// left alone unless a wanted entry takes over their key. Stale entries are
+/// removed after the writes, so the datapath never sees a partial ruleset;
+/// the map therefore needs room for the old and new entries at once.
+#[derive(Debug, PartialEq)]
+struct Plan<K> {
+    /// Wanted entries with the entry each replaces, if any
+    writes: Vec<(K, RuleEntry, Option<RuleEntry>)>,
+    /// Stale entries, kept so a failed update can put them back
+    stale: Vec<(K, RuleEntry)>,
+    /// Number of distinct wanted keys
+    entries: usize,
+}
+
+impl<K: Copy + Ord> Plan<K> {
+    /// Fails with [`MapError::Full`] if the existing entries and the added
+    /// ones exceed `max_entries`. Later wanted entries for the same key win,
+    /// as with sequential inserts.
+    fn new(
+        name: &'static str,
+        max_entries: u32,
+        wanted: impl IntoIterator<Item = (K, RuleEntry)>,
+        existing: BTreeMap<K, RuleEntry>,
+    ) -> Result<Self, MapError> {
+        let wanted: BTreeMap<_, _> = wanted.into_iter().collect();
+
+        let added = wanted
+            .keys()
+            .filter(|key| !existing.contains_key(key))
+            .count();
+        if existing.len() + added > max_entries as usize {
+            return Err(MapError::Full {
+                name,
+                entries: existing.len() + added,
+                max_entries,
+            });
+        }
+
+        let stale = existing
+            .iter()
+            .filter(|(key, entry)| entry.expires_ns == 0 && !wanted.contains_key(key))
+            .map(|(&key, &entry)| (key, entry))
+            .collect();
+
+        let writes = wanted
+            .iter()
+            .filter_map(|(&key, &entry)| {
+                let previous = existing.get(&key).copied();
+                let unchanged = previous.is_some_and(|previous| {
+                    previous.expires_ns == 0
+                        && previous.action == entry.action
+                        && previous.rule == entry.rule
+                });
+                (!unchanged).then_some((key, entry, previous))
+            })
+            .collect();
+
+        Ok(Self {
+            writes,
+            stale,
+            entries: wanted.len(),
+        })
+    }
+}
+
+/// The changes a `Trie::replace` made, for putting the previous entries back.
+struct Undo<K: Pod> {
+    /// Written keys with the entry each replaced, if any
+    written: Vec<(Key<K>, Option<RuleEntry>)>,
+    removed: Vec<(Key<K>, RuleEntry)>,
+}
+
+/// An LPM trie of rule entries.
+struct Trie<K> {
+    name: &'static str,
+    map: LpmTrie<MapData, K, RuleEntry>,
+    max_entries: u32,
+}
+
+impl<K: Pod + Ord> Trie<K> {
+    fn take(ebpf: &mut Ebpf, name: &'static str) -> Result<Self, MapError> {
+        let map = take(ebpf, name)?;
+        Ok(Self {
+            name,
+            max_entries: max_entries(name, &map)?,
+            map,
+        })
+    }
+
+    fn usage(&self) -> Result<MapUsage, MapError> {
+        MapUsage::count(self.name, &self.map, self.map.keys())
+    }
+
+    fn list(&self) -> Result<Vec<(Key<K>, RuleEntry)>, MapError> {
+        self.map
+            .iter()
+            .collect::<Result<_, _>>()
+            .map_err(failed(self.name))
+    }
+
+    /// Looks `key` up the way the datapath does: the entry stored under
+    /// exactly `key` if there is one, else the one under the longest prefix
+    /// covering it.
+    fn lookup(&self, key: &Key<K>) -> Result<Option<RuleEntry>, MapError> {
+        match self.map.get(key, 0) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(aya::maps::MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(failed(self.name)(e)),
+        }
+    }
+
+    fn insert(&mut self, key: &Key<K>, entry: RuleEntry) -> Result<(), MapError> {
+        self.map.insert(key, entry, 0).map_err(failed(self.name))
+    }
+
+    /// Inserts an entry unless one is stored under exactly `key`. Returns
+    /// `false` if there is one.
+    fn insert_new(&mut self, key: &Key<K>, entry: RuleEntry) -> Result<bool, MapError> {
+        match self.map.insert(key, entry, BPF_NOEXIST) {
+            Ok(()) => Ok(true),
+            Err(e) if failed_with(&e, io::ErrorKind::AlreadyExists) => Ok(false),
+            Err(e) => Err(failed(self.name)(e)),
+        }
+    }
+
+    /// Removes the entry stored under exactly `key`. Returns `false` if there
+    /// is none.
+    fn remove(&mut self, key: &Key<K>) -> Result<bool, MapError> {
+        match self.map.remove(key) {
+            Ok(()) => Ok(true),
+            Err(e) if failed_with(&e, io::ErrorKind::NotFound) => Ok(false),
+            Err(e) => Err(failed(self.name)(e)),
+        }
+    }
+
+    fn clear(&mut self) -> Result<usize, MapError> {
+        let entries = self.list()?;
+        for (key, _) in &entries {
+            self.map.remove(key).map_err(failed(self.name))?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Works out how `replace` would bring the trie to `entries`.
+    fn plan(&self, entries: &[(Key<K>, RuleEntry)]) -> Result<Plan<(u32, K)>, MapError> {
+        let existing = self
+            .list()?
+            .into_iter()
+            .map(|(key, entry)| ((key.prefix_len(), key.data()), entry))
+            .collect();
+        let wanted = entries
+            .iter()
+            .map(|(key, entry)| ((key.prefix_len(), key.data()), *entry));
+        Plan::new(self.name, self.max_entries, wanted, existing)
+    }
+
+    /// Fails with [`MapError::Full`] if `replace` would, without touching
+    /// the trie.
+    fn check(&self, entries: &[(Key<K>, RuleEntry)]) -> Result<(), MapError> {
+        self.plan(entries).map(|_| ())
+    }
+
+    /// Brings the trie to the given entries by applying only the
+    /// differences, so the datapath never sees a partial ruleset.
+    ///
+    /// See [`Plan`] for which entries are written, kept and removed. If an
+    /// update fails, the changes made so far are undone; on success they are
+    /// returned so the caller can still undo them.
+    fn replace(&mut self, entries: &[(Key<K>, RuleEntry)]) -> Result<Undo<K>, MapError> {
+        let plan = self.plan(entries)?;
+        let mut undo = Undo {
+            written: Vec::new(),
+            removed: Vec::new(),
+        };
+        if let Err(e) = self.apply(&plan, &mut undo) {
+            self.undo(undo);
+            return Err(e);
+        }
+
+        debug!(
+            map = self.name,
+            entries = plan.entries,
+            written = plan.writes.len(),
+            removed = plan.stale.len(),
+            "Updated blocklist"
+        );
+        Ok(undo)
+    }
+
+    fn apply(&mut self, plan: &Plan<(u32, K)>, undo: &mut Undo<K>) -> Result<(), MapError> {
+        for &((prefix_len, data), entry, previous) in &plan.writes {
+            let key = Key::new(prefix_len, data);
+            self.insert(&key, entry)?;
+            undo.written.push((key, previous));
+        }
+        for &((prefix_len, data), entry) in &plan.stale {
+            let key = Key::new(prefix_len, data);
+            self.map.remove(&key).map_err(failed(self.name))?;
+            undo.removed.push((key, entry));
+        }
+        Ok(())
+    }
+
+    /// Puts back the entries a `replace` changed, as far as the map allows.
+    fn undo(&mut self, undo: Undo<K>) {
+        for (key, previous) in undo.written.into_iter().rev() {
+            let _ = match previous {
+                Some(previous) => self.map.insert(&key, previous, 0),
+                None => self.map.remove(&key),
+            };
+        }
+        for (key, entry) in undo.removed {
+            let _ = self.map.insert(&key, entry, 0);
+        }
+    }
+}
+
+/// An address blocklist: LPM tries of IPv4 and IPv6 prefixes (network byte
+/// order) mapping to rule entries.
+pub struct Blocklist {
+    v4: Trie<u32>,
+    v6: Trie<[u8; 16]>,
+}
+
+impl Blocklist {
+    pub(crate) fn take(
+        ebpf: &mut Ebpf,
+        v4: &'static str,
+        v6: &'static str,
+    ) -> Result<Self, MapError> {
+        Ok(Self {
+            v4: Trie::take(ebpf, v4)?,
+            v6: Trie::take(ebpf, v6)?,
+        })
+    }
+
+    /// The entry the datapath applies to `prefix`: the one stored under
+    /// exactly `prefix` if there is one, else the one under the longest
+    /// prefix covering it.
+    pub fn lookup(&self, prefix: &Prefix) -> Result<Option<RuleEntry>, MapError> {
+        match prefix.addr {
+            IpAddr::V4(addr) => self.v4.lookup(&v4_key(addr, prefix.len)),
+            IpAddr::V6(addr) => self.v6.lookup(&v6_key(addr, prefix.len)),
+        }
+    }
+
+    /// Inserts or overwrites the entry for `prefix`.
+    pub fn add(&mut self, prefix: &Prefix, entry: RuleEntry) -> Result<(), MapError> {
+        match prefix.addr {
+            IpAddr::V4(addr) => self.v4.insert(&v4_key(addr, prefix.len), entry),
+            IpAddr::V6(addr) => self.v6.insert(&v6_key(addr, prefix.len), entry),
+        }
+    }
+
+    /// Inserts the entry for `prefix` unless it already has one. Returns
+    /// `false` if it does.
+    pub fn add_new(&mut self, prefix: &Prefix, entry: RuleEntry) -> Result<bool, MapError> {
+        match prefix.addr {
+            IpAddr::V4(addr) => self.v4.insert_new(&v4_key(addr, prefix.len), entry),
+            IpAddr::V6(addr) => self.v6.insert_new(&v6_key(addr, prefix.len), entry),
+        }
+    }
+
+    /// Removes the entry stored under exactly `prefix`. Returns `false` if
+    /// there is none.
+    pub fn remove(&mut self, prefix: &Prefix) -> Result<bool, MapError> {
+        match prefix.addr {
+            IpAddr::V4(addr) => self.v4.remove(&v4_key(addr, prefix.len)),
+            IpAddr::V6(addr) => self.v6.remove(&v6_key(addr, prefix.len)),
+        }
+    }
+
+    /// All entries, IPv4 first.
+    pub fn list(&self) -> Result<Vec<(Prefix, RuleEntry)>, MapError> {
+        let v4 = self.v4.list()?.into_iter().map(|(key, entry)| {
+            let addr = Ipv4Addr::from(u32::from_be(key.data()));
+            (prefix(addr.into(), &key), entry)
+        });
+        let v6 = self.v6.list()?.into_iter().map(|(key, entry)| {
+            let addr = Ipv6Addr::from(key.data());
+            (prefix(addr.into(), &key), entry)
+        });
+        Ok(v4.chain(v6).collect())
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&mut self) -> Result<usize, MapError> {
+        Ok(self.v4.clear()? + self.v6.clear()?)
+    }
+
+    /// Fill levels of the IPv4 and IPv6 tries.
+    pub fn usage(&self) -> Result<[MapUsage; 2], MapError> {
+        Ok([self.v4.usage()?, self.v6.usage()?])
+    }
+
+    /// Fails with [`MapError::Full`] if the entries would not fit, without
+    /// changing the blocklist.
+    pub fn check(&self, entries: &[(Prefix, RuleEntry)]) -> Result<(), MapError> {
+        let (v4, v6) = split(entries);
+        self.v4.check(&v4)?;
+        self.v6.check(&v6)
+    }
+
+    /// Brings the blocklist to the given entries without a window in which
+    /// it is partially updated; see `Trie::replace`. Temporary entries
+    /// (non-zero `expires_ns`) survive unless `entries` covers their prefix.
+    ///
+    /// Fails without changing either trie if the entries do not fit, and
+    /// puts the IPv4 entries back if the IPv6 update fails.
+    pub fn replace(&mut self, entries: &[(Prefix, RuleEntry)]) -> Result<(), MapError> {
+        let (v4, v6) = split(entries);
+        self.v4.check(&v4)?;
+        self.v6.check(&v6)?;
+        let undo = self.v4.replace(&v4)?;
+        if let Err(e) = self.v6.replace(&v6) {
+            self.v4.undo(undo);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Trie keys with the entries to store under them.
+type TrieEntries<K> = Vec<(Key<K>, RuleEntry)>;
+
+/// Splits blocklist entries into IPv4 and IPv6 trie entries.
+fn split(entries: &[(Prefix, RuleEntry)]) -> (TrieEntries<u32>, TrieEntries<[u8; 16]>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for &(prefix, entry) in entries {
+        match prefix.addr {
+            IpAddr::V4(addr) => v4.push((v4_key(addr, prefix.len), entry)),
+            IpAddr::V6(addr) => v6.push((v6_key(addr, prefix.len), entry)),
+        }
+    }
+    (v4, v6)
+}
+
+fn v4_key(addr: Ipv4Addr, len: u32) -> Key<u32> {
+    Key::new(len, u32::from(addr).to_be())
+}
+
+fn v6_key(addr: Ipv6Addr, len: u32) -> Key<[u8; 16]> {
+    Key::new(len, addr.octets())
+}
+
+fn prefix<K: Pod>(addr: IpAddr, key: &Key<K>) -> Prefix {
+    Prefix {
+        addr,
+        len: key.prefix_len(),
+    }
+}
+
+/// The port blocklist: protocol and destination port prefixes (see
+/// `beryl_common::port_key`) mapping to rule entries.
+pub struct PortBlocklist {
+    trie: Trie<[u8; 4]>,
+}
+
+impl PortBlocklist {
+    pub(crate) fn take(ebpf: &mut Ebpf) -> Result<Self, MapError> {
+        Ok(Self {
+            trie: Trie::take(ebpf, "PORT_BLOCKLIST")?,
+        })
+    }
+
+    /// Inserts or overwrites the entry for `prefix`.
+    pub fn add(&mut self, prefix: &PortPrefix, entry: RuleEntry) -> Result<(), MapError> {
+        self.trie.insert(&prefix.key(), entry)
+    }
+
+    /// Removes the entry stored under exactly `prefix`. Returns `false` if
+    /// there is none.
+    pub fn remove(&mut self, prefix: &PortPrefix) -> Result<bool, MapError> {
+        self.trie.remove(&prefix.key())
+    }
+
+    pub fn list(&self) -> Result<Vec<(PortPrefix, RuleEntry)>, MapError> {
+        Ok(self
+            .trie
+            .list()?
+            .iter()
+            .map(|(key, entry)| (PortPrefix::from_key(key), *entry))
+            .collect())
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&mut self) -> Result<usize, MapError> {
+        self.trie.clear()
+    }
+
+    pub fn usage(&self) -> Result<MapUsage, MapError> {
+        self.trie.usage()
+    }
+
+    /// Fails with [`MapError::Full`] if the entries would not fit, like
+    /// [`Blocklist::check`].
+    pub fn check(&self, entries: &[(PortPrefix, RuleEntry)]) -> Result<(), MapError> {
+        self.trie.check(&port_keys(entries))
+    }
+
+    /// Brings the blocklist to the given entries, like
+    /// [`Blocklist::replace`].
+    pub fn replace(&mut self, entries: &[(PortPrefix, RuleEntry)]) -> Result<(), MapError> {
+        self.trie.replace(&port_keys(entries)).map(|_| ())
+    }
+}
+
+fn port_keys(entries: &[(PortPrefix, RuleEntry)]) -> TrieEntries<[u8; 4]> {
+    entries
+        .iter()
+        .map(|(prefix, entry)| (prefix.key(), *entry))
+        .collect()
+}
+
+/// The per-CPU packet statistics shared by the XDP and TC programs.
+pub struct StatsMap {
+    map: PerCpuArray<MapData, Stats>,
+}
+
+impl StatsMap {
+    const NAME: &'static str = "STATS";
+
+    pub(crate) fn take(ebpf: &mut Ebpf) -> Result<Self, MapError> {
+        Ok(Self {
+            map: take(ebpf, Self::NAME)?,
+        })
+    }
+
+    /// Statistics summed over all CPUs.
+    pub fn read(&self) -> Result<Stats, MapError> {
+        let per_cpu = self.map.get(&0, 0).map_err(failed(Self::NAME))?;
+        let mut total = Stats::default();
+        for stats in per_cpu.iter() {
+            total += *stats;
+        }
+        Ok(total)
+    }
+
+    /// Resets every counter to zero.
+    pub fn clear(&mut self) -> Result<(), MapError> {
+        let io_error = |e| MapError::Map {
+            name: Self::NAME,
+            source: aya::maps::MapError::IoError(e),
+        };
+        let cpus = nr_cpus().map_err(|(_, e)| io_error(e))?;
+        let zeroed = PerCpuValues::try_from(vec![Stats::default(); cpus]).map_err(io_error)?;
+        self.map.set(0, zeroed, 0).map_err(failed(Self::NAME))
+    }
+}
+
+/// A single-entry array holding the settings of one datapath feature (the
+/// `*_CONFIG` maps).
+pub struct Params<T: Pod> {
+    name: &'static str,
+    map: Array<MapData, T>,
+}
+
+impl<T: Pod> Params<T> {
+    pub(crate) fn take(ebpf: &mut Ebpf, name: &'static str) -> Result<Self, MapError> {
+        Ok(Self {
+            name,
+            map: take(ebpf, name)?,
+        })
+    }
+
+    pub fn get(&self) -> Result<T, MapError> {
+        self.map.get(&0, 0).map_err(failed(self.name))
+    }
+
+    pub fn set(&mut self, params: T) -> Result<(), MapError> {
+        self.map.set(0, params, 0).map_err(failed(self.name))
+    }
+}
+
+/// The settings maps of the datapath features.
+pub struct Settings {
+    pub validation: Params<ValidationParams>,
+    pub rate_limit: Params<RateLimitParams>,
+    pub conntrack: Params<ConntrackParams>,
+    pub fast_path: Params<FastPathParams>,
+    pub anti_spoof: Params<AntiSpoofParams>,
+    pub port_scan: Params<PortScanParams>,
+    pub icmp: Params<IcmpParams>,
+    pub capture: Params<CaptureParams>,
+}
+
+impl Settings {
+    pub(crate) fn take(ebpf: &mut Ebpf) -> Result<Self, MapError> {
+        Ok(Self {
+            validation: Params::take(ebpf, "VALIDATION_CONFIG")?,
+            rate_limit: Params::take(ebpf, "RATE_LIMIT_CONFIG")?,
+            conntrack: Params::take(ebpf, "CONNTRACK_CONFIG")?,
+            fast_path: Params::take(ebpf, "FAST_PATH_CONFIG")?,
+            anti_spoof: Params::take(ebpf, "ANTI_SPOOF_CONFIG")?,
+            port_scan: Params::take(ebpf, "PORT_SCAN_CONFIG")?,
+            icmp: Params::take(ebpf, "ICMP_CONFIG")?,
+            capture: Params::take(ebpf, "CAPTURE_CONFIG")?,
+        })
+    }
+}
+
+/// The double-buffered ordered rule table (`RULES`) and the bank selector
+/// (`RULE_SET`) the XDP program reads it through.
+pub struct RuleTable {
+    rules: Array<MapData, FilterRule>,
+    set: Array<MapData, u32>,
+}
+
+impl RuleTable {
+    const RULES: &'static str = "RULES";
+    const RULE_SET: &'static str = "RULE_SET";
+
+    pub(crate) fn take(ebpf: &mut Ebpf) -> Result<Self, MapError> {
+        Ok(Self {
+            rules: take(ebpf, Self::RULES)?,
+            set: take(ebpf, Self::RULE_SET)?,
+        })
+    }
+
+    fn rule_set(&self) -> Result<RuleSet, MapError> {
+        let bits = self.set.get(&0, 0).map_err(failed(Self::RULE_SET))?;
+        Ok(RuleSet::from_bits(bits))
+    }
+
+    /// The rules of the active bank, in evaluation order.
+    pub fn list(&self) -> Result<Vec<FilterRule>, MapError> {
+        let active = self.rule_set()?;
+        (0..active.count)
+            .map(|i| {
+                self.rules
+                    .get(&(active.base() + i), 0)
+                    .map_err(failed(Self::RULES))
+            })
+            .collect()
+    }
+
+    /// Fails with [`MapError::Full`] if there are more rules than a bank
+    /// holds.
+    pub fn check(&self, rules: &[FilterRule]) -> Result<(), MapError> {
+        if rules.len() > MAX_FILTER_RULES as usize {
+            return Err(MapError::Full {
+                name: Self::RULES,
+                entries: rules.len(),
+                max_entries: MAX_FILTER_RULES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the rules into the inactive bank and then switches banks, so
+    /// the XDP program moves from the old ruleset to the new one in a single
+    /// map update.
+    ///
+    /// Rules identical to an active one apart from their hit counters take
+    /// over its counters (less any hits counted while the bank is written).
+    pub fn replace(&mut self, rules: &[FilterRule]) -> Result<(), MapError> {
+        self.check(rules)?;
+        let active = self.rule_set()?;
+        let next = RuleSet {
+            bank: active.bank ^ 1,
+            count: rules.len() as u32,
+        };
+
+        let mut previous = self.list()?;
+        for (i, rule) in rules.iter().enumerate() {
+            let mut rule = *rule;
+            if let Some(old) = previous
+                .iter()
+                .position(|old| same_rule(old, &rule))
+                .map(|found| previous.swap_remove(found))
+            {
+                rule.entry.packets = old.entry.packets;
+                rule.entry.bytes = old.entry.bytes;
+            }
+            self.rules
+                .set(next.base() + i as u32, rule, 0)
+                .map_err(failed(Self::RULES))?;
+        }
+        self.set
+            .set(0, next.to_bits(), 0)
+            .map_err(failed(Self::RULE_SET))?;
+        debug!(
+            bank = next.bank,
+            rules = next.count,
+            "Switched rule table bank"
+        );
+        Ok(())
+    }
+}
+
+/// Whether two rules differ only in their hit counters.
+fn same_rule(a: &FilterRule, b: &FilterRule) -> bool {
+    let without_counters = |rule: &FilterRule| FilterRule {
+        entry: RuleEntry {
+            packets: 0,
+            bytes: 0,
+            ..rule.entry
+        },
+        ..*rule
+    };
+    without_counters(a) == without_counters(b)
+}
+
+/// The ICMP/ICMPv6 rules by type and code (`ICMP_RULES`).
+pub struct IcmpRules {
+    map: HashMap<MapData, IcmpKey, u32>,
+}
+
+impl IcmpRules {
+    const NAME: &'static str = "ICMP_RULES";
+
+    pub(crate) fn take(ebpf: &mut Ebpf) -> Result<Self, MapError> {
+        Ok(Self {
+            map: take(ebpf, Self::NAME)?,
+        })
+    }
+
+    /// Brings the table to the given rules, writing only the changes.
+    pub fn replace(&mut self, rules: &[(IcmpKey, IcmpAction)]) -> Result<(), MapError> {
+        let existing: Vec<(IcmpKey, u32)> = self
+            .map
+            .iter()
+            .collect::<Result<_, _>>()
+            .map_err(failed(Self::NAME))?;
+        for &(key, action) in rules {
+            if !existing.contains(&(key, action as u32)) {
+                self.map
+                    .insert(key, action as u32, 0)
+                    .map_err(failed(Self::NAME))?;
+            }
+        }
+        for (key, _) in existing {
+            if !rules.iter().any(|(wanted, _)| *wanted == key) {
+                self.map.remove(&key).map_err(failed(Self::NAME))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of interfaces by ifindex, each with a value (`IFACE_ROLES` maps
+/// them to their `InterfaceRole`, `FAST_PATH_IFACES` holds 0).
+pub struct InterfaceMap {
+    name: &'static str,
+    map: HashMap<MapData, u32, u32>,
+}
+
+impl InterfaceMap {
+    pub(crate) fn take(ebpf: &mut Ebpf, name: &'static str) -> Result<Self, MapError> {
+        Ok(Self {
+            name,
+            map: take(ebpf, name)?,
+        })
+    }
+
+    /// Brings the map to the given interfaces, removing the others.
+    pub fn replace(&mut self, ifaces: &BTreeMap<u32, u32>) -> Result<(), MapError> {
+        let stale: Vec<u32> = self
+            .map
+            .keys()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(failed(self.name))?
+            .into_iter()
+            .filter(|ifindex| !ifaces.contains_key(ifindex))
+            .collect();
+        for ifindex in stale {
+            self.map.remove(&ifindex).map_err(failed(self.name))?;
+        }
+        for (&ifindex, &value) in ifaces {
+            self.map
+                .insert(ifindex, value, 0)
+                .map_err(failed(self.name))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use beryl_common::PacketAction;
+
+    const MAX: u32 = 4;
+
+    fn drop(rule: u32) -> RuleEntry {
+        RuleEntry::new(PacketAction::Drop).with_rule(rule)
+    }
+
+    fn hit(entry: RuleEntry) -> RuleEntry {
+        RuleEntry {
+            packets: 10,
+            bytes: 1500,
+            ..entry
+        }
+    }
+
+    fn plan(
+        wanted: &[(u32, RuleEntry)],
+        existing: &[(u32, RuleEntry)],
+    ) -> Result<Plan<u32>, MapError> {
+        Plan::new(
+            "TEST",
+            MAX,
+            wanted.iter().copied(),
+            existing.iter().copied().collect(),
+        )
+    }
+
+    #[test]
+    fn unchanged_entries_keep_their_counters() {
+        let plan = plan(&[(1, drop(0)), (2, drop(1))], &[(1, hit(drop(0)))]).unwrap();
+        // Entry 1 is left alone, so its hits survive; only entry 2 is written
+        assert_eq!(plan.writes, [(2, drop(1), None)]);
+        assert!(plan.stale.is_empty());
+        assert_eq!(plan.entries, 2);
+    }
+
+    #[test]
+    fn changed_entries_are_rewritten() {
+        let old = hit(drop(0));
+        let pass = RuleEntry::new(PacketAction::Pass);
+        let plan = plan(&[(1, drop(3)), (2, pass)], &[(1, old), (2, drop(0))]).unwrap();
+        assert_eq!(
+            plan.writes,
+            [(1, drop(3), Some(old)), (2, pass, Some(drop(0)))]
+        );
+    }
+
+    #[test]
+    fn temporary_entries_are_kept() {
+        let temporary = drop(0).with_expiry(1_000);
+        let plan = plan(
+            &[(1, drop(0))],
+            &[(1, drop(0)), (2, temporary), (3, drop(1))],
+        )
+        .unwrap();
+        assert!(plan.writes.is_empty());
+        assert_eq!(plan.stale, [(3, drop(1))]);
+    }
+
+    #[test]
+    fn configured_entry_takes_over_temporary_one() {
+        let temporary = drop(0).with_expiry(1_000);
+        let plan = plan(&[(2, drop(0))], &[(2, temporary)]).unwrap();
+        assert_eq!(plan.writes, [(2, drop(0), Some(temporary))]);
+        assert!(plan.stale.is_empty());
+    }
+
+    #[test]
+    fn later_duplicates_win() {
+        let plan = plan(&[(1, drop(0)), (1, drop(5))], &[]).unwrap();
+        assert_eq!(plan.writes, [(1, drop(5), None)]);
+        assert_eq!(plan.entries, 1);
+    }
+
+    #[test]
+    fn full_counts_kept_temporary_entries() {
+        let temporary = drop(0).with_expiry(1_000);
+        let wanted: Vec<_> = (0..MAX).map(|key| (key, drop(key))).collect();
+        assert!(plan(&wanted, &[]).is_ok());
+
+        let err = plan(&wanted, &[(100, temporary)]).unwrap_err();
+        assert!(matches!(
+            err,
+            MapError::Full {
+                name: "TEST",
+                entries: 5,
+                max_entries: MAX,
+            }
+        ));
+    }
+
+    #[test]
+    fn old_and_new_entries_must_fit_together() {
+        let existing: Vec<_> = (0..MAX).map(|key| (key, drop(key))).collect();
+
+        // Swapping one entry needs MAX + 1 slots until the stale one goes
+        let wanted = [(0, drop(0)), (1, drop(1)), (2, drop(2)), (9, drop(9))];
+        let err = plan(&wanted, &existing).unwrap_err();
+        assert!(matches!(
+            err,
+            MapError::Full {
+                entries: 5,
+                max_entries: MAX,
+                ..
+            }
+        ));
+
+        // With a free slot the stale entry is removed after the writes
+        let plan = plan(&wanted[1..], &existing[1..]).unwrap();
+        assert_eq!(plan.stale, [(3, drop(3))]);
+        assert_eq!(plan.writes, [(9, drop(9), None)]);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn trie_updates_match_exact_prefixes() {
+        let map = crate::layout::tests::trie(MAX);
+        let mut trie = Trie {
+            name: "TEST",
+            map: LpmTrie::try_from(aya::maps::Map::LpmTrie(map)).unwrap(),
+            max_entries: MAX,
+        };
+        let net = v4_key(Ipv4Addr::new(10, 0, 0, 0), 24);
+        let host = v4_key(Ipv4Addr::new(10, 0, 0, 1), 32);
+
+        assert!(trie.insert_new(&net, drop(0)).unwrap());
+        assert!(!trie.insert_new(&net, drop(1)).unwrap());
+        // A lookup falls back to the covering prefix; a removal does not
+        assert_eq!(trie.lookup(&host).unwrap(), Some(drop(0)));
+        assert!(!trie.remove(&host).unwrap());
+
+        assert!(trie.insert_new(&host, drop(2)).unwrap());
+        assert_eq!(trie.lookup(&host).unwrap(), Some(drop(2)));
+        assert!(trie.remove(&host).unwrap());
+        assert!(trie.remove(&net).unwrap());
+        assert_eq!(trie.lookup(&host).unwrap(), None);
+    }
+
+    #[test]
+    fn same_rule_ignores_counters_only() {
+        let rule = FilterRule {
+            proto: 6,
+            dst_port_min: 22,
+            dst_port_max: 22,
+            entry: drop(0),
+            ..Default::default()
+        };
+        let counted = FilterRule {
+            entry: hit(rule.entry),
+            ..rule
+        };
+        assert!(same_rule(&rule, &counted));
+
+        let other_port = FilterRule {
+            dst_port_max: 23,
+            ..rule
+        };
+        assert!(!same_rule(&rule, &other_port));
+        let other_index = FilterRule {
+            entry: drop(1),
+            ..rule
+        };
+        assert!(!same_rule(&rule, &other_index));
+    }
+}
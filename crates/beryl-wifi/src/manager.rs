@@ -50,3 +50,9 @@ impl WifiManager {
         Ok(())
     }
 }
+
+impl Default for WifiManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,10 +1,23 @@
-use axum::{Json, Router, extract::State, routing::get};
-use beryl_common::Stats;
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use beryl_common::{CAPTURE_SNAPLEN_MAX, CaptureFilter, RuleEntry, Stats};
 use beryl_config::Config;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tower_http::trace::TraceLayer;
 
+use crate::{
+    blocklist::{BlockError, BlockRequest, TimedBlock},
+    capture::{self, CaptureError, CaptureLimits},
+    events::FirewallEvent,
+};
+
 // Re-export Router for use in main.rs
 pub use crate::Router as AppRouter;
 
@@ -30,12 +43,81 @@ pub struct ServicesStatus {
 #[derive(serde::Serialize)]
 pub struct StatsResponse {
     pub packets: Stats,
+    pub bytes: ByteStats,
+    pub hits: FirewallHits,
+    pub maps: Vec<MapUsage>,
+}
+
+/// How full a size-limited eBPF map is.
+#[derive(Clone, serde::Serialize)]
+pub struct MapUsage {
+    pub name: &'static str,
+    pub entries: usize,
+    pub max_entries: u32,
+}
+
+impl From<beryl_ebpf::MapUsage> for MapUsage {
+    fn from(usage: beryl_ebpf::MapUsage) -> Self {
+        Self {
+            name: usage.name,
+            entries: usage.entries,
+            max_entries: usage.max_entries,
+        }
+    }
+}
+
+/// Total bytes seen by the datapath in each direction.
+#[derive(serde::Serialize)]
+pub struct ByteStats {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+/// Match counters for a single rule or blocklist entry.
+#[derive(serde::Serialize)]
+pub struct RuleHit {
+    /// The entry as configured: an address/prefix or a port number
+    pub rule: String,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl RuleHit {
+    pub fn new(rule: String, entry: &RuleEntry) -> Self {
+        Self {
+            rule,
+            packets: entry.packets,
+            bytes: entry.bytes,
+        }
+    }
+}
+
+/// Per-entry drop counters for each blocklist, busiest entries first.
+#[derive(serde::Serialize, Default)]
+pub struct FirewallHits {
+    /// Ordered 5-tuple rules (allows and drops)
+    pub rules: Vec<RuleHit>,
+    pub ingress_ips: Vec<RuleHit>,
+    pub ports: Vec<RuleHit>,
+    pub egress_ips: Vec<RuleHit>,
 }
 
 pub fn app(state: AppState) -> Router {
     Router::new()
         .route("/api/v1/status", get(status_handler))
         .route("/api/v1/stats", get(stats_handler))
+        .route("/api/v1/firewall/hits", get(hits_handler))
+        .route("/api/v1/firewall/events", get(events_handler))
+        .route(
+            "/api/v1/firewall/blocklist",
+            get(list_blocks_handler).post(add_block_handler),
+        )
+        .route(
+            "/api/v1/firewall/blocklist/:ip",
+            delete(remove_block_handler),
+        )
+        .route("/api/v1/capture", get(capture_handler))
+        .route("/api/v1/datapath/upgrade", post(upgrade_handler))
         .route("/api/v1/config", get(get_config).put(put_config))
         .layer(TraceLayer::new_for_http())
         .with_state(state)
@@ -56,7 +138,107 @@ async fn status_handler() -> Json<StatusResponse> {
 async fn stats_handler(State(state): State<AppState>) -> Json<StatsResponse> {
     let router = state.router.read().await;
     let stats = router.get_stats().unwrap_or_default();
-    Json(StatsResponse { packets: stats })
+    let hits = router.get_rule_hits().unwrap_or_default();
+    let maps = router.get_map_usage();
+    Json(StatsResponse {
+        packets: stats,
+        bytes: ByteStats {
+            rx: stats.ingress.bytes_total,
+            tx: stats.egress.bytes_total,
+        },
+        hits,
+        maps,
+    })
+}
+
+async fn hits_handler(State(state): State<AppState>) -> Json<FirewallHits> {
+    let router = state.router.read().await;
+    Json(router.get_rule_hits().unwrap_or_default())
+}
+
+async fn events_handler(State(state): State<AppState>) -> Json<Vec<FirewallEvent>> {
+    let router = state.router.read().await;
+    Json(router.recent_events())
+}
+
+async fn list_blocks_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<TimedBlock>>, (StatusCode, String)> {
+    let router = state.router.read().await;
+    router
+        .timed_blocks()
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Adds a temporary block (or extends an existing one).
+async fn add_block_handler(
+    State(state): State<AppState>,
+    Json(request): Json<BlockRequest>,
+) -> Response {
+    match state.router.write().await.add_timed_block(&request) {
+        Ok(block) => (StatusCode::CREATED, Json(block)).into_response(),
+        Err(e) => block_error(e),
+    }
+}
+
+/// Lifts a temporary block; `ip` is the blocked address or URL-encoded CIDR.
+async fn remove_block_handler(State(state): State<AppState>, Path(ip): Path<String>) -> Response {
+    match state.router.write().await.remove_timed_block(&ip) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => block_error(e),
+    }
+}
+
+fn block_error(e: BlockError) -> Response {
+    let status = match e {
+        BlockError::Invalid(_) => StatusCode::BAD_REQUEST,
+        BlockError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, e.to_string()).into_response()
+}
+
+/// Streams packets matching the filter as a pcap file until the session limits
+/// are reached or the client disconnects.
+async fn capture_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<CaptureFilter>,
+    Query(limits): Query<CaptureLimits>,
+) -> Response {
+    let packets = match state.router.write().await.start_capture(&filter) {
+        Ok(packets) => packets,
+        Err(e) => {
+            let status = match e {
+                CaptureError::Busy => StatusCode::CONFLICT,
+                CaptureError::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+                CaptureError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            return (status, e.to_string()).into_response();
+        }
+    };
+
+    let snaplen = filter.snaplen.unwrap_or(CAPTURE_SNAPLEN_MAX as u32);
+    let stream = capture::pcap_stream(packets, snaplen, limits, state.router.clone());
+    (
+        [
+            (header::CONTENT_TYPE, "application/vnd.tcpdump.pcap"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"capture.pcap\"",
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+/// Reloads the eBPF object and swaps the attached programs in place.
+async fn upgrade_handler(State(state): State<AppState>) -> Response {
+    match state.router.write().await.upgrade_datapath() {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")).into_response(),
+    }
 }
 
 async fn get_config(State(state): State<AppState>) -> Json<Option<Config>> {
@@ -70,6 +252,15 @@ async fn put_config(State(state): State<AppState>, Json(config): Json<Config>) -
     if let Err(e) = router.apply_firewall_config(&config.firewall) {
         tracing::error!("Failed to apply firewall config: {}", e);
     }
+    if let Err(e) = router.apply_interface_roles(&config.interfaces) {
+        tracing::error!("Failed to apply interface roles: {}", e);
+    }
+    if let Err(e) = router.apply_fast_path_interfaces(&config.interfaces) {
+        tracing::error!("Failed to apply fast-path interfaces: {}", e);
+    }
+    if let Err(e) = router.apply_anti_spoof(&config.firewall.anti_spoof, &config.interfaces) {
+        tracing::error!("Failed to apply anti-spoofing: {}", e);
+    }
     if let Err(e) = router.apply_dhcp_config(&config.dhcp).await {
         tracing::error!("Failed to apply DHCP config: {}", e);
     }
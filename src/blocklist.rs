@@ -0,0 +1,94 @@
+//! Temporary blocklist entries.
+//!
+//! Time-limited blocks share the `BLOCKLIST`/`BLOCKLIST_V6` maps with the
+//! configured ones and are marked by a non-zero `RuleEntry::expires_ns`. They
+//! survive config reloads; a sweeper task removes them once they expire and
+//! records the removal in the event log. Sources the XDP program detects as
+//! port scanning are blocked the same way for `firewall.port_scan.block_secs`.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::{RwLock, mpsc},
+    time::interval,
+};
+use tracing::error;
+
+use crate::Router;
+
+/// How often expired blocks are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Port-scan sources waiting to be blocked
+const SCAN_QUEUE: usize = 64;
+
+/// Longest block the API accepts (30 days)
+pub const MAX_BLOCK_SECS: u64 = 30 * 24 * 3600;
+
+/// Body of `POST /api/v1/firewall/blocklist`.
+#[derive(Debug, Deserialize)]
+pub struct BlockRequest {
+    /// Source address or CIDR prefix
+    pub ip: String,
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// A temporary block as listed by the API.
+#[derive(Clone, Debug, Serialize)]
+pub struct TimedBlock {
+    /// Blocked source prefix in CIDR notation
+    pub prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Unix time in milliseconds at which the block expires
+    pub expires_ms: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Why a temporary block could not be added.
+#[derive(Debug)]
+pub enum BlockError {
+    /// The request was rejected
+    Invalid(String),
+    /// The blocklist maps could not be updated
+    Unavailable(anyhow::Error),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::Invalid(e) => write!(f, "invalid block: {e}"),
+            BlockError::Unavailable(e) => write!(f, "blocklist unavailable: {e}"),
+        }
+    }
+}
+
+/// Spawns the task that removes expired blocks.
+pub fn spawn_sweeper(router: Arc<RwLock<Router>>) {
+    tokio::spawn(async move {
+        let mut sweep = interval(SWEEP_INTERVAL);
+        loop {
+            sweep.tick().await;
+            if let Err(e) = router.write().await.sweep_expired_blocks() {
+                error!("Failed to sweep expired blocks: {}", e);
+            }
+        }
+    });
+}
+
+/// Spawns the task that blocks sources detected as port scanning and returns
+/// the sender the drop event reader reports them to.
+pub fn spawn_scan_blocker(router: Arc<RwLock<Router>>) -> mpsc::Sender<IpAddr> {
+    let (tx, mut rx) = mpsc::channel(SCAN_QUEUE);
+    tokio::spawn(async move {
+        while let Some(src) = rx.recv().await {
+            if let Err(e) = router.write().await.block_scanner(src) {
+                error!("Failed to block port-scan source {}: {}", src, e);
+            }
+        }
+    });
+    tx
+}
@@ -0,0 +1,293 @@
+//! On-demand packet capture.
+//!
+//! While a capture is running, the eBPF programs copy the start of every
+//! matching packet into the `CAPTURE` ring buffer. A reader task fans the
+//! packets out to the capture session, which streams them to the API client
+//! as a pcap file. Only one capture runs at a time.
+
+use anyhow::Result;
+use axum::body::Bytes;
+use aya::maps::{Map, MapData, RingBuf};
+use beryl_common::{CAPTURE_SNAPLEN_MAX, CaptureRecord};
+use futures_util::Stream;
+use std::{convert::Infallible, fmt, sync::Arc, time::Duration};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{RwLock, broadcast, mpsc},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use crate::{Router, events::WallClock};
+
+/// Packets captured when the request sets no `count`
+const DEFAULT_PACKETS: u64 = 1000;
+const MAX_PACKETS: u64 = 100_000;
+
+/// Capture length when the request sets no `duration_secs`
+const DEFAULT_DURATION_SECS: u64 = 30;
+const MAX_DURATION_SECS: u64 = 600;
+
+/// Packets buffered between the ring buffer reader and the session
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// pcap file magic (microsecond timestamps) and Ethernet link type
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Session limits, given alongside the `CaptureFilter` query parameters.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct CaptureLimits {
+    /// Stop after this many packets
+    #[serde(default)]
+    pub count: Option<u64>,
+    /// Stop after this many seconds
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+/// A packet read from the `CAPTURE` ring buffer.
+#[derive(Clone, Debug)]
+pub struct CapturedPacket {
+    pub timestamp_ns: u64,
+    pub orig_len: u32,
+    pub data: Bytes,
+}
+
+impl From<&CaptureRecord> for CapturedPacket {
+    fn from(record: &CaptureRecord) -> Self {
+        let cap_len = (record.cap_len as usize).min(CAPTURE_SNAPLEN_MAX);
+        Self {
+            timestamp_ns: record.timestamp_ns,
+            orig_len: record.orig_len,
+            data: Bytes::copy_from_slice(&record.data[..cap_len]),
+        }
+    }
+}
+
+/// Why a capture could not be started.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// Another capture is already running
+    Busy,
+    /// The filter was rejected
+    InvalidFilter(String),
+    /// The capture maps could not be updated
+    Unavailable(anyhow::Error),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Busy => write!(f, "a capture is already running"),
+            CaptureError::InvalidFilter(e) => write!(f, "invalid capture filter: {e}"),
+            CaptureError::Unavailable(e) => write!(f, "capture unavailable: {e}"),
+        }
+    }
+}
+
+/// Capture state owned by the router.
+pub struct Capture {
+    packets: broadcast::Sender<CapturedPacket>,
+    pub active: bool,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            packets: broadcast::channel(CHANNEL_CAPACITY).0,
+            active: false,
+        }
+    }
+}
+
+impl Capture {
+    pub fn subscribe(&self) -> broadcast::Receiver<CapturedPacket> {
+        self.packets.subscribe()
+    }
+}
+
+/// Spawns the task that drains the `CAPTURE` ring buffer into `capture`.
+pub fn spawn_capture_reader(map: Map, capture: &Capture) -> Result<()> {
+    let ring: RingBuf<MapData> = RingBuf::try_from(map)?;
+    let mut ring = AsyncFd::new(ring)?;
+    let packets = capture.packets.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let mut guard = match ring.readable_mut().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    error!("Capture ring buffer failed: {}", e);
+                    return;
+                }
+            };
+            let ring = guard.get_inner_mut();
+            while let Some(item) = ring.next() {
+                if item.len() < size_of::<CaptureRecord>() {
+                    continue;
+                }
+                let record: CaptureRecord =
+                    unsafe { std::ptr::read_unaligned(item.as_ptr().cast()) };
+                // No receivers just means no session is listening any more
+                let _ = packets.send(CapturedPacket::from(&record));
+            }
+            guard.clear_ready();
+        }
+    });
+
+    Ok(())
+}
+
+/// Streams a capture session as a pcap file.
+///
+/// The session ends when the packet or time limit is reached or the client
+/// disconnects; the capture is then disabled in the datapath.
+pub fn pcap_stream(
+    mut packets: broadcast::Receiver<CapturedPacket>,
+    snaplen: u32,
+    limits: CaptureLimits,
+    router: Arc<RwLock<Router>>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let count = limits.count.unwrap_or(DEFAULT_PACKETS).min(MAX_PACKETS);
+    let duration = Duration::from_secs(
+        limits
+            .duration_secs
+            .unwrap_or(DEFAULT_DURATION_SECS)
+            .min(MAX_DURATION_SECS),
+    );
+    let (tx, mut rx) = mpsc::channel::<Bytes>(64);
+
+    tokio::spawn(async move {
+        let _guard = StopOnDrop(router);
+        info!(count, ?duration, "Packet capture started");
+
+        if tx.send(pcap_header(snaplen)).await.is_err() {
+            return;
+        }
+
+        let clock = WallClock::now();
+        let deadline = sleep(duration);
+        tokio::pin!(deadline);
+        let mut sent = 0;
+        while sent < count {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = tx.closed() => break,
+                packet = packets.recv() => match packet {
+                    Ok(packet) => {
+                        if tx.send(pcap_record(&clock, &packet)).await.is_err() {
+                            break;
+                        }
+                        sent += 1;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Capture client fell behind, packets skipped");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+
+        info!(packets = sent, "Packet capture finished");
+    });
+
+    futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx).map(|bytes| bytes.map(Ok)))
+}
+
+/// Disables the capture in the datapath when the session task ends.
+struct StopOnDrop(Arc<RwLock<Router>>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        let router = self.0.clone();
+        tokio::spawn(async move {
+            if let Err(e) = router.write().await.stop_capture() {
+                error!("Failed to stop packet capture: {}", e);
+            }
+        });
+    }
+}
+
+/// pcap global header (little-endian, microsecond timestamps).
+fn pcap_header(snaplen: u32) -> Bytes {
+    let mut buf = Vec::with_capacity(24);
+    buf.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    buf.extend_from_slice(&2u16.to_le_bytes()); // version major
+    buf.extend_from_slice(&4u16.to_le_bytes()); // version minor
+    buf.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+    buf.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+    buf.extend_from_slice(&snaplen.to_le_bytes());
+    buf.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    buf.into()
+}
+
+/// pcap record header followed by the captured bytes.
+fn pcap_record(clock: &WallClock, packet: &CapturedPacket) -> Bytes {
+    let time = clock.to_unix(packet.timestamp_ns);
+    let mut buf = Vec::with_capacity(16 + packet.data.len());
+    buf.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    buf.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    buf.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&packet.orig_len.to_le_bytes());
+    buf.extend_from_slice(&packet.data);
+    buf.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cap_len: u32, orig_len: u32) -> CaptureRecord {
+        CaptureRecord {
+            timestamp_ns: 9_750_000_000,
+            orig_len,
+            cap_len,
+            data: std::array::from_fn(|i| i as u8),
+        }
+    }
+
+    #[test]
+    fn global_header_is_little_endian_ethernet() {
+        let header = pcap_header(96);
+        assert_eq!(
+            &header[..],
+            [
+                0xd4, 0xc3, 0xb2, 0xa1, // magic
+                2, 0, 4, 0, // version 2.4
+                0, 0, 0, 0, // thiszone
+                0, 0, 0, 0, // sigfigs
+                96, 0, 0, 0, // snaplen
+                1, 0, 0, 0, // LINKTYPE_ETHERNET
+            ]
+        );
+    }
+
+    #[test]
+    fn record_carries_wall_clock_time_and_captured_bytes() {
+        // The packet was captured 250 ms before the clock was read
+        let clock = WallClock::at(Duration::new(1_700_000_000, 500_000_000), 10_000_000_000);
+        let packet = CapturedPacket::from(&record(4, 1514));
+        let bytes = pcap_record(&clock, &packet);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1_700_000_000u32.to_le_bytes()); // ts_sec
+        expected.extend_from_slice(&250_000u32.to_le_bytes()); // ts_usec
+        expected.extend_from_slice(&4u32.to_le_bytes()); // incl_len
+        expected.extend_from_slice(&1514u32.to_le_bytes()); // orig_len
+        expected.extend_from_slice(&[0, 1, 2, 3]);
+        assert_eq!(&bytes[..], expected);
+    }
+
+    #[test]
+    fn records_are_truncated_to_the_snaplen() {
+        let packet = CapturedPacket::from(&record(u32::MAX, 9000));
+        assert_eq!(packet.data.len(), CAPTURE_SNAPLEN_MAX);
+        assert_eq!(packet.orig_len, 9000);
+
+        let clock = WallClock::at(Duration::from_secs(1_700_000_000), 10_000_000_000);
+        let bytes = pcap_record(&clock, &packet);
+        assert_eq!(bytes.len(), 16 + CAPTURE_SNAPLEN_MAX);
+        assert_eq!(bytes[8..12], (CAPTURE_SNAPLEN_MAX as u32).to_le_bytes());
+    }
+}
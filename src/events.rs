@@ -0,0 +1,410 @@
+//! Firewall event log.
+//!
+//! Drop events arrive from the eBPF programs through the `EVENTS` ring buffer.
+//! They are grouped by reason and 5-tuple over a short window so that a flood
+//! produces a handful of log lines instead of one per packet, and the groups
+//! are kept in a bounded buffer of recent events served by the API, together
+//! with temporary blocklist changes made by the daemon. Sources dropped for
+//! port scanning are also handed to the daemon so it can block them.
+
+use anyhow::Result;
+use aya::maps::{Map, MapData, RingBuf};
+use beryl_common::{Addr128, DropEvent, DropReason};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::unix::AsyncFd, sync::mpsc, time::interval};
+use tracing::{error, info, warn};
+
+/// Number of events kept for the API
+const RECENT_EVENTS: usize = 1024;
+
+/// How often pending drop groups are logged and moved into the event log
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drop groups logged per flush; the rest are only counted
+const MAX_LOGGED_PER_FLUSH: usize = 10;
+
+/// Distinct drop groups tracked per flush window before events are only counted
+const MAX_PENDING_GROUPS: usize = 4096;
+
+pub type SharedEventLog = Arc<Mutex<EventLog>>;
+
+/// An entry in the firewall event log.
+#[derive(Clone, Debug, Serialize)]
+pub struct FirewallEvent {
+    /// Unix time in milliseconds
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Packets dropped by the datapath within one flush window
+    Drop(DropSummary),
+    /// A temporary blocklist entry was added
+    BlockAdded(BlockSummary),
+    /// A temporary blocklist entry expired and was removed
+    BlockExpired(BlockSummary),
+}
+
+/// Dropped packets sharing a reason and 5-tuple.
+#[derive(Clone, Debug, Serialize)]
+pub struct DropSummary {
+    pub reason: DropReason,
+    /// Config index of the matching rule, for rule and port blocklist drops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<u32>,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub packets: u64,
+    /// Unix time in milliseconds of the last packet in the group
+    pub last_ms: u64,
+}
+
+/// A temporary blocklist entry.
+#[derive(Clone, Debug, Serialize)]
+pub struct BlockSummary {
+    /// Blocked source prefix in CIDR notation
+    pub prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Unix time in milliseconds at which the block expires
+    pub expires_ms: u64,
+}
+
+/// Bounded buffer of recent firewall events, oldest first.
+#[derive(Default)]
+pub struct EventLog {
+    events: VecDeque<FirewallEvent>,
+}
+
+impl EventLog {
+    /// Records an event that happened now.
+    pub fn record(&mut self, kind: EventKind) {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.push(FirewallEvent { time_ms, kind });
+    }
+
+    pub fn push(&mut self, event: FirewallEvent) {
+        if self.events.len() == RECENT_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Recent events, newest first.
+    pub fn recent(&self) -> Vec<FirewallEvent> {
+        self.events.iter().rev().cloned().collect()
+    }
+}
+
+/// Grouping key for drop events within a flush window.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct DropKey {
+    reason: DropReason,
+    rule: u32,
+    src: Addr128,
+    dst: Addr128,
+    proto: u8,
+    src_port: u16,
+    dst_port: u16,
+    is_ipv6: bool,
+}
+
+struct DropGroup {
+    packets: u64,
+    first_ns: u64,
+    last_ns: u64,
+}
+
+/// Spawns the task that drains the `EVENTS` ring buffer into `log`, sending
+/// the source of each port-scan drop to `scans` once per flush window.
+pub fn spawn_drop_reader(map: Map, log: SharedEventLog, scans: mpsc::Sender<IpAddr>) -> Result<()> {
+    let ring: RingBuf<MapData> = RingBuf::try_from(map)?;
+    let mut ring = AsyncFd::new(ring)?;
+
+    tokio::spawn(async move {
+        let mut pending: HashMap<DropKey, DropGroup> = HashMap::new();
+        let mut scanners: HashSet<IpAddr> = HashSet::new();
+        let mut overflow = 0u64;
+        let mut flush = interval(FLUSH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                guard = ring.readable_mut() => {
+                    let mut guard = match guard {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            error!("Drop event ring buffer failed: {}", e);
+                            return;
+                        }
+                    };
+                    let ring = guard.get_inner_mut();
+                    while let Some(item) = ring.next() {
+                        if item.len() < size_of::<DropEvent>() {
+                            continue;
+                        }
+                        let event: DropEvent =
+                            unsafe { std::ptr::read_unaligned(item.as_ptr().cast()) };
+                        if !aggregate(&mut pending, &event) {
+                            overflow += 1;
+                        }
+                        if DropReason::from(event.reason) == DropReason::PortScan {
+                            let src = to_ip(&event.src, event.is_ipv6 != 0);
+                            if scanners.insert(src) && scans.try_send(src).is_err() {
+                                warn!(%src, "Port-scan queue full, source not blocked");
+                            }
+                        }
+                    }
+                    guard.clear_ready();
+                }
+                _ = flush.tick() => {
+                    flush_pending(&mut pending, overflow, &log);
+                    scanners.clear();
+                    overflow = 0;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Adds an event to its group. Returns `false` if the event could not be
+/// grouped because too many groups are pending.
+fn aggregate(pending: &mut HashMap<DropKey, DropGroup>, event: &DropEvent) -> bool {
+    let key = DropKey {
+        reason: DropReason::from(event.reason),
+        rule: event.rule,
+        src: event.src,
+        dst: event.dst,
+        proto: event.proto,
+        src_port: event.src_port,
+        dst_port: event.dst_port,
+        is_ipv6: event.is_ipv6 != 0,
+    };
+
+    if let Some(group) = pending.get_mut(&key) {
+        group.packets += 1;
+        group.last_ns = group.last_ns.max(event.timestamp_ns);
+        return true;
+    }
+    if pending.len() >= MAX_PENDING_GROUPS {
+        return false;
+    }
+    pending.insert(
+        key,
+        DropGroup {
+            packets: 1,
+            first_ns: event.timestamp_ns,
+            last_ns: event.timestamp_ns,
+        },
+    );
+    true
+}
+
+/// Moves pending groups into the event log, logging the busiest ones.
+fn flush_pending(pending: &mut HashMap<DropKey, DropGroup>, overflow: u64, log: &SharedEventLog) {
+    if pending.is_empty() && overflow == 0 {
+        return;
+    }
+
+    let clock = WallClock::now();
+    let mut groups: Vec<_> = pending.drain().collect();
+    groups.sort_by_key(|(_, group)| std::cmp::Reverse(group.packets));
+
+    for (key, group) in groups.iter().take(MAX_LOGGED_PER_FLUSH) {
+        info!(
+            reason = ?key.reason,
+            src = %to_ip(&key.src, key.is_ipv6),
+            dst = %to_ip(&key.dst, key.is_ipv6),
+            proto = key.proto,
+            src_port = key.src_port,
+            dst_port = key.dst_port,
+            packets = group.packets,
+            "Dropped packets"
+        );
+    }
+    if groups.len() > MAX_LOGGED_PER_FLUSH || overflow > 0 {
+        let suppressed = groups.len().saturating_sub(MAX_LOGGED_PER_FLUSH);
+        warn!(
+            groups = suppressed,
+            ungrouped_packets = overflow,
+            "Suppressed drop event log lines"
+        );
+    }
+
+    groups.sort_by_key(|(_, group)| group.first_ns);
+    let mut log = log.lock().unwrap();
+    for (key, group) in groups {
+        let rule =
+            matches!(key.reason, DropReason::Rule | DropReason::PortBlocklist).then_some(key.rule);
+        log.push(FirewallEvent {
+            time_ms: clock.to_unix_ms(group.first_ns),
+            kind: EventKind::Drop(DropSummary {
+                reason: key.reason,
+                rule,
+                src: to_ip(&key.src, key.is_ipv6),
+                dst: to_ip(&key.dst, key.is_ipv6),
+                proto: key.proto,
+                src_port: key.src_port,
+                dst_port: key.dst_port,
+                packets: group.packets,
+                last_ms: clock.to_unix_ms(group.last_ns),
+            }),
+        });
+    }
+}
+
+/// Converts `bpf_ktime_get_ns` timestamps (CLOCK_MONOTONIC) to Unix time.
+pub(crate) struct WallClock {
+    unix: Duration,
+    monotonic_ns: u64,
+}
+
+impl WallClock {
+    pub(crate) fn now() -> Self {
+        Self {
+            unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            monotonic_ns: monotonic_ns(),
+        }
+    }
+
+    /// A clock that read `unix` at monotonic time `monotonic_ns`.
+    #[cfg(test)]
+    pub(crate) fn at(unix: Duration, monotonic_ns: u64) -> Self {
+        Self { unix, monotonic_ns }
+    }
+
+    /// Unix time of a monotonic timestamp, as a duration since the epoch.
+    pub(crate) fn to_unix(&self, monotonic_ns: u64) -> Duration {
+        let age = Duration::from_nanos(self.monotonic_ns.saturating_sub(monotonic_ns));
+        self.unix.saturating_sub(age)
+    }
+
+    pub(crate) fn to_unix_ms(&self, monotonic_ns: u64) -> u64 {
+        self.to_unix(monotonic_ns).as_millis() as u64
+    }
+}
+
+/// Current CLOCK_MONOTONIC time, the clock behind `bpf_ktime_get_ns`.
+pub(crate) fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn to_ip(addr: &Addr128, is_ipv6: bool) -> IpAddr {
+    if is_ipv6 {
+        // Words are in network byte order, so their in-memory bytes are the address
+        let bytes: [u8; 16] = std::array::from_fn(|i| addr[i / 4].to_ne_bytes()[i % 4]);
+        IpAddr::V6(Ipv6Addr::from(bytes))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(u32::from_be(addr[3])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drop_event(reason: DropReason, rule: u32, src: Ipv4Addr, dst_port: u16) -> DropEvent {
+        DropEvent {
+            timestamp_ns: monotonic_ns(),
+            src: [0, 0, 0, u32::from(src).to_be()],
+            dst: [0, 0, 0, u32::from(Ipv4Addr::new(192, 168, 8, 1)).to_be()],
+            src_port: 40000,
+            dst_port,
+            proto: 6,
+            reason: reason as u32,
+            rule,
+            ..DropEvent::default()
+        }
+    }
+
+    fn drops(log: &SharedEventLog) -> Vec<DropSummary> {
+        log.lock()
+            .unwrap()
+            .recent()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::Drop(summary) => Some(summary),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_are_counted_per_reason_and_source() {
+        let scanner = Ipv4Addr::new(198, 51, 100, 7);
+        let blocked = Ipv4Addr::new(203, 0, 113, 9);
+        let mut events = vec![drop_event(DropReason::Rule, 3, scanner, 22); 3];
+        events.push(drop_event(DropReason::Rule, 3, scanner, 23));
+        events.extend([drop_event(DropReason::Blocklist, 0, blocked, 22); 2]);
+
+        let mut pending = HashMap::new();
+        for event in &events {
+            assert!(aggregate(&mut pending, event));
+        }
+        assert_eq!(pending.len(), 3);
+
+        let log = SharedEventLog::default();
+        flush_pending(&mut pending, 0, &log);
+        assert!(pending.is_empty());
+
+        let drops = drops(&log);
+        let count = |matches: &dyn Fn(&DropSummary) -> bool| -> u64 {
+            drops.iter().filter(|d| matches(d)).map(|d| d.packets).sum()
+        };
+        assert_eq!(count(&|d| d.reason == DropReason::Rule), 4);
+        assert_eq!(count(&|d| d.reason == DropReason::Blocklist), 2);
+        assert_eq!(count(&|d| d.src == IpAddr::V4(scanner)), 4);
+        assert_eq!(count(&|d| d.src == IpAddr::V4(blocked)), 2);
+        assert_eq!(count(&|d| d.dst_port == 22), 5);
+
+        // Only rule and port blocklist drops name a rule
+        for drop in &drops {
+            let rule = (drop.reason == DropReason::Rule).then_some(3);
+            assert_eq!(drop.rule, rule);
+        }
+    }
+
+    #[test]
+    fn events_past_the_group_limit_are_not_grouped() {
+        let mut pending = HashMap::new();
+        let src = Ipv4Addr::new(198, 51, 100, 7);
+        for port in 0..MAX_PENDING_GROUPS as u16 {
+            assert!(aggregate(
+                &mut pending,
+                &drop_event(DropReason::PortScan, 0, src, port)
+            ));
+        }
+
+        let extra = drop_event(DropReason::PortScan, 0, src, u16::MAX);
+        assert!(!aggregate(&mut pending, &extra));
+        // Events of an existing group are still counted
+        assert!(aggregate(
+            &mut pending,
+            &drop_event(DropReason::PortScan, 0, src, 0)
+        ));
+        assert_eq!(pending.len(), MAX_PENDING_GROUPS);
+    }
+}
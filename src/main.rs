@@ -3,34 +3,53 @@
 //! This daemon loads the XDP eBPF program and manages firewall rules
 //! via configuration file watching.
 
-use anyhow::{Context, Result};
-use aya::maps::{HashMap, PerCpuArray};
-use beryl_common::{FirewallConfig, PacketAction, Stats};
-use beryl_config::Config;
-use beryl_dhcp::{Client as DhcpClient, ClientConfig, Server as DhcpServer, database::LeaseDatabase};
+use anyhow::{Context, Result, bail};
+use beryl_common::{
+    AntiSpoofConfig, AntiSpoofParams, CaptureFilter, CaptureParams, FirewallConfig, IPPROTO_TCP,
+    IPPROTO_UDP, InterfaceRole, MAX_FILTER_RULES, PacketAction, PortProtocol, PortRange, RuleEntry,
+    Stats, parse_cidr,
+};
+use beryl_config::{Config, InterfacesConfig};
+use beryl_dhcp::{Client as DhcpClient, Server as DhcpServer, database::LeaseDatabase};
 use beryl_dns::DnsServer;
-use beryl_ebpf::BerylEbpf;
+use beryl_ebpf::{BerylEbpf, Blocklist, LoadOptions, MapSizes, PortPrefix, Prefix};
 use beryl_wifi::apply_wifi_config;
+use blocklist::{BlockError, BlockRequest, MAX_BLOCK_SECS, TimedBlock};
+use capture::{Capture, CaptureError, CapturedPacket};
 use clap::Parser;
+use events::{BlockSummary, FirewallEvent, SharedEventLog, WallClock};
 use notify::{EventKind, RecursiveMode, Watcher};
-use std::{net::Ipv4Addr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
-    sync::{RwLock, mpsc},
+    sync::{RwLock, broadcast, mpsc},
     task::JoinHandle,
     time::interval,
 };
-use tracing::{Level, debug, error, info};
+use tracing::{Level, debug, error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
 mod actuator;
 mod api;
+mod blocklist;
+mod capture;
+mod events;
+
+/// How often the map fill levels reported by the API are recounted
+const MAP_USAGE_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Parser)]
 #[command(name = "beryl-routerd", about = "XDP/eBPF Firewall for Beryl AX")]
 pub struct Args {
-    /// Network interface to attach XDP program to (LAN/WAN ingress)
-    #[arg(short, long, default_value = "eth0")]
-    pub interface: String,
+    /// Attach to this interface only, instead of every interface in the
+    /// `[interfaces]` config
+    #[arg(short, long)]
+    pub interface: Option<String>,
 
     /// Configuration file path
     #[arg(short, long, default_value = "/etc/beryl/config.toml")]
@@ -47,6 +66,15 @@ pub struct Args {
     /// API server bind address
     #[arg(long, default_value = "0.0.0.0:8080")]
     pub api_bind: String,
+
+    /// Discard pinned eBPF maps and links instead of reusing them
+    #[arg(long)]
+    pub reset_maps: bool,
+
+    /// Load the eBPF object from this file instead of the built-in one; the
+    /// file is read again on each datapath upgrade
+    #[arg(long)]
+    pub ebpf_object: Option<PathBuf>,
 }
 
 pub struct Router {
@@ -58,18 +86,64 @@ pub struct Router {
     current_config: Option<Config>,
     // Shared state between DHCP and DNS
     lease_db: Option<Arc<RwLock<LeaseDatabase>>>,
+    // Labels of the ordered firewall rules, indexed by config position
+    rule_labels: Vec<String>,
+    // Recent drop events and other firewall events, shared with the reader task
+    events: SharedEventLog,
+    // On-demand packet capture
+    capture: Capture,
+    // Reasons given for temporary blocks, by prefix
+    block_reasons: BTreeMap<String, String>,
+    // Fill levels of the size-limited maps, refreshed by a background task
+    map_usage: Arc<Mutex<Vec<api::MapUsage>>>,
 }
 
 impl Router {
     pub fn new(args: &Args) -> Result<Self> {
-        let mut ebpf = BerylEbpf::load()?;
+        // Map sizes and attached interfaces are fixed at load time, so read
+        // them from the config up front
+        let config = if args.config.exists() {
+            beryl_config::load_config(&args.config)
+                .map_err(|e| warn!("Failed to read config for map sizes and interfaces: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let mut ebpf = BerylEbpf::load(&LoadOptions {
+            map_sizes: map_sizes(config.as_ref()),
+            reset_maps: args.reset_maps,
+            object: args.ebpf_object.clone(),
+        })?;
 
-        // Attach XDP (Ingress)
-        ebpf.attach_xdp(&args.interface, args.skb_mode)?;
+        let interfaces: Vec<_> = match (&args.interface, &config) {
+            (Some(name), _) => vec![(None, name.as_str())],
+            (None, Some(config)) => config
+                .interfaces
+                .roles()
+                .map(|(role, iface)| (Some(role), iface.name.as_str()))
+                .collect(),
+            (None, None) => {
+                bail!("No interfaces to attach to: pass --interface or configure [interfaces]")
+            }
+        };
 
-        // Attach TC (Egress)
-        if let Err(e) = ebpf.attach_tc_egress(&args.interface) {
-            error!("Failed to attach TC egress: {}", e);
+        for (role, iface) in interfaces {
+            // Attach XDP (Ingress); only the WAN firewall is essential
+            if let Err(e) = ebpf.attach_xdp(iface, args.skb_mode) {
+                if matches!(role, None | Some(InterfaceRole::Wan)) {
+                    return Err(e);
+                }
+                error!(iface, ?role, "Failed to attach XDP: {}", e);
+                continue;
+            }
+
+            // Attach TC (Egress)
+            if let Err(e) = ebpf.attach_tc_egress(iface) {
+                error!(iface, "Failed to attach TC egress: {}", e);
+            }
+        }
+        if let Err(e) = ebpf.release_stale_links() {
+            warn!("Failed to release links of unused interfaces: {}", e);
         }
 
         Ok(Self {
@@ -80,6 +154,11 @@ impl Router {
             dns_handle: None,
             current_config: None,
             lease_db: None,
+            rule_labels: Vec::new(),
+            events: SharedEventLog::default(),
+            capture: Capture::default(),
+            block_reasons: BTreeMap::new(),
+            map_usage: Arc::default(),
         })
     }
 
@@ -92,6 +171,9 @@ impl Router {
         };
 
         self.apply_firewall_config(&config.firewall)?;
+        self.apply_interface_roles(&config.interfaces)?;
+        self.apply_fast_path_interfaces(&config.interfaces)?;
+        self.apply_anti_spoof(&config.firewall.anti_spoof, &config.interfaces)?;
         self.apply_dhcp_config(&config.dhcp).await?;
         self.apply_dns_config(&config.dns).await?;
         self.apply_wifi_config(&config.wifi).await?;
@@ -108,71 +190,503 @@ impl Router {
         Ok(())
     }
 
-    pub fn get_current_config(&self) -> Option<Config> {
-        self.current_config.clone()
+    /// Starts consuming drop events from the eBPF ring buffer, reporting
+    /// port-scan sources to `scans`.
+    pub fn spawn_event_reader(&mut self, scans: mpsc::Sender<IpAddr>) -> Result<()> {
+        let map = self
+            .ebpf
+            .take_map("EVENTS")
+            .context("EVENTS ring buffer not found")?;
+        events::spawn_drop_reader(map, self.events.clone(), scans)
     }
 
-    pub fn apply_firewall_config(&mut self, config: &FirewallConfig) -> Result<()> {
-        // Update IP blocklist (XDP Ingress)
-        if let Some(map) = self.ebpf.get_map_mut("BLOCKLIST") {
-            let mut blocklist: HashMap<_, u32, u32> = HashMap::try_from(map)?;
-
-            // Clear existing entries
-            let keys: Vec<u32> = blocklist.keys().filter_map(|k| k.ok()).collect();
-            for key in keys {
-                let _ = blocklist.remove(&key);
-            }
+    /// Starts consuming captured packets from the eBPF ring buffer.
+    pub fn spawn_capture_reader(&mut self) -> Result<()> {
+        let map = self
+            .ebpf
+            .take_map("CAPTURE")
+            .context("CAPTURE ring buffer not found")?;
+        capture::spawn_capture_reader(map, &self.capture)
+    }
 
-            // Add new blocked IPs
-            for ip_str in &config.blocked_ips {
-                if let Ok(ip) = ip_str.parse::<Ipv4Addr>() {
-                    let ip_u32 = u32::from(ip);
-                    blocklist.insert(ip_u32, PacketAction::Drop as u32, 0)?;
-                    debug!(ip = %ip_str, "Added IP to ingress blocklist");
-                }
+    /// Enables packet capture in the datapath and subscribes to its packets.
+    pub fn start_capture(
+        &mut self,
+        filter: &CaptureFilter,
+    ) -> Result<broadcast::Receiver<CapturedPacket>, CaptureError> {
+        if self.capture.active {
+            return Err(CaptureError::Busy);
+        }
+        let params = filter.to_params().map_err(CaptureError::InvalidFilter)?;
+
+        // Subscribe before enabling so no early packets are missed
+        let packets = self.capture.subscribe();
+        self.set_capture_params(params)
+            .map_err(CaptureError::Unavailable)?;
+        self.capture.active = true;
+        info!(?filter, "Packet capture enabled");
+        Ok(packets)
+    }
+
+    /// Disables packet capture in the datapath.
+    pub fn stop_capture(&mut self) -> Result<()> {
+        self.capture.active = false;
+        self.set_capture_params(CaptureParams::default())
+    }
+
+    fn set_capture_params(&mut self, params: CaptureParams) -> Result<()> {
+        Ok(self.ebpf.settings_mut().capture.set(params)?)
+    }
+
+    /// Replaces the attached eBPF programs with a freshly loaded object
+    /// without interrupting traffic; rules and flow state carry over in the
+    /// pinned maps, which are resized to the current `firewall.map_sizes`.
+    ///
+    /// Refused while a packet capture is running, since the capture filter
+    /// is not shared with the new programs.
+    pub fn upgrade_datapath(&mut self) -> Result<()> {
+        if self.capture.active {
+            bail!("a packet capture is running");
+        }
+        self.ebpf.reload(map_sizes(self.current_config.as_ref()))?;
+        info!("Datapath upgraded");
+        Ok(())
+    }
+
+    /// Recent firewall events, newest first.
+    pub fn recent_events(&self) -> Vec<FirewallEvent> {
+        self.events.lock().unwrap().recent()
+    }
+
+    /// Blocks a source prefix until the request's duration has passed,
+    /// extending any temporary block already in place for it.
+    pub fn add_timed_block(&mut self, request: &BlockRequest) -> Result<TimedBlock, BlockError> {
+        if !(1..=MAX_BLOCK_SECS).contains(&request.duration_secs) {
+            return Err(BlockError::Invalid(format!(
+                "duration_secs must be between 1 and {MAX_BLOCK_SECS}"
+            )));
+        }
+        let prefix = parse_prefix(&request.ip)
+            .ok_or_else(|| BlockError::Invalid(format!("invalid address: {}", request.ip)))?;
+        let expires_ns =
+            events::monotonic_ns() + Duration::from_secs(request.duration_secs).as_nanos() as u64;
+        let entry = RuleEntry::new(PacketAction::Drop).with_expiry(expires_ns);
+
+        let label = prefix.to_string();
+        let existing = self
+            .insert_timed(&prefix, entry)
+            .map_err(BlockError::Unavailable)?;
+        if existing.is_some_and(|existing| existing.expires_ns == 0) {
+            return Err(BlockError::Invalid(format!(
+                "{label} is already blocked by the configuration"
+            )));
+        }
+
+        match &request.reason {
+            Some(reason) => self.block_reasons.insert(label.clone(), reason.clone()),
+            None => self.block_reasons.remove(&label),
+        };
+        let expires_ms = WallClock::now().to_unix_ms(expires_ns);
+        info!(
+            prefix = %label,
+            duration_secs = request.duration_secs,
+            reason = request.reason.as_deref().unwrap_or(""),
+            "Added temporary block"
+        );
+        self.events
+            .lock()
+            .unwrap()
+            .record(events::EventKind::BlockAdded(BlockSummary {
+                prefix: label.clone(),
+                reason: request.reason.clone(),
+                expires_ms,
+            }));
+
+        let counters = existing.unwrap_or_default();
+        Ok(TimedBlock {
+            prefix: label,
+            reason: request.reason.clone(),
+            expires_ms,
+            packets: counters.packets,
+            bytes: counters.bytes,
+        })
+    }
+
+    /// Blocks a source the XDP program detected as port scanning for the
+    /// configured `port_scan.block_secs`.
+    pub fn block_scanner(&mut self, src: IpAddr) -> Result<()> {
+        let Some(config) = &self.current_config else {
+            return Ok(());
+        };
+        let port_scan = &config.firewall.port_scan;
+        if port_scan.block_secs == 0 {
+            return Ok(());
+        }
+
+        let request = BlockRequest {
+            ip: src.to_string(),
+            duration_secs: port_scan.block_secs.min(MAX_BLOCK_SECS),
+            reason: Some(format!(
+                "port scan: over {} ports in {}s",
+                port_scan.threshold, port_scan.window_secs
+            )),
+        };
+        match self.add_timed_block(&request) {
+            Ok(_) => Ok(()),
+            // Already blocked by the configuration
+            Err(BlockError::Invalid(e)) => {
+                debug!(%src, "Port-scan source not blocked: {}", e);
+                Ok(())
             }
+            Err(BlockError::Unavailable(e)) => Err(e),
         }
+    }
 
-        // Update Port blocklist (XDP Ingress)
-        if let Some(map) = self.ebpf.get_map_mut("PORT_BLOCKLIST") {
-            let mut port_blocklist: HashMap<_, u16, u32> = HashMap::try_from(map)?;
+    /// Removes a temporary block. Returns `false` if the prefix has none.
+    pub fn remove_timed_block(&mut self, ip: &str) -> Result<bool, BlockError> {
+        let prefix = parse_prefix(ip)
+            .ok_or_else(|| BlockError::Invalid(format!("invalid address: {ip}")))?;
+        let label = prefix.to_string();
+        let removed = self
+            .remove_timed(&prefix)
+            .map_err(BlockError::Unavailable)?;
+        if removed {
+            self.block_reasons.remove(&label);
+            info!(prefix = %label, "Removed temporary block");
+        }
+        Ok(removed)
+    }
 
-            let port_keys: Vec<u16> = port_blocklist.keys().filter_map(|k| k.ok()).collect();
-            for key in port_keys {
-                let _ = port_blocklist.remove(&key);
+    /// Lists the temporary blocks, soonest to expire first.
+    pub fn timed_blocks(&self) -> Result<Vec<TimedBlock>> {
+        let clock = WallClock::now();
+        let mut blocks: Vec<_> = self
+            .timed_entries()?
+            .into_iter()
+            .map(|(prefix, entry)| TimedBlock {
+                reason: self.block_reasons.get(&prefix.to_string()).cloned(),
+                prefix: prefix.to_string(),
+                expires_ms: clock.to_unix_ms(entry.expires_ns),
+                packets: entry.packets,
+                bytes: entry.bytes,
+            })
+            .collect();
+        blocks.sort_by_key(|block| block.expires_ms);
+        Ok(blocks)
+    }
+
+    /// Removes temporary blocks whose time is up and records each in the
+    /// event log.
+    pub fn sweep_expired_blocks(&mut self) -> Result<()> {
+        let now = events::monotonic_ns();
+        let expired = self.remove_expired(now)?;
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        let clock = WallClock::now();
+        let mut log = self.events.lock().unwrap();
+        for (label, entry) in expired {
+            info!(prefix = %label, packets = entry.packets, "Temporary block expired");
+            log.record(events::EventKind::BlockExpired(BlockSummary {
+                reason: self.block_reasons.remove(&label),
+                prefix: label,
+                expires_ms: clock.to_unix_ms(entry.expires_ns),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Inserts a temporary entry, returning the entry previously stored under
+    /// exactly this prefix. Permanent entries are left untouched, and the hit
+    /// counters of a temporary one carry over.
+    fn insert_timed(&mut self, prefix: &Prefix, mut entry: RuleEntry) -> Result<Option<RuleEntry>> {
+        let blocklist = self.ebpf.blocklist_mut();
+        if blocklist.add_new(prefix, entry)? {
+            return Ok(None);
+        }
+        // The prefix has an entry, so the lookup finds it and not a shorter one
+        let existing = blocklist.lookup(prefix)?;
+        if let Some(existing) = existing {
+            if existing.expires_ns == 0 {
+                return Ok(Some(existing));
             }
+            entry.packets = existing.packets;
+            entry.bytes = existing.bytes;
+        }
+        blocklist.add(prefix, entry)?;
+        Ok(existing)
+    }
+
+    /// Removes a temporary entry stored under exactly this prefix.
+    fn remove_timed(&mut self, prefix: &Prefix) -> Result<bool> {
+        let blocklist = self.ebpf.blocklist_mut();
+        // An entry under exactly this prefix is the one the lookup finds; a
+        // temporary one under a shorter prefix is left alone by the removal
+        if !blocklist
+            .lookup(prefix)?
+            .is_some_and(|entry| entry.expires_ns != 0)
+        {
+            return Ok(false);
+        }
+        Ok(blocklist.remove(prefix)?)
+    }
+
+    /// Reads the temporary entries of the ingress blocklist.
+    fn timed_entries(&self) -> Result<Vec<(Prefix, RuleEntry)>> {
+        Ok(self
+            .ebpf
+            .blocklist()
+            .list()?
+            .into_iter()
+            .filter(|(_, entry)| entry.expires_ns != 0)
+            .collect())
+    }
 
-            for port in &config.blocked_ports {
-                port_blocklist.insert(*port, PacketAction::Drop as u32, 0)?;
-                debug!(port, "Added port to ingress blocklist");
+    /// Removes the temporary entries of the ingress blocklist that expired by
+    /// `now_ns`, returning them by prefix.
+    fn remove_expired(&mut self, now_ns: u64) -> Result<Vec<(String, RuleEntry)>> {
+        let expired: Vec<_> = self
+            .timed_entries()?
+            .into_iter()
+            .filter(|(_, entry)| entry.expires_ns <= now_ns)
+            .collect();
+
+        let blocklist = self.ebpf.blocklist_mut();
+        let mut removed = Vec::new();
+        for (prefix, entry) in expired {
+            match blocklist.remove(&prefix) {
+                Ok(_) => removed.push((prefix.to_string(), entry)),
+                Err(e) => warn!(%prefix, "Failed to remove expired block: {}", e),
             }
         }
+        Ok(removed)
+    }
+
+    pub fn get_current_config(&self) -> Option<Config> {
+        self.current_config.clone()
+    }
+
+    pub fn apply_firewall_config(&mut self, config: &FirewallConfig) -> Result<()> {
+        config
+            .map_sizes
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid map_sizes config: {e}"))?;
+        let rate_limit = config
+            .rate_limit
+            .to_params()
+            .map_err(|e| anyhow::anyhow!("Invalid rate_limit config: {e}"))?;
+        let port_scan = config
+            .port_scan
+            .to_params()
+            .map_err(|e| anyhow::anyhow!("Invalid port_scan config: {e}"))?;
+        let (icmp_rules, icmp) = config
+            .icmp
+            .compile()
+            .map_err(|e| anyhow::anyhow!("Invalid icmp config: {e}"))?;
+        let rules = config
+            .ordered_rules()
+            .into_iter()
+            .map(|(index, rule)| {
+                rule.compile(index as u32).map_err(|e| {
+                    anyhow::anyhow!("Invalid firewall rule {}: {e}", rule.label(index))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if rules.len() > MAX_FILTER_RULES as usize {
+            anyhow::bail!(
+                "Too many firewall rules: {} (maximum {MAX_FILTER_RULES})",
+                rules.len()
+            );
+        }
+        let ingress = parse_prefixes(&config.blocked_ips, "ingress");
+        let egress = parse_prefixes(&config.blocked_egress_ips, "egress");
+        let port_entries: Vec<_> = config
+            .port_entries()
+            .map(|(proto, port, bits, entry)| (PortPrefix { proto, port, bits }, entry))
+            .collect();
+
+        // Make sure every table fits before changing any of them, so a
+        // ruleset that is too large leaves the running one intact (the rule
+        // count was checked above)
+        self.ebpf
+            .blocklist()
+            .check(&ingress)
+            .context("Ingress blocklist too large")?;
+        self.ebpf
+            .port_blocklist()
+            .check(&port_entries)
+            .context("Port blocklist too large")?;
+        self.ebpf
+            .egress_blocklist()
+            .check(&egress)
+            .context("Egress blocklist too large")?;
+
+        // Update ordered rule table (XDP Ingress)
+        self.ebpf
+            .rules_mut()
+            .replace(&rules)
+            .context("Failed to update rule table")?;
+        self.rule_labels = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| rule.label(index))
+            .collect();
+
+        // Update IP blocklists (XDP Ingress)
+        self.ebpf
+            .blocklist_mut()
+            .replace(&ingress)
+            .context("Failed to update ingress blocklist")?;
+
+        // Update Port blocklist (XDP Ingress, both address families)
+        self.ebpf
+            .port_blocklist_mut()
+            .replace(&port_entries)
+            .context("Failed to update port blocklist")?;
+
+        // Update Egress blocklists (TC Egress)
+        self.ebpf
+            .egress_blocklist_mut()
+            .replace(&egress)
+            .context("Failed to update egress blocklist")?;
+
+        // Update ICMP rules (XDP Ingress)
+        self.ebpf
+            .icmp_rules_mut()
+            .replace(&icmp_rules)
+            .context("Failed to update ICMP rules")?;
+
+        let settings = self.ebpf.settings_mut();
 
-        // Update Egress blocklist (TC Egress)
-        if let Some(map) = self.ebpf.get_map_mut("EGRESS_BLOCK") {
-            let mut egress_block: HashMap<_, u32, u32> = HashMap::try_from(map)?;
+        // Update rate limiter settings (XDP Ingress)
+        settings.rate_limit.set(rate_limit)?;
 
-            let keys: Vec<u32> = egress_block.keys().filter_map(|k| k.ok()).collect();
-            for key in keys {
-                let _ = egress_block.remove(&key);
+        // Update port-scan detection settings (XDP Ingress)
+        settings.port_scan.set(port_scan)?;
+
+        // Update ICMP rate limit (XDP Ingress)
+        settings.icmp.set(icmp)?;
+
+        // Update connection tracking settings (TC records flows, XDP enforces)
+        let mut conntrack = config.conntrack.to_params();
+        // The fast path only forwards tracked flows
+        conntrack.track |= config.fast_path.enabled as u32;
+        settings.conntrack.set(conntrack)?;
+
+        // Update fast-path settings (XDP Ingress)
+        settings.fast_path.set(config.fast_path.to_params())?;
+
+        // Update fragment and malformed-packet policy (XDP Ingress)
+        settings.validation.set(config.validation.to_params())?;
+
+        info!(
+            rules = rules.len(),
+            ingress_ips = ingress.len(),
+            ingress_port_rules = config.blocked_ports.len() + config.port_rules.len(),
+            egress_ips = egress.len(),
+            rate_limit_pps = rate_limit.pps,
+            syn_limit_pps = rate_limit.syn_pps,
+            port_scan_threshold = port_scan.threshold,
+            icmp_rules = icmp_rules.len(),
+            default_deny = config.conntrack.default_deny,
+            fast_path = config.fast_path.enabled,
+            fragments = ?config.validation.fragments,
+            malformed = ?config.validation.malformed,
+            tcp_flags = config.validation.tcp_flags,
+            "Firewall configuration applied"
+        );
+
+        Ok(())
+    }
+
+    /// Tells the eBPF programs the role of each configured interface, which
+    /// selects the rules and checks applied to its traffic.
+    pub fn apply_interface_roles(&mut self, config: &InterfacesConfig) -> Result<()> {
+        let mut wanted = BTreeMap::new();
+        for (role, iface) in config.roles() {
+            match ifindex(&iface.name) {
+                Ok(ifindex) => {
+                    wanted.insert(ifindex, role as u32);
+                }
+                Err(e) => warn!(iface = %iface.name, ?role, "Skipping interface role: {}", e),
             }
+        }
+
+        self.ebpf
+            .iface_roles_mut()
+            .replace(&wanted)
+            .context("Failed to update interface roles")?;
+
+        info!(interfaces = wanted.len(), "Interface roles applied");
+        Ok(())
+    }
 
-            for ip_str in &config.blocked_egress_ips {
-                if let Ok(ip) = ip_str.parse::<Ipv4Addr>() {
-                    let ip_u32 = u32::from(ip);
-                    egress_block.insert(ip_u32, PacketAction::Drop as u32, 0)?;
-                    debug!(ip = %ip_str, "Added IP to egress blocklist");
+    /// Sets the interfaces the XDP fast path may redirect to (every
+    /// configured interface).
+    pub fn apply_fast_path_interfaces(&mut self, config: &InterfacesConfig) -> Result<()> {
+        let mut ifaces = BTreeMap::new();
+        for name in config.roles().map(|(_, iface)| &iface.name) {
+            // XDP cannot transmit on a bridge; its members need their own entries
+            if Path::new("/sys/class/net")
+                .join(name)
+                .join("bridge")
+                .exists()
+            {
+                warn!(iface = %name, "Bridge interfaces cannot be fast-path targets");
+                continue;
+            }
+            match ifindex(name) {
+                Ok(ifindex) => {
+                    ifaces.insert(ifindex, 0);
+                    debug!(iface = %name, ifindex, "Added fast-path interface");
                 }
+                Err(e) => warn!(iface = %name, "Skipping fast-path interface: {}", e),
             }
         }
 
+        self.ebpf
+            .fast_path_ifaces_mut()
+            .replace(&ifaces)
+            .context("Failed to update fast-path interfaces")?;
+        Ok(())
+    }
+
+    /// Fills the bogon maps from the built-in list, the LAN prefix and the
+    /// configured exceptions, and turns anti-spoofing on for the upstream
+    /// interfaces.
+    pub fn apply_anti_spoof(
+        &mut self,
+        config: &AntiSpoofConfig,
+        interfaces: &InterfacesConfig,
+    ) -> Result<()> {
+        let mut bogons = Vec::new();
+        for (prefix, drop) in config.prefixes(interfaces.lan.address.as_deref()) {
+            let action = if drop {
+                PacketAction::Drop
+            } else {
+                PacketAction::Pass
+            };
+            match parse_prefix(prefix) {
+                Some(prefix) => bogons.push((prefix, RuleEntry::new(action))),
+                None => warn!(prefix, "Ignoring invalid anti-spoofing prefix"),
+            }
+        }
+        self.ebpf
+            .bogons_mut()
+            .replace(&bogons)
+            .context("Failed to update bogon prefixes")?;
+
+        self.ebpf.settings_mut().anti_spoof.set(AntiSpoofParams {
+            enabled: config.enabled as u32,
+        })?;
+
         info!(
-            ingress_ips = config.blocked_ips.len(),
-            ingress_ports = config.blocked_ports.len(),
-            egress_ips = config.blocked_egress_ips.len(),
-            "Firewall configuration applied"
+            enabled = config.enabled,
+            prefixes = bogons.len(),
+            "Anti-spoofing configured"
         );
-
         Ok(())
     }
 
@@ -220,10 +734,12 @@ impl Router {
                     match client.acquire().await {
                         Ok(lease) => {
                             info!("DHCP Lease acquired: {}/{}", lease.ip, lease.netmask);
-                            if let Err(e) = actuator::NetworkActuator::apply_lease(&config.interface, &lease) {
+                            if let Err(e) =
+                                actuator::NetworkActuator::apply_lease(&config.interface, &lease)
+                            {
                                 error!("Failed to apply DHCP lease: {}", e);
                             }
-                            
+
                             // Renewal logic (simple sleep for 50% of lease time)
                             let sleep_time = Duration::from_secs((lease.lease_time / 2).into());
                             debug!("Sleeping for {:?} before renewal", sleep_time);
@@ -251,52 +767,197 @@ impl Router {
             info!("Stopped existing DNS server");
         }
 
-        if let Some(server_config) = &config.server {
-            if server_config.enabled {
-                // Need lease DB for local resolution
-                if let Some(db) = &self.lease_db {
-                    info!("Starting DNS server...");
-                    // Determine local domain from DHCP config if available?
-                    // Ideally DNS config should have it or we grab from DHCP options.
-                    // For now, pass None or "lan"
-                    let local_domain = Some("lan".to_string());
-
-                    let server = DnsServer::new(server_config.clone(), db.clone(), local_domain);
-                    let handle = tokio::spawn(async move {
-                        if let Err(e) = server.run().await {
-                            error!("DNS Server failed: {}", e);
-                        }
-                    });
-                    self.dns_handle = Some(handle);
-                } else {
-                    tracing::warn!(
-                        "DNS Server enabled but DHCP (and Lease DB) is not initialized. Local resolution will fail."
-                    );
-                    // We could start it without local resolution, but for now let's skip or start with empty DB?
-                    // Or just don't start.
-                }
+        if let Some(server_config) = &config.server
+            && server_config.enabled
+        {
+            // Need lease DB for local resolution
+            if let Some(db) = &self.lease_db {
+                info!("Starting DNS server...");
+                // Determine local domain from DHCP config if available?
+                // Ideally DNS config should have it or we grab from DHCP options.
+                // For now, pass None or "lan"
+                let local_domain = Some("lan".to_string());
+
+                let server = DnsServer::new(server_config.clone(), db.clone(), local_domain);
+                let handle = tokio::spawn(async move {
+                    if let Err(e) = server.run().await {
+                        error!("DNS Server failed: {}", e);
+                    }
+                });
+                self.dns_handle = Some(handle);
+            } else {
+                tracing::warn!(
+                    "DNS Server enabled but DHCP (and Lease DB) is not initialized. Local resolution will fail."
+                );
+                // We could start it without local resolution, but for now let's skip or start with empty DB?
+                // Or just don't start.
             }
         }
         Ok(())
     }
 
     pub fn get_stats(&self) -> Result<Stats> {
-        let map = self.ebpf.get_map("STATS").context("STATS map not found")?;
-        let stats: PerCpuArray<_, Stats> = PerCpuArray::try_from(map)?;
+        Ok(self.ebpf.stats().read()?)
+    }
+
+    /// Fill levels of the size-limited maps, as of the last refresh.
+    pub fn get_map_usage(&self) -> Vec<api::MapUsage> {
+        self.map_usage.lock().unwrap().clone()
+    }
+
+    /// Counts the entries of the size-limited maps. This walks every key of
+    /// the tracking tables, so it runs on an interval rather than per API
+    /// request.
+    pub fn refresh_map_usage(&self) -> Result<()> {
+        let usage = self
+            .ebpf
+            .map_usage()?
+            .into_iter()
+            .map(api::MapUsage::from)
+            .collect();
+        *self.map_usage.lock().unwrap() = usage;
+        Ok(())
+    }
+
+    /// Collects the per-entry hit counters from every blocklist map.
+    pub fn get_rule_hits(&self) -> Result<api::FirewallHits> {
+        let mut ingress_ips = prefix_hits(self.ebpf.blocklist())?;
+        let mut egress_ips = prefix_hits(self.ebpf.egress_blocklist())?;
+
+        // A rule expands into several prefix entries (per protocol and
+        // range block); fold them back together by rule index.
+        let mut port_rules: BTreeMap<u32, PortRuleHits> = BTreeMap::new();
+        for (prefix, entry) in self.ebpf.port_blocklist().list()? {
+            let (start, end) = prefix.ports();
+            let hits = port_rules.entry(entry.rule).or_insert(PortRuleHits {
+                tcp: false,
+                udp: false,
+                start,
+                end,
+                packets: 0,
+                bytes: 0,
+            });
+            hits.tcp |= prefix.proto == IPPROTO_TCP;
+            hits.udp |= prefix.proto == IPPROTO_UDP;
+            hits.start = hits.start.min(start);
+            hits.end = hits.end.max(end);
+            hits.packets += entry.packets;
+            hits.bytes += entry.bytes;
+        }
+        let mut ports: Vec<_> = port_rules.into_values().map(api::RuleHit::from).collect();
 
-        let per_cpu_stats = stats.get(&0, 0)?;
-        let mut total = Stats::default();
+        let mut rules = Vec::new();
+        for rule in self.ebpf.rules().list()? {
+            let entry = rule.entry;
+            let label = self
+                .rule_labels
+                .get(entry.rule as usize)
+                .cloned()
+                .unwrap_or_else(|| format!("rule-{}", entry.rule));
+            rules.push(api::RuleHit::new(label, &entry));
+        }
 
-        for cpu_stats in per_cpu_stats.iter() {
-            total.packets_total += cpu_stats.packets_total;
-            total.packets_passed += cpu_stats.packets_passed;
-            total.packets_dropped += cpu_stats.packets_dropped;
+        for hits in [&mut rules, &mut ingress_ips, &mut ports, &mut egress_ips] {
+            hits.sort_by_key(|hit| std::cmp::Reverse(hit.packets));
         }
 
-        Ok(total)
+        Ok(api::FirewallHits {
+            rules,
+            ingress_ips,
+            ports,
+            egress_ips,
+        })
+    }
+}
+
+/// Reads the hit counters of an address blocklist, rendering each prefix as
+/// CIDR.
+fn prefix_hits(blocklist: &Blocklist) -> Result<Vec<api::RuleHit>> {
+    Ok(blocklist
+        .list()?
+        .into_iter()
+        .map(|(prefix, entry)| api::RuleHit::new(prefix.to_string(), &entry))
+        .collect())
+}
+
+/// Hit counters for one port rule, summed over its `PORT_BLOCKLIST` entries.
+struct PortRuleHits {
+    tcp: bool,
+    udp: bool,
+    start: u16,
+    end: u16,
+    packets: u64,
+    bytes: u64,
+}
+
+impl From<PortRuleHits> for api::RuleHit {
+    fn from(hits: PortRuleHits) -> Self {
+        let proto = match (hits.tcp, hits.udp) {
+            (true, false) => PortProtocol::Tcp,
+            (false, true) => PortProtocol::Udp,
+            _ => PortProtocol::Both,
+        };
+        let ports = PortRange {
+            start: hits.start,
+            end: hits.end,
+        };
+        api::RuleHit {
+            rule: format!("{}/{ports}", proto.as_str()),
+            packets: hits.packets,
+            bytes: hits.bytes,
+        }
+    }
+}
+
+/// Map capacities from the config; without one the object's sizes apply.
+fn map_sizes(config: Option<&Config>) -> MapSizes {
+    let Some(firewall) = config.map(|config| &config.firewall) else {
+        return MapSizes::default();
+    };
+    let sizes = &firewall.map_sizes;
+    MapSizes {
+        blocklist: Some(sizes.blocklist),
+        egress_blocklist: Some(sizes.egress_blocklist),
+        port_blocklist: Some(sizes.port_blocklist),
+        conntrack: Some(firewall.conntrack.max_entries),
+        rate_limit: Some(sizes.rate_limit),
+        port_scan: Some(sizes.port_scan),
     }
 }
 
+/// Resolves an interface name to its kernel ifindex.
+fn ifindex(name: &str) -> Result<u32> {
+    let path = Path::new("/sys/class/net").join(name).join("ifindex");
+    let index = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    index
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid ifindex for {name}"))
+}
+
+/// Parses an IPv4/IPv6 address or CIDR prefix.
+fn parse_prefix(s: &str) -> Option<Prefix> {
+    let (addr, len) = parse_cidr(s)?;
+    Some(Prefix { addr, len })
+}
+
+/// Parses a list of blocklist entries into drop entries, logging and
+/// skipping any that fail to parse.
+fn parse_prefixes(entries: &[String], direction: &str) -> Vec<(Prefix, RuleEntry)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let prefix = parse_prefix(entry);
+            if prefix.is_none() {
+                warn!(ip = %entry, direction, "Ignoring invalid blocklist entry");
+            }
+            prefix
+        })
+        .map(|prefix| (prefix, RuleEntry::new(PacketAction::Drop)))
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
@@ -314,6 +975,20 @@ async fn main() -> Result<()> {
     // Load initial config
     router.write().await.load_config().await?;
 
+    // Blocks sources the datapath reports as port scanning
+    let scans = blocklist::spawn_scan_blocker(router.clone());
+
+    // Drop event and packet capture reader tasks
+    if let Err(e) = router.write().await.spawn_event_reader(scans) {
+        error!("Failed to start drop event reader: {}", e);
+    }
+    if let Err(e) = router.write().await.spawn_capture_reader() {
+        error!("Failed to start packet capture reader: {}", e);
+    }
+
+    // Removes temporary blocks once they expire
+    blocklist::spawn_sweeper(router.clone());
+
     // Channel for config reload signals
     let (tx, mut rx) = mpsc::channel::<()>(1);
 
@@ -322,18 +997,18 @@ async fn main() -> Result<()> {
     let tx_watcher = tx.clone();
 
     let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
-        if let Ok(event) = res {
-            if matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
-                let _ = tx_watcher.blocking_send(());
-            }
+        if let Ok(event) = res
+            && matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_))
+        {
+            let _ = tx_watcher.blocking_send(());
         }
     })?;
 
-    if let Some(parent) = config_path.parent() {
-        if parent.exists() {
-            watcher.watch(parent, RecursiveMode::NonRecursive)?;
-            info!(path = ?config_path, "Watching config file");
-        }
+    if let Some(parent) = config_path.parent()
+        && parent.exists()
+    {
+        watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        info!(path = ?config_path, "Watching config file");
     }
 
     // Stats reporting task
@@ -347,9 +1022,25 @@ async fn main() -> Result<()> {
             match router.get_stats() {
                 Ok(stats) => {
                     info!(
-                        total = stats.packets_total,
-                        passed = stats.packets_passed,
-                        dropped = stats.packets_dropped,
+                        rx_packets = stats.ingress.packets_total,
+                        rx_bytes = stats.ingress.bytes_total,
+                        rx_dropped = stats.ingress.packets_dropped,
+                        tx_packets = stats.egress.packets_total,
+                        tx_bytes = stats.egress.bytes_total,
+                        tx_dropped = stats.egress.packets_dropped,
+                        rate_limited = stats.rate_limited,
+                        syn_limited = stats.syn_limited,
+                        conntrack_dropped = stats.conntrack_dropped,
+                        fast_path = stats.fast_path,
+                        slow_path = stats.slow_path,
+                        malformed = stats.malformed,
+                        port_scan_dropped = stats.port_scan_dropped,
+                        spoofed = stats.spoofed,
+                        tcp_null = stats.tcp_null,
+                        tcp_xmas = stats.tcp_xmas,
+                        tcp_syn_fin = stats.tcp_syn_fin,
+                        icmp_dropped = stats.icmp_dropped,
+                        icmp_rate_limited = stats.icmp_rate_limited,
                         "Packet statistics"
                     );
                 }
@@ -360,6 +1051,18 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Map fill levels reported by the API
+    let router_usage = router.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(MAP_USAGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = router_usage.read().await.refresh_map_usage() {
+                warn!("Failed to count map entries: {}", e);
+            }
+        }
+    });
+
     // API Server
     let api_router = router.clone();
     let api_bind = args.api_bind.clone();